@@ -14,6 +14,13 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
         sensor_name TEXT NOT NULL,
         value DOUBLE PRECISION NOT NULL,
         topic TEXT NOT NULL,
+        tenant_id TEXT,
+        dedupe_key TEXT,
+        unit TEXT,
+        device_class TEXT,
+        quality TEXT,
+        quality_reason TEXT,
+        clock_offset DOUBLE PRECISION,
         PRIMARY KEY (timestamp, id)
     );
 
@@ -23,18 +30,79 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
         id SERIAL NOT NULL,
         topic TEXT NOT NULL,
         payload TEXT NOT NULL,
+        tenant_id TEXT,
+        payload_size BIGINT,
         PRIMARY KEY (timestamp, id)
     );
 
+    -- Create device registry maintained by the bridge
+    CREATE TABLE IF NOT EXISTS devices (
+        device_id TEXT PRIMARY KEY,
+        first_seen TIMESTAMPTZ NOT NULL,
+        last_seen TIMESTAMPTZ NOT NULL,
+        last_topic TEXT NOT NULL,
+        message_count BIGINT NOT NULL DEFAULT 0
+    );
+
+    -- Create online/offline status maintained by the device watchdog
+    CREATE TABLE IF NOT EXISTS device_status (
+        device_id TEXT PRIMARY KEY,
+        online BOOLEAN NOT NULL,
+        last_seen TIMESTAMPTZ NOT NULL,
+        changed_at TIMESTAMPTZ NOT NULL
+    );
+
+    -- Create alerts table for the database alert action
+    CREATE TABLE IF NOT EXISTS alerts (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        rule TEXT NOT NULL,
+        status TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        sensor_name TEXT NOT NULL,
+        value DOUBLE PRECISION NOT NULL,
+        topic TEXT NOT NULL,
+        PRIMARY KEY (timestamp, id)
+    );
+
+    -- Create commands published to devices by the downlink
+    CREATE TABLE IF NOT EXISTS commands (
+        id BIGSERIAL PRIMARY KEY,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        device_id TEXT,
+        topic TEXT,
+        payload TEXT NOT NULL,
+        delivered_at TIMESTAMPTZ,
+        error TEXT
+    );
+
+    -- Wake the downlink as soon as a command is added
+    CREATE OR REPLACE FUNCTION notify_command() RETURNS trigger AS \$\$
+    BEGIN
+        PERFORM pg_notify('anvil_commands', NEW.id::text);
+        RETURN NEW;
+    END;
+    \$\$ LANGUAGE plpgsql;
+
+    DROP TRIGGER IF EXISTS commands_notify ON commands;
+    CREATE TRIGGER commands_notify AFTER INSERT ON commands
+        FOR EACH ROW EXECUTE FUNCTION notify_command();
+
     -- Convert to hypertables for time-series optimization
     SELECT create_hypertable('telemetry', 'timestamp', if_not_exists => TRUE);
     SELECT create_hypertable('raw_messages', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('alerts', 'timestamp', if_not_exists => TRUE);
 
     -- Create indexes for common queries
     CREATE INDEX IF NOT EXISTS idx_telemetry_device_id ON telemetry (device_id);
     CREATE INDEX IF NOT EXISTS idx_telemetry_sensor_name ON telemetry (sensor_name);
     CREATE INDEX IF NOT EXISTS idx_telemetry_device_sensor ON telemetry (device_id, sensor_name);
     CREATE INDEX IF NOT EXISTS idx_raw_messages_topic ON raw_messages (topic);
+    CREATE INDEX IF NOT EXISTS idx_commands_pending ON commands (id) WHERE delivered_at IS NULL AND error IS NULL;
+
+    -- Readings from mappings with a dedupe_key are stored once per key
+    -- (hypertable unique indexes must include the time column)
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_telemetry_dedupe_key ON telemetry (dedupe_key, timestamp);
 
     -- Configure proper authentication
     ALTER USER admin WITH PASSWORD 'admin';
@@ -0,0 +1,66 @@
+//! Run the bridge from another binary with a custom payload decoder
+//!
+//! Devices on `sensors/<device>/<sensor>` publish a bare number, e.g.
+//! `sensors/ob1/temperature` with payload `21.5`
+
+use anyhow::Result;
+use chrono::Utc;
+
+use anvil::config::Config;
+use anvil::db::{RawMessage, TelemetryReading};
+use anvil::{Bridge, Decoder, ParsedMessage, TopicMapping};
+
+struct PlainNumberDecoder;
+
+impl Decoder for PlainNumberDecoder {
+    fn decode(&self, topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+        let Ok(text) = std::str::from_utf8(payload) else {
+            return Vec::new();
+        };
+
+        let mut records = vec![ParsedMessage::RawMessage(RawMessage {
+            topic: topic.to_string(),
+            payload: text.to_string(),
+            timestamp: Utc::now(),
+            tenant: None,
+            payload_size: None,
+        })];
+
+        let levels: Vec<&str> = topic.split('/').collect();
+        if let ([_, device_id, sensor_name], Ok(value)) = (&levels[..], text.trim().parse()) {
+            records.push(ParsedMessage::TelemetryReading(TelemetryReading {
+                device_id: device_id.to_string(),
+                sensor_name: sensor_name.to_string(),
+                value,
+                topic: topic.to_string(),
+                timestamp: Utc::now(),
+                tenant: None,
+                table: None,
+                dedupe_key: None,
+                unit: None,
+                device_class: None,
+                quality: None,
+                clock_offset: None,
+                custom_insert: None,
+            }));
+        }
+
+        records
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut config = Config::default();
+    config.logging.banner = false;
+    config.http.enabled = false;
+
+    Bridge::builder()
+        .config(config)
+        .mappings(vec![TopicMapping::from_topic("sensors/+/+")])
+        .decoder(PlainNumberDecoder)
+        .run()
+        .await
+}
@@ -0,0 +1,323 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use axum::extract::{Query, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+use crate::config::Config;
+use crate::db::{self, Database};
+use crate::introspect;
+use crate::metrics::metrics;
+use crate::mqtt::BridgeCommand;
+use crate::state::{BridgeState, PauseMode};
+
+/// Shared state of the admin API
+#[derive(Clone)]
+pub struct AdminState {
+    pub bridge: Arc<BridgeState>,
+    pub commands: mpsc::Sender<BridgeCommand>,
+    /// Configuration file re-read on reload, reloading is unavailable
+    /// without one
+    pub config_path: Option<String>,
+    /// Mappings path given on the command line, replaces `mqtt.mappings`
+    pub mappings_override: Option<String>,
+    pub token: Option<String>,
+    /// Database the tables of reloaded mappings are checked against,
+    /// unchecked without one
+    pub database: Option<Arc<Database>>,
+}
+
+#[derive(Deserialize)]
+struct MappingQuery {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct PauseQuery {
+    #[serde(default)]
+    mode: PauseMode,
+}
+
+/// Single page dashboard polling `/admin/overview`
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Routes under `/admin`, guarded by the bearer token when one is configured
+/// The dashboard page itself is public, it asks for the token when needed
+pub fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/admin/overview", get(overview))
+        .route("/admin/mappings", get(list_mappings))
+        .route("/admin/mappings/pause", post(pause_mapping))
+        .route("/admin/mappings/resume", post(resume_mapping))
+        .route("/admin/pause", post(pause_all))
+        .route("/admin/resume", post(resume_all))
+        .route("/admin/reload", post(reload))
+        .route("/admin/config", get(show_config))
+        .route("/admin/unmatched", get(unmatched))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .route("/dashboard", get(dashboard))
+        .with_state(state)
+}
+
+async fn require_token(State(admin): State<AdminState>, request: Request, next: Next) -> Response {
+    if let Some(token) = &admin.token {
+        let authorized = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token);
+
+        if !authorized {
+            return error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+        }
+    }
+
+    next.run(request).await
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD_HTML)
+}
+
+/// Everything the dashboard shows, in one request
+async fn overview(State(admin): State<AdminState>) -> impl IntoResponse {
+    let insert_latency: serde_json::Map<_, _> = db::TABLES
+        .iter()
+        .map(|table| {
+            let histogram = metrics().insert_latency.with_label_values(&[table]);
+            let latency = json!({
+                "count": histogram.get_sample_count(),
+                "sum_secs": histogram.get_sample_sum(),
+            });
+            (table.to_string(), latency)
+        })
+        .collect();
+
+    Json(json!({
+        "uptime_secs": admin.bridge.uptime().as_secs(),
+        "mqtt_connected": admin.bridge.mqtt_connected(),
+        "paused": pause_status(&admin.bridge, None),
+        "mappings": mappings(&admin.bridge),
+        "insert_latency": insert_latency,
+        "recent_errors": admin.bridge.stats.recent_errors(),
+        "unmatched_topics": unmatched_summary(&admin.bridge),
+    }))
+}
+
+/// Unmatched topics without their samples, which the dashboard has no use for
+fn unmatched_summary(bridge: &BridgeState) -> Vec<serde_json::Value> {
+    bridge
+        .stats
+        .unmatched_topics()
+        .into_iter()
+        .map(|entry| {
+            json!({
+                "topic": entry.topic,
+                "mapping": entry.mapping,
+                "messages": entry.messages,
+                "last_seen": entry.last_seen,
+            })
+        })
+        .collect()
+}
+
+/// Topics without readings, each with its latest payload
+async fn unmatched(State(admin): State<AdminState>) -> impl IntoResponse {
+    Json(admin.bridge.stats.unmatched_topics())
+}
+
+/// Every subscription with its live statistics
+async fn list_mappings(State(admin): State<AdminState>) -> impl IntoResponse {
+    Json(mappings(&admin.bridge))
+}
+
+fn mappings(bridge: &BridgeState) -> Vec<serde_json::Value> {
+    let config = bridge.config();
+    let stats = bridge.stats.snapshot();
+
+    config
+        .mqtt
+        .topics
+        .iter()
+        .map(|mapping| {
+            json!({
+                "name": mapping.name(),
+                "table": mapping.spec().table,
+                "paused": pause_status(bridge, Some(mapping.name())),
+                "disabled_for_secs": bridge.budget.disabled_for(mapping.name()),
+                "stats": stats.get(mapping.name()).cloned().unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Mode and held messages of a pause, `false` when not paused
+fn pause_status(bridge: &BridgeState, subscription: Option<&str>) -> serde_json::Value {
+    match bridge.pause_status(subscription) {
+        Some((mode, held)) => json!({ "mode": mode, "held": held }),
+        None => json!(false),
+    }
+}
+
+fn has_mapping(bridge: &BridgeState, name: &str) -> bool {
+    bridge
+        .config()
+        .mqtt
+        .topics
+        .iter()
+        .any(|mapping| mapping.name() == name)
+}
+
+async fn pause_mapping(
+    State(admin): State<AdminState>,
+    Query(query): Query<MappingQuery>,
+    Query(pause): Query<PauseQuery>,
+) -> Response {
+    if !has_mapping(&admin.bridge, &query.name) {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("unknown mapping: {}", query.name),
+        );
+    }
+
+    let changed = admin.bridge.pause(Some(&query.name), pause.mode);
+    info!(
+        "Paused subscription {} ({})",
+        query.name,
+        pause.mode.as_str()
+    );
+    Json(json!({ "name": query.name, "paused": true, "changed": changed })).into_response()
+}
+
+async fn resume_mapping(
+    State(admin): State<AdminState>,
+    Query(query): Query<MappingQuery>,
+) -> Response {
+    if !has_mapping(&admin.bridge, &query.name) {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("unknown mapping: {}", query.name),
+        );
+    }
+
+    let changed = admin.bridge.resume(Some(&query.name));
+    info!("Resumed subscription {}", query.name);
+    Json(json!({ "name": query.name, "paused": false, "changed": changed })).into_response()
+}
+
+/// Pause every subscription, messages held back are stored on resume
+/// before those of a subscription paused on its own
+async fn pause_all(State(admin): State<AdminState>, Query(pause): Query<PauseQuery>) -> Response {
+    let changed = admin.bridge.pause(None, pause.mode);
+    info!("Paused all subscriptions ({})", pause.mode.as_str());
+    Json(json!({ "paused": true, "changed": changed })).into_response()
+}
+
+async fn resume_all(State(admin): State<AdminState>) -> Response {
+    let changed = admin.bridge.resume(None);
+    info!("Resumed all subscriptions");
+    Json(json!({ "paused": false, "changed": changed })).into_response()
+}
+
+/// Re-read the configuration file and mapping files and apply the
+/// subscription list
+/// Other settings only take effect after a restart
+async fn reload(State(admin): State<AdminState>) -> Response {
+    let config = match load_config(&admin).await {
+        Ok(config) => config,
+        Err(e) => return error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)),
+    };
+    if let Some(database) = &admin.database {
+        let checked = match database.connection().await {
+            Ok(connection) => introspect::check(&connection.client, &config).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = checked {
+            return error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e));
+        }
+    }
+
+    let (reply, response) = oneshot::channel();
+    let command = BridgeCommand::SetTopics {
+        topics: config.mqtt.topics,
+        reply,
+    };
+
+    if admin.commands.send(command).await.is_err() {
+        return error(StatusCode::SERVICE_UNAVAILABLE, "bridge is not running");
+    }
+
+    match response.await {
+        Ok(Ok(changes)) => Json(changes).into_response(),
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(_) => error(StatusCode::SERVICE_UNAVAILABLE, "bridge is not running"),
+    }
+}
+
+async fn load_config(admin: &AdminState) -> anyhow::Result<Config> {
+    let Some(path) = &admin.config_path else {
+        anyhow::bail!("the bridge was not started from a config file");
+    };
+    let mut config = Config::load(path).await?;
+    if admin.mappings_override.is_some() {
+        config.mqtt.mappings = admin.mappings_override.clone();
+    }
+    config.load_mappings()?;
+    Ok(config)
+}
+
+async fn show_config(State(admin): State<AdminState>) -> impl IntoResponse {
+    Json(admin.bridge.config().redacted())
+}
+
+/// Base URL of the admin API of a bridge running with `config`, on this host
+/// when it listens on every interface
+pub fn local_url(config: &Config) -> String {
+    let bind = config.http.bind.replace("0.0.0.0", "127.0.0.1");
+    format!("http://{}", bind)
+}
+
+/// Call an admin endpoint of a running bridge, returning its reply
+pub async fn request(
+    method: reqwest::Method,
+    base_url: &str,
+    token: Option<&str>,
+    path: &str,
+    query: &[(&str, &str)],
+) -> anyhow::Result<serde_json::Value> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), path);
+    let mut request = reqwest::Client::new().request(method, &url).query(query);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the admin API at {}", url))?;
+    let status = response.status();
+    let body: serde_json::Value = response
+        .json()
+        .await
+        .with_context(|| format!("Invalid reply from {}", url))?;
+    if !status.is_success() {
+        let message = body["error"].as_str().unwrap_or("request failed");
+        anyhow::bail!("{}: {}", status, message);
+    }
+    Ok(body)
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::config::substitute_env;
+use crate::db::{Database, TelemetryReading};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Contents of an `alerts.yaml` file
+#[derive(Debug, Deserialize)]
+struct AlertsFile {
+    rules: Vec<AlertRule>,
+}
+
+/// A threshold on one sensor, optionally limited to a single device
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    /// Device the rule applies to, every device when omitted
+    #[serde(default)]
+    pub device: Option<String>,
+    pub sensor: String,
+    /// Fire when the value is greater than this
+    #[serde(default)]
+    pub above: Option<f64>,
+    /// Fire when the value is less than this
+    #[serde(default)]
+    pub below: Option<f64>,
+    /// Seconds the condition must hold before the alert fires
+    #[serde(default)]
+    pub for_secs: u64,
+    pub actions: Vec<AlertAction>,
+}
+
+/// What happens when an alert fires or resolves
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AlertAction {
+    /// Publish the event as JSON to an MQTT topic
+    Mqtt { topic: String },
+    /// POST the event as JSON to a URL
+    Webhook { url: String },
+    /// Insert the event into the `alerts` table
+    Database,
+}
+
+impl AlertRule {
+    fn applies_to(&self, reading: &TelemetryReading) -> bool {
+        reading.sensor_name == self.sensor
+            && self
+                .device
+                .as_ref()
+                .is_none_or(|device| *device == reading.device_id)
+    }
+
+    fn breached(&self, value: f64) -> bool {
+        self.above.is_some_and(|limit| value > limit)
+            || self.below.is_some_and(|limit| value < limit)
+    }
+}
+
+/// Load and validate the rules in an alerts file
+pub fn load_rules(path: &str) -> Result<Vec<AlertRule>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read alerts file: {}", path))?;
+
+    let contents = substitute_env(&contents)
+        .with_context(|| format!("Failed to expand alerts file: {}", path))?;
+
+    let file: AlertsFile = serde_yaml::from_str(&contents)
+        .with_context(|| format!("Failed to parse alerts file: {}", path))?;
+
+    for rule in &file.rules {
+        if rule.above.is_none() && rule.below.is_none() {
+            bail!("Alert rule {} needs `above` or `below`", rule.name);
+        }
+        if rule.actions.is_empty() {
+            bail!("Alert rule {} has no actions", rule.name);
+        }
+    }
+
+    Ok(file.rules)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        }
+    }
+}
+
+/// Payload sent to every action of a rule
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub status: AlertStatus,
+    pub device_id: String,
+    pub sensor: String,
+    pub value: f64,
+    pub topic: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Condition state of one rule for one device
+#[derive(Debug, Default)]
+struct Tracker {
+    /// When the current breach started
+    since: Option<DateTime<Utc>>,
+    firing: bool,
+}
+
+/// Evaluates incoming readings against the alert rules
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    /// Keyed by rule index and device id
+    trackers: Mutex<HashMap<(usize, String), Tracker>>,
+    database: Arc<Database>,
+    http: reqwest::Client,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>, database: Arc<Database>) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .with_context(|| "Failed to create webhook client")?;
+
+        Ok(Self {
+            rules,
+            trackers: Mutex::new(HashMap::new()),
+            database,
+            http,
+        })
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Update rule state with a reading and return alerts that fired or
+    /// resolved because of it
+    pub fn evaluate(&self, reading: &TelemetryReading) -> Vec<(usize, AlertEvent)> {
+        let mut trackers = self.trackers.lock().unwrap();
+        let now = Utc::now();
+        let mut events = Vec::new();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.applies_to(reading) {
+                continue;
+            }
+
+            let tracker = trackers
+                .entry((index, reading.device_id.clone()))
+                .or_default();
+
+            let status = if rule.breached(reading.value) {
+                let since = *tracker.since.get_or_insert(now);
+                let held = (now - since).to_std().unwrap_or_default();
+                if tracker.firing || held < Duration::from_secs(rule.for_secs) {
+                    continue;
+                }
+                tracker.firing = true;
+                AlertStatus::Firing
+            } else {
+                tracker.since = None;
+                if !tracker.firing {
+                    continue;
+                }
+                tracker.firing = false;
+                AlertStatus::Resolved
+            };
+
+            events.push((
+                index,
+                AlertEvent {
+                    rule: rule.name.clone(),
+                    status,
+                    device_id: reading.device_id.clone(),
+                    sensor: reading.sensor_name.clone(),
+                    value: reading.value,
+                    topic: reading.topic.clone(),
+                    time: now,
+                },
+            ));
+        }
+
+        events
+    }
+
+    /// Run the actions of the rule that produced `event` in the background
+    pub fn dispatch(self: &Arc<Self>, rule: usize, event: AlertEvent, mqtt: &AsyncClient) {
+        match event.status {
+            AlertStatus::Firing => warn!(
+                rule = %event.rule,
+                device_id = %event.device_id,
+                value = event.value,
+                "Alert firing"
+            ),
+            AlertStatus::Resolved => info!(
+                rule = %event.rule,
+                device_id = %event.device_id,
+                value = event.value,
+                "Alert resolved"
+            ),
+        }
+
+        let engine = self.clone();
+        let mqtt = mqtt.clone();
+        tokio::spawn(async move {
+            for action in &engine.rules[rule].actions {
+                if let Err(e) = engine.run_action(action, &event, &mqtt).await {
+                    error!("Alert {} action failed: {:#}", event.rule, e);
+                }
+            }
+        });
+    }
+
+    async fn run_action(
+        &self,
+        action: &AlertAction,
+        event: &AlertEvent,
+        mqtt: &AsyncClient,
+    ) -> Result<()> {
+        match action {
+            AlertAction::Mqtt { topic } => {
+                let payload = serde_json::to_vec(event)?;
+                mqtt.publish(topic, QoS::AtLeastOnce, false, payload)
+                    .await
+                    .with_context(|| format!("Failed to publish alert to {}", topic))?;
+            }
+            AlertAction::Webhook { url } => {
+                self.http
+                    .post(url)
+                    .json(event)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .with_context(|| format!("Failed to call webhook {}", url))?;
+            }
+            AlertAction::Database => {
+                self.database
+                    .connection()
+                    .await?
+                    .client
+                    .execute(
+                        "INSERT INTO alerts (timestamp, rule, status, device_id, sensor_name, value, topic) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                        &[
+                            &event.time,
+                            &event.rule,
+                            &event.status.as_str(),
+                            &event.device_id,
+                            &event.sensor,
+                            &event.value,
+                            &event.topic,
+                        ],
+                    )
+                    .await
+                    .with_context(|| "Failed to insert alert")?;
+            }
+        }
+
+        Ok(())
+    }
+}
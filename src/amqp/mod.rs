@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicQosOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{Connection, ConnectionProperties, Consumer};
+use tracing::{error, warn};
+
+use crate::config::AmqpConfig;
+use crate::ingest::{Ingest, Message};
+
+/// Declare and bind the queue and hand its deliveries to the workers until
+/// the bridge shuts down
+pub async fn start(config: &AmqpConfig, ingest: Ingest) -> Result<()> {
+    let connection = Connection::connect(&config.url, ConnectionProperties::default())
+        .await
+        .with_context(|| "Failed to connect to the AMQP broker")?;
+    let channel = connection
+        .create_channel()
+        .await
+        .with_context(|| "Failed to open an AMQP channel")?;
+
+    channel
+        .basic_qos(config.prefetch, BasicQosOptions::default())
+        .await
+        .with_context(|| "Failed to set the AMQP prefetch count")?;
+
+    channel
+        .queue_declare(
+            &config.queue,
+            QueueDeclareOptions {
+                durable: config.durable,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+        .with_context(|| format!("Failed to declare AMQP queue: {}", config.queue))?;
+
+    for binding in &config.bindings {
+        channel
+            .queue_bind(
+                &config.queue,
+                &binding.exchange,
+                &binding.routing_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to bind AMQP queue {} to {} with {}",
+                    config.queue, binding.exchange, binding.routing_key
+                )
+            })?;
+    }
+
+    let consumer = channel
+        .basic_consume(
+            &config.queue,
+            "anvil",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .with_context(|| format!("Failed to consume AMQP queue: {}", config.queue))?;
+
+    tokio::spawn(async move {
+        // Deliveries stop when the connection is dropped
+        let _connection = connection;
+        read(consumer, ingest).await;
+    });
+
+    Ok(())
+}
+
+/// Deliveries are acknowledged once queued, a delivery that could not be
+/// queued is redelivered by the broker
+async fn read(mut consumer: Consumer, ingest: Ingest) {
+    while let Some(delivery) = consumer.next().await {
+        let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                error!("AMQP consumer failed: {}", e);
+                break;
+            }
+        };
+
+        let message = Message {
+            topic: delivery.routing_key.as_str().replace('.', "/"),
+            payload: delivery.data.clone(),
+            ack: None,
+        };
+
+        if let Err(e) = ingest.submit(message).await {
+            warn!("Stopped reading from AMQP: {}", e);
+            break;
+        }
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+            error!("Failed to acknowledge AMQP delivery: {}", e);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Number, Value};
+
+/// Deepest nesting decoded, a schema referring to itself without consuming
+/// input would otherwise recurse forever
+const MAX_DEPTH: usize = 64;
+
+/// An Avro schema, read from its JSON form
+#[derive(Debug, Clone)]
+pub struct Schema {
+    root: Type,
+    /// Named records, enums and fixed types by full name
+    names: HashMap<String, Type>,
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<(String, Type)>),
+    Enum(Vec<String>),
+    Array(Box<Type>),
+    Map(Box<Type>),
+    Union(Vec<Type>),
+    Fixed(usize),
+    /// Reference to a named type by full name
+    Named(String),
+}
+
+impl Schema {
+    pub fn parse(schema: &str) -> Result<Self> {
+        let json: Value = serde_json::from_str(schema).with_context(|| "Invalid Avro schema")?;
+        let mut names = HashMap::new();
+        let root = parse_type(&json, None, &mut names)?;
+        Ok(Self { root, names })
+    }
+
+    /// Decode a binary encoded datum into its JSON value
+    /// Unions become the value of their branch, enums their symbol and
+    /// bytes a string
+    pub fn decode(&self, datum: &[u8]) -> Result<Value> {
+        let mut reader = Reader {
+            input: datum,
+            items: datum.len(),
+        };
+        self.read(&self.root, &mut reader, 0)
+    }
+
+    fn read(&self, kind: &Type, reader: &mut Reader<'_>, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("Avro datum nested too deeply");
+        }
+
+        Ok(match kind {
+            Type::Null => Value::Null,
+            Type::Boolean => Value::Bool(reader.byte()? != 0),
+            Type::Int | Type::Long => Value::from(reader.long()?),
+            Type::Float => float(f32::from_le_bytes(reader.array()?).into()),
+            Type::Double => float(f64::from_le_bytes(reader.array()?)),
+            Type::Bytes | Type::String => {
+                let length = reader.length()?;
+                Value::String(String::from_utf8_lossy(reader.take(length)?).into_owned())
+            }
+            Type::Fixed(size) => {
+                Value::String(String::from_utf8_lossy(reader.take(*size)?).into_owned())
+            }
+            Type::Record(fields) => {
+                let mut record = Map::new();
+                for (name, field) in fields {
+                    record.insert(name.clone(), self.read(field, reader, depth + 1)?);
+                }
+                Value::Object(record)
+            }
+            Type::Enum(symbols) => {
+                let index = reader.length()?;
+                let symbol = symbols
+                    .get(index)
+                    .ok_or_else(|| anyhow!("Avro enum index {} out of range", index))?;
+                Value::String(symbol.clone())
+            }
+            Type::Array(items) => {
+                let mut array = Vec::new();
+                while let Some(count) = reader.block()? {
+                    for _ in 0..count {
+                        array.push(self.read(items, reader, depth + 1)?);
+                    }
+                }
+                Value::Array(array)
+            }
+            Type::Map(values) => {
+                let mut map = Map::new();
+                while let Some(count) = reader.block()? {
+                    for _ in 0..count {
+                        let length = reader.length()?;
+                        let key = String::from_utf8_lossy(reader.take(length)?).into_owned();
+                        map.insert(key, self.read(values, reader, depth + 1)?);
+                    }
+                }
+                Value::Object(map)
+            }
+            Type::Union(branches) => {
+                let index = reader.length()?;
+                let branch = branches
+                    .get(index)
+                    .ok_or_else(|| anyhow!("Avro union index {} out of range", index))?;
+                self.read(branch, reader, depth + 1)?
+            }
+            Type::Named(name) => {
+                let named = self
+                    .names
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Unknown Avro type {}", name))?;
+                self.read(named, reader, depth + 1)?
+            }
+        })
+    }
+}
+
+/// A JSON number, `null` for NaN and infinities
+fn float(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+fn parse_type(
+    json: &Value,
+    namespace: Option<&str>,
+    names: &mut HashMap<String, Type>,
+) -> Result<Type> {
+    match json {
+        Value::String(name) => {
+            Ok(primitive(name).unwrap_or_else(|| Type::Named(full_name(name, namespace))))
+        }
+        Value::Array(branches) => branches
+            .iter()
+            .map(|branch| parse_type(branch, namespace, names))
+            .collect::<Result<_>>()
+            .map(Type::Union),
+        Value::Object(object) => parse_complex(object, namespace, names),
+        other => bail!("Invalid Avro type {}", other),
+    }
+}
+
+fn parse_complex(
+    object: &Map<String, Value>,
+    namespace: Option<&str>,
+    names: &mut HashMap<String, Type>,
+) -> Result<Type> {
+    let kind = match object.get("type") {
+        Some(Value::String(kind)) => kind.as_str(),
+        // `{"type": {"type": "array", ...}}` and the like
+        Some(nested) => return parse_type(nested, namespace, names),
+        None => bail!("Avro type without a type"),
+    };
+    if let Some(primitive) = primitive(kind) {
+        // Logical types are read as their underlying type
+        return Ok(primitive);
+    }
+
+    let name = object.get("name").and_then(Value::as_str);
+    let namespace = object
+        .get("namespace")
+        .and_then(Value::as_str)
+        .or(namespace);
+    let qualified = name.map(|name| full_name(name, namespace));
+    // Names inside a type are relative to the type's own namespace
+    let inner_namespace = qualified
+        .as_deref()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(namespace, _)| namespace)
+        .or(namespace)
+        .map(str::to_string);
+
+    let parsed = match kind {
+        "record" | "error" => {
+            let fields = object
+                .get("fields")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("Avro record without fields"))?;
+            let mut parsed = Vec::with_capacity(fields.len());
+            for field in fields {
+                let name = field
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("Avro field without a name"))?;
+                let kind = field
+                    .get("type")
+                    .ok_or_else(|| anyhow!("Avro field {} without a type", name))?;
+                parsed.push((
+                    name.to_string(),
+                    parse_type(kind, inner_namespace.as_deref(), names)?,
+                ));
+            }
+            Type::Record(parsed)
+        }
+        "enum" => {
+            let symbols = object
+                .get("symbols")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("Avro enum without symbols"))?;
+            Type::Enum(
+                symbols
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect(),
+            )
+        }
+        "array" => {
+            let items = object
+                .get("items")
+                .ok_or_else(|| anyhow!("Avro array without items"))?;
+            Type::Array(Box::new(parse_type(items, namespace, names)?))
+        }
+        "map" => {
+            let values = object
+                .get("values")
+                .ok_or_else(|| anyhow!("Avro map without values"))?;
+            Type::Map(Box::new(parse_type(values, namespace, names)?))
+        }
+        "fixed" => {
+            let size = object
+                .get("size")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| anyhow!("Avro fixed without a size"))?;
+            Type::Fixed(size as usize)
+        }
+        other => return Ok(Type::Named(full_name(other, namespace))),
+    };
+
+    if let Some(qualified) = qualified {
+        names.insert(qualified, parsed.clone());
+    }
+    Ok(parsed)
+}
+
+fn primitive(name: &str) -> Option<Type> {
+    Some(match name {
+        "null" => Type::Null,
+        "boolean" => Type::Boolean,
+        "int" => Type::Int,
+        "long" => Type::Long,
+        "float" => Type::Float,
+        "double" => Type::Double,
+        "bytes" => Type::Bytes,
+        "string" => Type::String,
+        _ => return None,
+    })
+}
+
+/// `name` qualified with `namespace` unless it already is
+fn full_name(name: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) if !name.contains('.') && !namespace.is_empty() => {
+            format!("{}.{}", namespace, name)
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Binary encoded input, read front to back
+struct Reader<'a> {
+    input: &'a [u8],
+    /// Array and map items left to read, one per byte of the datum at
+    /// most since items of `null` take no bytes at all
+    items: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if count > self.input.len() {
+            bail!("Avro datum ends early");
+        }
+        let (taken, rest) = self.input.split_at(count);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("N bytes taken"))
+    }
+
+    /// A zig-zag encoded variable length integer
+    fn long(&mut self) -> Result<i64> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        bail!("Invalid Avro integer")
+    }
+
+    /// A length, index or count, which cannot be negative
+    fn length(&mut self) -> Result<usize> {
+        let value = self.long()?;
+        usize::try_from(value).map_err(|_| anyhow!("Invalid Avro length {}", value))
+    }
+
+    /// Item count of the next array or map block, `None` after the last
+    /// A negative count is followed by the block's size in bytes
+    fn block(&mut self) -> Result<Option<usize>> {
+        let count = self.long()?;
+        if count == 0 {
+            return Ok(None);
+        }
+        if count < 0 {
+            self.long()?;
+        }
+        let count = usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX);
+        if count > self.input.len() || count > self.items {
+            bail!("Avro block of {} items is longer than the datum", count);
+        }
+        self.items -= count;
+        Ok(Some(count))
+    }
+}
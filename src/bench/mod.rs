@@ -0,0 +1,244 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use tokio_postgres::Client;
+use tracing::debug;
+
+use crate::config::Config;
+use crate::db::{self, Database, TableAllowlist};
+use crate::decoder::{decode_message, Decoder, DecoderRegistry, JsonDecoder};
+use crate::ingest::{Ingest, Message};
+use crate::matcher::MappingSet;
+use crate::mqtt::{BridgeOptions, Workers};
+use crate::parser::ParsedMessage;
+use crate::simulate::{self, SensorSpec};
+use crate::sink::{BoxFuture, PostgresSink, Sink};
+use crate::state::BridgeState;
+use crate::tenant::TenantResolver;
+
+/// Topic prefix of generated messages, used to clean up afterwards
+const BENCH_TOPIC_PREFIX: &str = "anvil-bench";
+
+pub struct BenchOptions {
+    pub messages: usize,
+    pub devices: usize,
+    pub sensors: Vec<SensorSpec>,
+    /// Messages written together, 1 behaves like the live bridge
+    pub batch_size: usize,
+    /// Write batches with COPY, as while catching up on a backlog
+    pub copy: bool,
+    /// Leave the generated rows in the database
+    pub keep: bool,
+}
+
+pub struct BenchReport {
+    pub messages: usize,
+    pub rows: usize,
+    /// Writes that failed, their messages are written one at a time
+    pub failed: usize,
+    pub parse_time: Duration,
+    pub insert_time: Duration,
+    /// Commit latency of every batch, sorted ascending
+    pub batch_latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn rows_per_sec(&self) -> f64 {
+        self.rows as f64 / self.insert_time.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn rows_per_batch(&self) -> f64 {
+        self.rows as f64 / self.batch_latencies.len().max(1) as f64
+    }
+
+    /// Batch latency at percentile `p` (0.0 - 1.0)
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.batch_latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let index = ((self.batch_latencies.len() - 1) as f64 * p).round() as usize;
+        self.batch_latencies[index]
+    }
+}
+
+/// Generate messages in-process and drive them through the mappings and
+/// workers of the bridge into the database, measuring each stage
+/// Topics are `anvil-bench/<run>/dev<n>`, decoded and routed by a mapping
+/// of `config` when one matches them
+pub async fn run(config: &Config, options: &BenchOptions) -> Result<BenchReport> {
+    let run_prefix = format!("{}/{}", BENCH_TOPIC_PREFIX, Utc::now().timestamp_millis());
+
+    let devices = options.devices.max(1);
+    let payloads: Vec<(String, String)> = (0..options.messages)
+        .map(|n| {
+            let device = n % devices;
+            let topic = format!("{}/dev{}", run_prefix, device + 1);
+            let phase = device as f64 / devices as f64;
+            let payload = simulate::build_payload(&options.sensors, 60.0, n as f64, phase);
+            (topic, payload)
+        })
+        .collect();
+
+    let mappings = MappingSet::new(config.mqtt.topics.clone());
+    let decoders = DecoderRegistry::from_config(&config.decoders)?;
+    let default: Arc<dyn Decoder> = Arc::new(JsonDecoder);
+    let started = Instant::now();
+    for (topic, payload) in &payloads {
+        let mapping = mappings.find(topic);
+        let decoder = decoders.select(mapping, &default);
+        decode_message(mapping, decoder.as_ref(), topic, payload.as_bytes()).await;
+    }
+    let parse_time = started.elapsed();
+    let tables = cleanup_tables(&mappings, &run_prefix);
+
+    let database = Arc::new(Database::connect(&config.database.url).await?);
+    let sink = PostgresSink::new(database.clone(), TableAllowlist::new(&config.database)?)
+        .with_transactions(db::connect(&config.database.url).await?);
+    let sink = Arc::new(TimedSink::new(sink, options.copy));
+
+    // Batches are taken from the queue as while catching up
+    let mut config = config.clone();
+    config.catchup.batch_size = options.batch_size.max(1);
+    let state = Arc::new(BridgeState::new(&config));
+    if options.batch_size > 1 || options.copy {
+        state.catchup.begin();
+    }
+
+    let (ingest, queues) = Ingest::new(
+        &config.workers,
+        Arc::new(RwLock::new(mappings)),
+        state.clone(),
+    );
+    let bridge_options = BridgeOptions {
+        tenants: TenantResolver::new(&config.tenants)?,
+        decoders,
+        sink: Some(sink.clone()),
+        payloads: config.payloads.clone(),
+        commit_rows: config.database.commit_rows,
+        ..BridgeOptions::default()
+    };
+    let workers = Workers::start(
+        &config.mqtt,
+        database.clone(),
+        state,
+        &ingest,
+        queues,
+        bridge_options,
+    );
+
+    let started = Instant::now();
+    for (topic, payload) in payloads {
+        let message = Message {
+            topic,
+            payload: payload.into_bytes(),
+            ack: None,
+        };
+        ingest.submit(message).await?;
+    }
+    workers.finish(&ingest).await;
+    let insert_time = started.elapsed();
+
+    let mut batch_latencies = std::mem::take(&mut *sink.latencies.lock().unwrap());
+    batch_latencies.sort();
+
+    if !options.keep {
+        cleanup(&database.connection().await?.client, &run_prefix, &tables).await?;
+    }
+
+    Ok(BenchReport {
+        messages: options.messages,
+        rows: sink.rows.load(Ordering::Relaxed),
+        failed: sink.failed.load(Ordering::Relaxed),
+        parse_time,
+        insert_time,
+        batch_latencies,
+    })
+}
+
+/// Times every write to the database, counting the rows written
+struct TimedSink {
+    inner: PostgresSink,
+    /// Bulk writes use COPY rather than a transaction of inserts
+    copy: bool,
+    latencies: Mutex<Vec<Duration>>,
+    rows: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl TimedSink {
+    fn new(inner: PostgresSink, copy: bool) -> Self {
+        Self {
+            inner,
+            copy,
+            latencies: Mutex::new(Vec::new()),
+            rows: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+
+    async fn timed(&self, rows: usize, write: BoxFuture<'_, Result<()>>) -> Result<()> {
+        let started = Instant::now();
+        let result = write.await;
+        self.latencies.lock().unwrap().push(started.elapsed());
+        match &result {
+            Ok(()) => self.rows.fetch_add(rows, Ordering::Relaxed),
+            Err(_) => self.failed.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+}
+
+impl Sink for TimedSink {
+    fn write<'a>(&'a self, message: &'a ParsedMessage) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.timed(1, self.inner.write(message)))
+    }
+
+    fn write_batch<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.timed(messages.len(), self.inner.write_batch(messages)))
+    }
+
+    fn write_bulk<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        let write = if self.copy {
+            self.inner.write_bulk(messages)
+        } else {
+            self.inner.write_batch(messages)
+        };
+        Box::pin(self.timed(messages.len(), write))
+    }
+}
+
+/// Tables the generated rows end up in, those of the bridge and the one a
+/// matching mapping routes them to
+fn cleanup_tables(mappings: &MappingSet, run_prefix: &str) -> Vec<String> {
+    let mut tables: Vec<String> = db::TABLES.iter().map(|table| table.to_string()).collect();
+    let table = mappings
+        .find(&format!("{}/dev1", run_prefix))
+        .and_then(|mapping| mapping.fixed_table());
+    if let Some(table) = table.map(|table| table.to_string()) {
+        if !tables.contains(&table) {
+            tables.push(table);
+        }
+    }
+    tables
+}
+
+async fn cleanup(client: &Client, run_prefix: &str, tables: &[String]) -> Result<()> {
+    let pattern = format!("{}/%", run_prefix);
+
+    for table in tables {
+        let deleted = client
+            .execute(
+                &format!("DELETE FROM {} WHERE topic LIKE $1", table),
+                &[&pattern],
+            )
+            .await
+            .with_context(|| format!("Failed to clean up benchmark rows in {}", table))?;
+        debug!("Removed {} benchmark rows from {}", deleted, table);
+    }
+
+    Ok(())
+}
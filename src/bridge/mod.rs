@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::admin::AdminState;
+use crate::alerts::{self, AlertEngine};
+use crate::config::{Config, DatabaseConfig, MqttConfig};
+use crate::db::{self, Database, TableAllowlist};
+use crate::decoder::{Decoder, DecoderRegistry};
+use crate::discovery::HomeAssistant;
+use crate::downlink;
+use crate::http;
+use crate::ingest::{Ingest, IngestState};
+use crate::introspect;
+use crate::leader;
+use crate::mapping::TopicMapping;
+use crate::matcher::MappingSet;
+use crate::mqtt::{self, BridgeCommand, BridgeOptions, MqttBridge};
+use crate::retention::Retention;
+use crate::sink::{PostgresSink, RetryingSink, Sink};
+use crate::state::BridgeState;
+use crate::stats;
+use crate::tenant::TenantResolver;
+use crate::watchdog::Watchdog;
+
+/// A configured bridge, connected and subscribed, ready to run
+pub struct Bridge {
+    mqtt: MqttBridge,
+    state: Arc<BridgeState>,
+    commands: mpsc::Sender<BridgeCommand>,
+    banner: bool,
+}
+
+/// Assembles a [`Bridge`] from a configuration and extension points
+pub struct BridgeBuilder {
+    config: Config,
+    config_file: Option<String>,
+    mappings_file: Option<String>,
+    decoder: Option<Arc<dyn Decoder>>,
+    decoders: Vec<(String, Arc<dyn Decoder>)>,
+    sink: Option<Arc<dyn Sink>>,
+}
+
+impl Bridge {
+    /// Start from the default configuration
+    pub fn builder() -> BridgeBuilder {
+        BridgeBuilder {
+            config: Config::default(),
+            config_file: None,
+            mappings_file: None,
+            decoder: None,
+            decoders: Vec::new(),
+            sink: None,
+        }
+    }
+
+    /// Runtime state and statistics, as served by the HTTP endpoints
+    pub fn state(&self) -> Arc<BridgeState> {
+        self.state.clone()
+    }
+
+    /// Channel for controlling the running bridge
+    pub fn commands(&self) -> mpsc::Sender<BridgeCommand> {
+        self.commands.clone()
+    }
+
+    /// Process messages until Ctrl+C
+    pub async fn run(self) -> Result<()> {
+        if self.banner {
+            println!();
+            println!(
+                "{}",
+                "Bridge is running. Press Ctrl+C to stop...".bright_green()
+            );
+            println!();
+        }
+
+        self.mqtt.run().await
+    }
+}
+
+impl BridgeBuilder {
+    /// Replace the whole configuration
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn mqtt(mut self, mqtt: MqttConfig) -> Self {
+        self.config.mqtt = mqtt;
+        self
+    }
+
+    pub fn database(mut self, database: DatabaseConfig) -> Self {
+        self.config.database = database;
+        self
+    }
+
+    /// Replace the mappings given in the configuration
+    pub fn mappings(mut self, mappings: Vec<TopicMapping>) -> Self {
+        self.config.mqtt.topics = mappings;
+        self
+    }
+
+    /// Also load mappings from a YAML file or directory, replacing
+    /// `mqtt.mappings`
+    pub fn mappings_file(mut self, path: impl Into<String>) -> Self {
+        self.mappings_file = Some(path.into());
+        self
+    }
+
+    /// Config file the admin API reloads subscriptions from
+    pub fn config_file(mut self, path: impl Into<String>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Decode payloads with `decoder` instead of the built-in JSON decoder
+    pub fn decoder(mut self, decoder: impl Decoder + 'static) -> Self {
+        self.decoder = Some(Arc::new(decoder));
+        self
+    }
+
+    /// Add a decoder mappings can select as `decoder: <name>`
+    pub fn register_decoder(mut self, name: &str, decoder: impl Decoder + 'static) -> Self {
+        self.decoders.push((name.to_string(), Arc::new(decoder)));
+        self
+    }
+
+    /// Write records to `sink` instead of the database tables
+    /// The database is still used for alerts, devices and health checks
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Build the bridge and run it until Ctrl+C
+    pub async fn run(self) -> Result<()> {
+        self.build().await?.run().await
+    }
+
+    /// Connect to the database and broker and start the HTTP endpoints
+    pub async fn build(self) -> Result<Bridge> {
+        let mut config = self.config;
+        if self.mappings_file.is_some() {
+            config.mqtt.mappings = self.mappings_file.clone();
+        }
+        config.load_mappings()?;
+
+        let banner = config.logging.banner;
+
+        if banner {
+            print_banner(&config);
+        } else {
+            info!(
+                broker = %format!("{}:{}", config.mqtt.host, config.mqtt.port),
+                topics = ?config.mqtt.topics.iter().map(|m| m.name()).collect::<Vec<_>>(),
+                "Starting Anvil telemetry bridge"
+            );
+        }
+
+        // Initialize database connection
+        let database = Arc::new(Database::connect(&config.database.url).await?);
+        startup_step(banner, "Connected to TimescaleDB");
+
+        // A custom sink may not write to these tables at all
+        if self.sink.is_none() {
+            introspect::check(&database.current().client, &config).await?;
+            startup_step(banner, "Checked table columns");
+        }
+
+        let alert_engine = match &config.alerts.rules {
+            Some(path) => {
+                let rules = alerts::load_rules(path)?;
+                let engine = AlertEngine::new(rules, database.clone())?;
+                startup_step(
+                    banner,
+                    &format!("Loaded {} alert rules from {}", engine.rule_count(), path),
+                );
+                Some(Arc::new(engine))
+            }
+            None => None,
+        };
+
+        let watchdog = (config.devices.offline_after_secs > 0)
+            .then(|| Arc::new(Watchdog::new(&config.devices, database.clone())));
+
+        let state = Arc::new(BridgeState::new(&config));
+        let (command_tx, command_rx) = mpsc::channel(8);
+
+        if config.logging.summary_interval_secs > 0 {
+            let interval = Duration::from_secs(config.logging.summary_interval_secs);
+            tokio::spawn(stats::report_periodically(state.clone(), interval));
+        }
+
+        // Every source hands its messages to the same workers
+        let mappings = Arc::new(RwLock::new(MappingSet::new(config.mqtt.topics.clone())));
+        let (ingest, queues) = Ingest::new(&config.workers, mappings, state.clone());
+
+        // Start the HTTP server
+        if config.http.enabled {
+            let listener = http::bind(&config.http.bind).await?;
+            let admin = config.http.admin.then(|| {
+                if config.http.admin_token.is_none() {
+                    warn!("Admin API is enabled without an admin_token");
+                }
+                AdminState {
+                    bridge: state.clone(),
+                    commands: command_tx.clone(),
+                    config_path: self.config_file.clone(),
+                    mappings_override: self.mappings_file.clone(),
+                    token: config.http.admin_token.clone(),
+                    database: self.sink.is_none().then(|| database.clone()),
+                }
+            });
+            let ingest = config.http.ingest.then(|| {
+                if config.http.ingest_token.is_none() {
+                    warn!("Ingest endpoint is enabled without an ingest_token");
+                }
+                IngestState {
+                    ingest: ingest.clone(),
+                    token: config.http.ingest_token.clone(),
+                }
+            });
+            tokio::spawn(http::serve(
+                listener,
+                state.clone(),
+                database.clone(),
+                admin,
+                ingest,
+            ));
+            startup_step(
+                banner,
+                &format!("HTTP endpoints available at http://{}", config.http.bind),
+            );
+        }
+
+        // Initialize MQTT client
+        let allowlist = TableAllowlist::new(&config.database)?;
+        for mapping in &config.mqtt.topics {
+            if let Some(table) = mapping.fixed_table() {
+                allowlist.check(&table).with_context(|| {
+                    format!("Mapping {} targets a forbidden table", mapping.name())
+                })?;
+            }
+        }
+
+        let mut decoders = DecoderRegistry::from_config(&config.decoders)?;
+        for (name, decoder) in self.decoders {
+            decoders.register(&name, decoder);
+        }
+        for mapping in &config.mqtt.topics {
+            if let Some(decoder) = &mapping.spec().decoder {
+                if !decoders.contains(decoder) {
+                    bail!(
+                        "Mapping {} uses unknown decoder {}",
+                        mapping.name(),
+                        decoder
+                    );
+                }
+            }
+        }
+
+        // A standby waits here, serving HTTP but before it connects to any
+        // broker
+        let leadership = match &config.ha {
+            Some(ha) => {
+                state.set_standby(true);
+                let leadership = leader::acquire(ha, &config.database.url).await?;
+                state.set_standby(false);
+                startup_step(banner, "Elected leader");
+                Some(leadership)
+            }
+            None => None,
+        };
+
+        let tenant_schemas = TenantResolver::new(&config.tenants)?
+            .map(|tenants| tenants.schemas())
+            .unwrap_or_default();
+        if let Some(retention) =
+            Retention::new(&config.retention, &config.database.url, tenant_schemas)
+        {
+            tokio::spawn(retention.run());
+            startup_step(
+                banner,
+                &format!(
+                    "Raw messages kept for {}s",
+                    config.retention.raw_messages_secs
+                ),
+            );
+        }
+
+        let mut publishers = HashMap::new();
+        for broker in &config.brokers {
+            publishers.insert(broker.name.clone(), mqtt::connect_publisher(broker));
+        }
+        for mapping in &config.mqtt.topics {
+            let broker = mapping
+                .spec()
+                .republish
+                .as_ref()
+                .and_then(|republish| republish.broker.as_ref());
+            if let Some(broker) = broker {
+                if !publishers.contains_key(broker) {
+                    bail!(
+                        "Mapping {} republishes to unknown broker {}",
+                        mapping.name(),
+                        broker
+                    );
+                }
+            }
+        }
+
+        let sink = match self.sink {
+            Some(sink) => sink,
+            None => {
+                // The rows of a message, and the COPY of catching up, are
+                // written in transactions on a connection of their own
+                let sink = PostgresSink::new(database.clone(), allowlist)
+                    .with_transactions(db::connect(&config.database.url).await?);
+                Arc::new(sink)
+            }
+        };
+        let mut sink = RetryingSink::new(sink, config.database.retry.clone());
+        // Spilled rows of acknowledged messages would be lost on a restart
+        if config.mqtt.session_file.is_some() {
+            sink = sink.without_spill();
+        }
+        let sink = Arc::new(sink);
+
+        let options = BridgeOptions {
+            alerts: alert_engine,
+            register_devices: config.devices.registry,
+            watchdog,
+            tenants: TenantResolver::new(&config.tenants)?,
+            decoder: self.decoder,
+            decoders,
+            sink: Some(sink),
+            discovery: HomeAssistant::new(&config.discovery),
+            payloads: config.payloads.clone(),
+            publishers,
+            commit_rows: config.database.commit_rows,
+        };
+
+        if let Some(nats) = &config.nats {
+            #[cfg(feature = "nats")]
+            {
+                crate::nats::start(nats, ingest.clone()).await?;
+                startup_step(
+                    banner,
+                    &format!("Subscribed to {} NATS subjects", nats.subjects.len()),
+                );
+            }
+            #[cfg(not(feature = "nats"))]
+            {
+                let _ = nats;
+                bail!("Reading from NATS needs anvil built with the nats feature");
+            }
+        }
+
+        if let Some(kafka) = &config.kafka {
+            #[cfg(feature = "kafka")]
+            {
+                crate::kafka::start(kafka, ingest.clone())?;
+                startup_step(
+                    banner,
+                    &format!("Subscribed to {} Kafka topics", kafka.topics.len()),
+                );
+            }
+            #[cfg(not(feature = "kafka"))]
+            {
+                let _ = kafka;
+                bail!("Reading from Kafka needs anvil built with the kafka feature");
+            }
+        }
+
+        if let Some(amqp) = &config.amqp {
+            #[cfg(feature = "amqp")]
+            {
+                crate::amqp::start(amqp, ingest.clone()).await?;
+                startup_step(banner, &format!("Consuming AMQP queue {}", amqp.queue));
+            }
+            #[cfg(not(feature = "amqp"))]
+            {
+                let _ = amqp;
+                bail!("Reading from AMQP needs anvil built with the amqp feature");
+            }
+        }
+
+        let mqtt = MqttBridge::new(
+            config.mqtt.clone(),
+            database,
+            state.clone(),
+            command_rx,
+            ingest,
+            queues,
+            options,
+        )
+        .await?;
+        startup_step(banner, "MQTT client started");
+
+        if let Some(downlink) = &config.downlink {
+            downlink::start(downlink, &config.database.url, mqtt.client()).await?;
+            startup_step(
+                banner,
+                &format!("Publishing commands from {}", downlink.table),
+            );
+        }
+
+        if let Some(leadership) = leadership {
+            tokio::spawn(leadership.watch(command_tx.clone()));
+        }
+
+        Ok(Bridge {
+            mqtt,
+            state,
+            commands: command_tx,
+            banner,
+        })
+    }
+}
+
+fn print_banner(config: &Config) {
+    println!("{}", "Anvil Telemetry Bridge".bright_cyan().bold());
+    println!("{}", "======================".bright_cyan());
+    println!();
+
+    println!(
+        "{} {}",
+        "MQTT Broker:".bright_green(),
+        format!("{}:{}", config.mqtt.host, config.mqtt.port).yellow()
+    );
+    println!(
+        "{} {} topics",
+        "Subscribed to:".bright_green(),
+        config.mqtt.topics.len().to_string().yellow()
+    );
+    for mapping in &config.mqtt.topics {
+        match &mapping.spec().table {
+            Some(table) => println!(
+                "  {} {} {} {}",
+                "→".dimmed(),
+                mapping.name().cyan(),
+                "→".dimmed(),
+                table.cyan()
+            ),
+            None => println!("  {} {}", "→".dimmed(), mapping.name().cyan()),
+        }
+    }
+    println!();
+}
+
+/// Report a startup step on the banner, or as a log line when the
+/// banner is disabled
+fn startup_step(banner: bool, message: &str) {
+    if banner {
+        println!("{} {}", "✓".green(), message.green());
+    } else {
+        info!("{}", message);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::config::ErrorsConfig;
+
+/// Errors of each mapping, sampled for the log, and the share of its
+/// messages failing to insert that disables the mapping
+pub struct ErrorBudget {
+    config: ErrorsConfig,
+    mappings: Mutex<HashMap<String, Budget>>,
+}
+
+#[derive(Default)]
+struct Budget {
+    /// Errors since the last one logged
+    unlogged: u64,
+    last_logged: Option<Instant>,
+    window_started: Option<Instant>,
+    messages: u64,
+    failures: u64,
+    disabled: Option<Disabled>,
+}
+
+/// A mapping dropping its messages until the cooldown ends and a probe
+/// message is stored
+struct Disabled {
+    until: Instant,
+    cooldown: Duration,
+    /// When the message let through as a probe was, while its outcome is
+    /// not known
+    probing: Option<Instant>,
+}
+
+impl ErrorBudget {
+    pub fn new(config: &ErrorsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            mappings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the messages of `mapping` may be stored, false while it is
+    /// disabled except for the one probe after each cooldown
+    pub fn admit(&self, mapping: &str) -> bool {
+        let mut mappings = self.mappings.lock().unwrap();
+        let Some(disabled) = mappings
+            .get_mut(mapping)
+            .and_then(|budget| budget.disabled.as_mut())
+        else {
+            return true;
+        };
+
+        let now = Instant::now();
+        // A probe without an outcome, e.g. one with nothing to insert, is
+        // given up on after a cooldown
+        let probe_pending = disabled
+            .probing
+            .is_some_and(|started| now < started + disabled.cooldown);
+        if now < disabled.until || probe_pending {
+            return false;
+        }
+        disabled.probing = Some(now);
+        true
+    }
+
+    /// Seconds until `mapping` is probed again, `None` when it is enabled
+    pub fn disabled_for(&self, mapping: &str) -> Option<u64> {
+        let mappings = self.mappings.lock().unwrap();
+        let disabled = mappings.get(mapping)?.disabled.as_ref()?;
+        Some(
+            disabled
+                .until
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        )
+    }
+
+    /// Count a stored message of `mapping`, failed when any of its inserts
+    /// did, disabling the mapping once too many fail and enabling it again
+    /// after a probe succeeds
+    pub fn record_message(&self, mapping: &str, failed: bool) {
+        let mut mappings = self.mappings.lock().unwrap();
+        let budget = mappings.entry(mapping.to_string()).or_default();
+        let now = Instant::now();
+
+        if let Some(disabled) = &mut budget.disabled {
+            if disabled.probing.take().is_none() {
+                return;
+            }
+            if failed {
+                let max = Duration::from_secs(self.config.max_cooldown_secs.max(1));
+                disabled.cooldown = (disabled.cooldown * 2).min(max);
+                disabled.until = now + disabled.cooldown;
+                warn!(
+                    "Probe of mapping {} failed, disabled for {}s more",
+                    mapping,
+                    disabled.cooldown.as_secs()
+                );
+            } else {
+                info!("Probe of mapping {} succeeded, enabling it", mapping);
+                budget.disabled = None;
+                budget.window_started = None;
+            }
+            return;
+        }
+
+        if self.config.max_failure_rate <= 0.0 {
+            return;
+        }
+
+        let window = Duration::from_secs(self.config.window_secs.max(1));
+        if budget
+            .window_started
+            .is_none_or(|started| now >= started + window)
+        {
+            budget.window_started = Some(now);
+            budget.messages = 0;
+            budget.failures = 0;
+        }
+        budget.messages += 1;
+        if failed {
+            budget.failures += 1;
+        }
+
+        let rate = budget.failures as f64 / budget.messages as f64;
+        if budget.messages >= self.config.min_messages.max(1)
+            && rate >= self.config.max_failure_rate
+        {
+            let cooldown = Duration::from_secs(self.config.cooldown_secs.max(1));
+            warn!(
+                "Disabling mapping {} for {}s, {} of {} messages failed to insert",
+                mapping,
+                cooldown.as_secs(),
+                budget.failures,
+                budget.messages
+            );
+            budget.disabled = Some(Disabled {
+                until: now + cooldown,
+                cooldown,
+                probing: None,
+            });
+        }
+    }
+
+    /// Log an error of `mapping`, sampled so a mapping failing every
+    /// message does not flood the log
+    pub fn log_error(&self, mapping: &str, context: &str, e: &anyhow::Error) {
+        let mut mappings = self.mappings.lock().unwrap();
+        let budget = mappings.entry(mapping.to_string()).or_default();
+        self.log(mapping, budget, Instant::now(), context, e);
+    }
+
+    /// Forget the mappings not in `subscriptions`
+    pub fn retain(&self, subscriptions: &[String]) {
+        self.mappings
+            .lock()
+            .unwrap()
+            .retain(|name, _| subscriptions.contains(name));
+    }
+
+    /// Log the first error, then at most one per interval and every Nth,
+    /// each with the count of errors left out since the last
+    fn log(
+        &self,
+        mapping: &str,
+        budget: &mut Budget,
+        now: Instant,
+        context: &str,
+        e: &anyhow::Error,
+    ) {
+        budget.unlogged += 1;
+
+        let interval = Duration::from_secs(self.config.log_interval_secs);
+        let due = budget
+            .last_logged
+            .is_none_or(|logged| now >= logged + interval);
+        let nth = self.config.log_every > 0 && budget.unlogged >= self.config.log_every;
+        if !due && !nth {
+            return;
+        }
+
+        match budget.unlogged - 1 {
+            0 => error!("{} on {}: {:#}", context, mapping, e),
+            skipped => error!(
+                "{} on {}: {:#} ({} more errors since the last logged)",
+                context, mapping, e, skipped
+            ),
+        }
+        budget.unlogged = 0;
+        budget.last_logged = Some(now);
+    }
+}
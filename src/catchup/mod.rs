@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::config::CatchupConfig;
+use crate::metrics::metrics;
+
+/// Whether the workers are catching up on a backlog, found by the rate
+/// messages arrive at right after connecting to the broker
+pub struct CatchUp {
+    config: CatchupConfig,
+    active: AtomicBool,
+    rate: Mutex<Rate>,
+}
+
+struct Rate {
+    /// When the bridge last connected to the broker
+    connected: Option<Instant>,
+    /// Start of the interval messages are being counted over
+    since: Instant,
+    messages: u64,
+    /// When catching up began, with the messages received since
+    started: Option<(Instant, u64)>,
+}
+
+impl CatchUp {
+    pub fn new(config: &CatchupConfig) -> Self {
+        Self {
+            config: config.clone(),
+            active: AtomicBool::new(false),
+            rate: Mutex::new(Rate {
+                connected: None,
+                since: Instant::now(),
+                messages: 0,
+                started: None,
+            }),
+        }
+    }
+
+    /// Whether messages are written in batches
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Most messages a worker writes at once while catching up
+    pub fn batch_size(&self) -> usize {
+        self.config.batch_size.max(1)
+    }
+
+    /// Time a worker waits for more messages to fill a batch
+    pub fn batch_wait(&self) -> Duration {
+        Duration::from_millis(self.config.batch_wait_ms)
+    }
+
+    /// Look for a backlog from now on
+    pub fn connected(&self) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut rate = self.rate.lock().unwrap();
+        let now = Instant::now();
+        rate.connected = Some(now);
+        rate.since = now;
+        rate.messages = 0;
+    }
+
+    /// Count a message received from the broker
+    pub fn received(&self) {
+        if self.config.enabled {
+            self.rate.lock().unwrap().messages += 1;
+        }
+    }
+
+    /// Write in batches from now on, until caught up
+    pub fn begin(&self) {
+        let mut rate = self.rate.lock().unwrap();
+        self.start(&mut rate, Instant::now(), 0);
+    }
+
+    fn start(&self, rate: &mut Rate, now: Instant, messages: u64) {
+        rate.started = Some((now, messages));
+        rate.connected = None;
+        self.active.store(true, Ordering::Relaxed);
+        metrics().catching_up.set(1);
+    }
+
+    /// Switch modes by the rate messages arrived at since the last tick,
+    /// called about every second with the messages waiting for a worker
+    /// A queue filled up right after connecting also means a backlog, one
+    /// arriving faster than the workers store it
+    pub fn tick(&self, backlog: usize, queue_full: bool) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut rate = self.rate.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(rate.since).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let per_second = rate.messages as f64 / elapsed;
+        let messages = rate.messages;
+        rate.since = now;
+        rate.messages = 0;
+
+        match rate.started {
+            None => {
+                let detecting = rate.connected.is_some_and(|connected| {
+                    now.duration_since(connected) <= Duration::from_secs(self.config.detect_secs)
+                });
+                if detecting && (per_second >= self.config.enter_rate || queue_full) {
+                    info!(
+                        "Catching up on a backlog at {:.0} messages/s, writing up to {} \
+                         messages at once",
+                        per_second,
+                        self.batch_size()
+                    );
+                    self.start(&mut rate, now, messages);
+                }
+            }
+            Some((started, received)) => {
+                let received = received + messages;
+                if per_second < self.config.exit_rate && backlog == 0 {
+                    info!(
+                        "Caught up on {} messages in {}s, writing messages as they arrive",
+                        received,
+                        now.duration_since(started).as_secs()
+                    );
+                    rate.started = None;
+                    self.active.store(false, Ordering::Relaxed);
+                    metrics().catching_up.set(0);
+                } else {
+                    rate.started = Some((started, received));
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,158 @@
-use anyhow::{Context, Result};
-use chrono::{DateTime, Utc};
-use tokio_postgres::{Client, NoTls};
-use tracing::{debug, error};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use tokio_postgres::{Client, GenericClient, NoTls, Statement};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{DatabaseConfig, TenantRouting};
+use crate::metrics::metrics;
+use crate::parser::ParsedMessage;
+use crate::tenant::Tenant;
+
+pub const TELEMETRY_TABLE: &str = "telemetry";
+pub const RAW_MESSAGES_TABLE: &str = "raw_messages";
+pub const DEVICES_TABLE: &str = "devices";
+
+/// Tables the bridge writes to
+pub const TABLES: &[&str] = &[TELEMETRY_TABLE, RAW_MESSAGES_TABLE];
+
+/// Schema unqualified table names are assumed to live in
+pub const DEFAULT_SCHEMA: &str = "public";
+
+/// Longest identifier PostgreSQL keeps without truncating
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Names accepted for schemas and tables coming from configuration or
+/// messages: lowercase letters, digits and underscores, not starting with
+/// a digit
+pub fn valid_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_IDENTIFIER_LEN
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Quote an identifier for interpolation into SQL
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// A table name, optionally qualified with its schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableName {
+    pub schema: Option<String>,
+    pub table: String,
+}
+
+impl TableName {
+    pub fn new(table: &str) -> Self {
+        Self {
+            schema: None,
+            table: table.to_string(),
+        }
+    }
+
+    /// Parse and validate `table` or `schema.table`
+    pub fn parse(name: &str) -> Result<Self> {
+        let (schema, table) = match name.split_once('.') {
+            Some((schema, table)) => (Some(schema), table),
+            None => (None, name),
+        };
+
+        if !valid_identifier(table) || !schema.is_none_or(valid_identifier) {
+            bail!("Invalid table name: {}", name);
+        }
+
+        Ok(Self {
+            schema: schema.map(str::to_string),
+            table: table.to_string(),
+        })
+    }
+
+    pub fn schema_or_default(&self) -> &str {
+        self.schema.as_deref().unwrap_or(DEFAULT_SCHEMA)
+    }
+
+    /// The name quoted for use in SQL
+    pub fn quoted(&self) -> String {
+        match &self.schema {
+            Some(schema) => format!(
+                "{}.{}",
+                quote_identifier(schema),
+                quote_identifier(&self.table)
+            ),
+            None => quote_identifier(&self.table),
+        }
+    }
+}
+
+impl fmt::Display for TableName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.schema {
+            Some(schema) => write!(f, "{}.{}", schema, self.table),
+            None => write!(f, "{}", self.table),
+        }
+    }
+}
+
+/// Schemas and tables the bridge may write to, anything when unset
+#[derive(Debug, Clone, Default)]
+pub struct TableAllowlist {
+    schemas: Vec<String>,
+    /// Entries qualified with their schema
+    tables: Vec<String>,
+}
+
+impl TableAllowlist {
+    pub fn new(config: &DatabaseConfig) -> Result<Self> {
+        let tables = config
+            .allowed_tables
+            .iter()
+            .map(|name| {
+                let table = TableName::parse(name)
+                    .with_context(|| "Invalid entry in database.allowed_tables")?;
+                Ok(format!("{}.{}", table.schema_or_default(), table.table))
+            })
+            .collect::<Result<_>>()?;
+
+        for schema in &config.allowed_schemas {
+            if !valid_identifier(schema) {
+                bail!("Invalid entry in database.allowed_schemas: {}", schema);
+            }
+        }
+
+        Ok(Self {
+            schemas: config.allowed_schemas.clone(),
+            tables,
+        })
+    }
+
+    pub fn check(&self, table: &TableName) -> Result<()> {
+        let schema = table.schema_or_default();
+
+        if !self.schemas.is_empty() && !self.schemas.iter().any(|s| s == schema) {
+            bail!("Writing to schema {} is not allowed", schema);
+        }
+
+        let qualified = format!("{}.{}", schema, table.table);
+        if !self.tables.is_empty() && !self.tables.contains(&qualified) {
+            bail!("Writing to table {} is not allowed", qualified);
+        }
+
+        Ok(())
+    }
+}
 
 pub async fn connect(database_url: &str) -> Result<Client> {
     let (client, connection) = tokio_postgres::connect(database_url, NoTls)
@@ -18,32 +169,326 @@ pub async fn connect(database_url: &str) -> Result<Client> {
     Ok(client)
 }
 
-#[derive(Debug)]
+/// The bridge's database connection, shared by the sink, the health
+/// checks and the other writers, opened again once it closes
+pub struct Database {
+    connection: RwLock<Arc<Connection>>,
+    /// Connections are opened again to, when set
+    url: Option<String>,
+    /// Held while reconnecting, so callers finding the connection closed
+    /// together open one new connection
+    reconnecting: tokio::sync::Mutex<()>,
+}
+
+/// A connection with the statements prepared on it
+pub struct Connection {
+    pub client: Client,
+    pub statements: StatementCache,
+}
+
+impl Connection {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            statements: StatementCache::default(),
+        }
+    }
+}
+
+impl Database {
+    /// Connect to `url`, connecting again whenever the connection is
+    /// found closed
+    pub async fn connect(url: &str) -> Result<Self> {
+        let mut database = Self::new(connect(url).await?);
+        database.url = Some(url.to_string());
+        Ok(database)
+    }
+
+    /// `client`, not replaced once it closes
+    pub fn new(client: Client) -> Self {
+        Self {
+            connection: RwLock::new(Arc::new(Connection::new(client))),
+            url: None,
+            reconnecting: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Where connections are opened again to
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// The connection as it is, open or closed
+    pub fn current(&self) -> Arc<Connection> {
+        self.connection.read().unwrap().clone()
+    }
+
+    /// The connection, opened again first when it closed
+    /// A closed connection that cannot be replaced is returned as it is,
+    /// its queries failing
+    pub async fn connection(&self) -> Result<Arc<Connection>> {
+        let current = self.current();
+        let Some(url) = self.url.as_deref().filter(|_| current.client.is_closed()) else {
+            return Ok(current);
+        };
+
+        let _reconnecting = self.reconnecting.lock().await;
+        let current = self.current();
+        if !current.client.is_closed() {
+            return Ok(current);
+        }
+        info!("Reconnecting to the database");
+        let connection = Arc::new(Connection::new(connect(url).await?));
+        *self.connection.write().unwrap() = connection.clone();
+        Ok(connection)
+    }
+
+    /// Whether the database can be reached, reconnecting when needed
+    pub async fn connected(&self) -> bool {
+        self.connection()
+            .await
+            .is_ok_and(|connection| !connection.client.is_closed())
+    }
+}
+
+/// Statements prepared on one connection, by their SQL, only valid on
+/// that connection
+#[derive(Default)]
+pub struct StatementCache {
+    statements: Mutex<HashMap<String, Statement>>,
+}
+
+impl StatementCache {
+    /// The statement for `sql`, prepared on `client` the first time
+    pub async fn prepare(&self, client: &impl GenericClient, sql: &str) -> Result<Statement> {
+        if let Some(statement) = self.statements.lock().unwrap().get(sql) {
+            return Ok(statement.clone());
+        }
+
+        let statement = client.prepare(sql).await?;
+        self.statements
+            .lock()
+            .unwrap()
+            .insert(sql.to_string(), statement.clone());
+        Ok(statement)
+    }
+}
+
+/// Insert a batch of parsed messages inside a single transaction
+/// Either every record in the batch is written or none of them are
+pub async fn insert_batch(
+    client: &mut Client,
+    statements: &StatementCache,
+    wide_columns: &WideColumns,
+    messages: &[ParsedMessage],
+) -> Result<()> {
+    add_wide_columns(client, wide_columns, messages).await?;
+    let transaction = client
+        .transaction()
+        .await
+        .with_context(|| "Failed to start transaction")?;
+
+    for message in messages {
+        match message {
+            ParsedMessage::TelemetryReading(reading) => {
+                reading.insert(&transaction, statements).await?
+            }
+            ParsedMessage::RawMessage(msg) => msg.insert(&transaction).await?,
+            ParsedMessage::WideRow(row) => row.insert(&transaction, wide_columns).await?,
+        }
+    }
+
+    transaction
+        .commit()
+        .await
+        .with_context(|| "Failed to commit transaction")?;
+
+    debug!("Committed batch of {} records", messages.len());
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
 pub struct TelemetryReading {
     pub device_id: String,
     pub sensor_name: String,
     pub value: f64,
     pub topic: String,
     pub timestamp: DateTime<Utc>,
+    pub tenant: Option<Tenant>,
+    /// Table replacing `telemetry`, set by mappings with a `table`
+    pub table: Option<TableName>,
+    /// Identity of the reading, set by mappings with a `dedupe_key`
+    pub dedupe_key: Option<String>,
+    /// Unit of measurement, set by mappings with `sensors`
+    pub unit: Option<String>,
+    /// Kind of quantity, e.g. `temperature`, set by mappings with `sensors`
+    pub device_class: Option<String>,
+    /// Outcome of the mapping's `validate` rules, with the rule broken
+    pub quality: Option<(Quality, Option<String>)>,
+    /// Seconds added to the device's timestamp by `clock_correction`
+    pub clock_offset: Option<f64>,
+    /// Statement writing the reading instead of the generated insert, set
+    /// by mappings with `insert_sql`
+    pub custom_insert: Option<CustomInsert>,
+}
+
+/// Trust in a reading, from the validation rules of its mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    #[default]
+    Good,
+    /// Plausible but unusual, e.g. outside the normal operating range
+    Suspect,
+    /// Most likely a sensor fault
+    Bad,
+}
+
+impl Quality {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Suspect => "suspect",
+            Quality::Bad => "bad",
+        }
+    }
 }
 
-#[derive(Debug)]
+impl ToSql for Quality {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> std::result::Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.as_str().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+#[derive(Debug, Clone)]
 pub struct RawMessage {
     pub topic: String,
     pub payload: String,
     pub timestamp: DateTime<Utc>,
+    pub tenant: Option<Tenant>,
+    /// Size in bytes of a received payload `payload` only holds part or a
+    /// digest of, set for oversized payloads
+    pub payload_size: Option<i64>,
+}
+
+/// Table a record of `tenant` is written to, schema routing replaces the
+/// schema of `table`
+fn destination(table: TableName, tenant: &Option<Tenant>) -> TableName {
+    match tenant {
+        Some(tenant) if tenant.routing == TenantRouting::Schema => TableName {
+            schema: Some(tenant.schema()),
+            table: table.table,
+        },
+        _ => table,
+    }
+}
+
+/// Value of the `tenant_id` column when tenants share tables
+fn tenant_column(tenant: &Option<Tenant>) -> Option<&str> {
+    match tenant {
+        Some(tenant) if tenant.routing == TenantRouting::Column => Some(&tenant.id),
+        _ => None,
+    }
 }
 
 impl TelemetryReading {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
-        client
-            .execute(
-                "INSERT INTO telemetry (timestamp, device_id, sensor_name, value, topic) VALUES ($1, $2, $3, $4, $5)",
-                &[&self.timestamp, &self.device_id, &self.sensor_name, &self.value, &self.topic],
-            )
+    /// Columns written for this reading, with their types and values
+    fn columns(&self) -> Vec<(&'static str, Type, &(dyn ToSql + Sync))> {
+        let mut columns: Vec<(&str, Type, &(dyn ToSql + Sync))> = vec![
+            ("timestamp", Type::TIMESTAMPTZ, &self.timestamp),
+            ("device_id", Type::TEXT, &self.device_id),
+            ("sensor_name", Type::TEXT, &self.sensor_name),
+            ("value", Type::FLOAT8, &self.value),
+            ("topic", Type::TEXT, &self.topic),
+        ];
+        if let Some(tenant) = &self.tenant {
+            if tenant.routing == TenantRouting::Column {
+                columns.push(("tenant_id", Type::TEXT, &tenant.id));
+            }
+        }
+        if let Some(dedupe_key) = &self.dedupe_key {
+            columns.push(("dedupe_key", Type::TEXT, dedupe_key));
+        }
+        if let Some(unit) = &self.unit {
+            columns.push(("unit", Type::TEXT, unit));
+        }
+        if let Some(device_class) = &self.device_class {
+            columns.push(("device_class", Type::TEXT, device_class));
+        }
+        if let Some((quality, reason)) = &self.quality {
+            columns.push(("quality", Type::TEXT, quality));
+            columns.push(("quality_reason", Type::TEXT, reason));
+        }
+        if let Some(clock_offset) = &self.clock_offset {
+            columns.push(("clock_offset", Type::FLOAT8, clock_offset));
+        }
+        columns
+    }
+
+    /// Table this reading is written to
+    pub fn target(&self) -> TableName {
+        let table = self
+            .table
+            .clone()
+            .unwrap_or_else(|| TableName::new(TELEMETRY_TABLE));
+        destination(table, &self.tenant)
+    }
+
+    /// Insert the reading, a mapping's `insert_sql` prepared once per
+    /// connection in `statements`
+    pub async fn insert(
+        &self,
+        client: &impl GenericClient,
+        statements: &StatementCache,
+    ) -> Result<()> {
+        if let Some(custom) = &self.custom_insert {
+            return custom.execute(client, statements, self).await;
+        }
+        let (columns, params): (Vec<&str>, Vec<&(dyn ToSql + Sync)>) = self
+            .columns()
+            .into_iter()
+            .map(|(column, _, param)| (column, param))
+            .unzip();
+
+        let placeholders: Vec<String> = (1..=params.len()).map(|i| format!("${}", i)).collect();
+        let mut sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            self.target().quoted(),
+            columns.join(", "),
+            placeholders.join(", ")
+        );
+        // Redeliveries of a stored reading hit the unique dedupe_key index
+        if self.dedupe_key.is_some() {
+            sql.push_str(" ON CONFLICT DO NOTHING");
+        }
+
+        let inserted = client
+            .execute(&sql, &params)
             .await
             .with_context(|| "Failed to insert telemetry reading")?;
 
+        if inserted == 0 {
+            debug!(
+                "Skipped duplicate telemetry: device={}, sensor={}, key={}",
+                self.device_id,
+                self.sensor_name,
+                self.dedupe_key.as_deref().unwrap_or_default()
+            );
+            metrics().duplicates_skipped.inc();
+            return Ok(());
+        }
+
         debug!(
             "Inserted telemetry: device={}, sensor={}, value={}",
             self.device_id, self.sensor_name, self.value
@@ -54,12 +499,36 @@ impl TelemetryReading {
 }
 
 impl RawMessage {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
+    /// Table this message is written to
+    pub fn target(&self) -> TableName {
+        destination(TableName::new(RAW_MESSAGES_TABLE), &self.tenant)
+    }
+
+    pub async fn insert(&self, client: &impl GenericClient) -> Result<()> {
+        let tenant_id = tenant_column(&self.tenant);
+
+        let mut columns = vec!["timestamp", "topic", "payload"];
+        let mut params: Vec<&(dyn ToSql + Sync)> =
+            vec![&self.timestamp, &self.topic, &self.payload];
+        if let Some(tenant_id) = &tenant_id {
+            columns.push("tenant_id");
+            params.push(tenant_id);
+        }
+        if let Some(payload_size) = &self.payload_size {
+            columns.push("payload_size");
+            params.push(payload_size);
+        }
+
+        let placeholders: Vec<String> = (1..=params.len()).map(|i| format!("${}", i)).collect();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            self.target().quoted(),
+            columns.join(", "),
+            placeholders.join(", ")
+        );
+
         client
-            .execute(
-                "INSERT INTO raw_messages (timestamp, topic, payload) VALUES ($1, $2, $3)",
-                &[&self.timestamp, &self.topic, &self.payload],
-            )
+            .execute(&sql, &params)
             .await
             .with_context(|| "Failed to insert raw message")?;
 
@@ -68,3 +537,662 @@ impl RawMessage {
         Ok(())
     }
 }
+
+/// A whole message stored as one row, by mappings with `pivot`
+#[derive(Debug, Clone)]
+pub struct WideRow {
+    pub device_id: String,
+    pub topic: String,
+    pub timestamp: DateTime<Utc>,
+    pub tenant: Option<Tenant>,
+    pub table: TableName,
+    /// Payload fields by column, numbers, strings and booleans
+    pub fields: Vec<(String, Value)>,
+    /// Add a column for a field the table does not have yet, rather than
+    /// leaving the field out
+    pub auto_add_columns: bool,
+}
+
+/// Type of each column of a table, as written in a cast
+/// `None` marks a column found missing and not added
+type ColumnTypes = HashMap<String, Option<String>>;
+
+/// Columns of the tables wide rows are written to, read from the
+/// database once per table
+#[derive(Default)]
+pub struct WideColumns {
+    /// By quoted table name
+    tables: Mutex<HashMap<String, ColumnTypes>>,
+}
+
+impl WideColumns {
+    async fn get(&self, client: &impl GenericClient, table: &TableName) -> Result<ColumnTypes> {
+        let cached = self.tables.lock().unwrap().get(&table.quoted()).cloned();
+        match cached {
+            Some(types) => Ok(types),
+            None => self.read(client, table).await,
+        }
+    }
+
+    /// Read the columns of `table` from the database again
+    async fn read(&self, client: &impl GenericClient, table: &TableName) -> Result<ColumnTypes> {
+        let types = table_columns(client, table).await?;
+        self.set(table, types.clone());
+        Ok(types)
+    }
+
+    fn set(&self, table: &TableName, types: ColumnTypes) {
+        self.tables.lock().unwrap().insert(table.quoted(), types);
+    }
+
+    /// Read the columns of `table` again next time
+    fn forget(&self, table: &TableName) {
+        self.tables.lock().unwrap().remove(&table.quoted());
+    }
+}
+
+impl WideRow {
+    /// Table this row is written to
+    pub fn target(&self) -> TableName {
+        destination(self.table.clone(), &self.tenant)
+    }
+
+    /// Insert the row, leaving out fields the table has no column for
+    /// Missing columns are added by [`add_columns`](Self::add_columns)
+    /// beforehand
+    pub async fn insert(
+        &self,
+        client: &impl GenericClient,
+        wide_columns: &WideColumns,
+    ) -> Result<()> {
+        let target = self.target();
+        let types = self.column_types(client, wide_columns, &target).await?;
+        let tenant_id = tenant_column(&self.tenant);
+
+        let mut columns = vec![
+            "timestamp".to_string(),
+            "device_id".to_string(),
+            "topic".to_string(),
+        ];
+        let mut values = vec!["$1".to_string(), "$2".to_string(), "$3".to_string()];
+        let mut params: Vec<&(dyn ToSql + Sync)> =
+            vec![&self.timestamp, &self.device_id, &self.topic];
+        if let Some(tenant_id) = &tenant_id {
+            columns.push("tenant_id".to_string());
+            params.push(tenant_id);
+            values.push(format!("${}", params.len()));
+        }
+
+        // Sent as text and cast, so a field fits whatever type its column has
+        let texts: Vec<(&str, String, &str)> = self
+            .fields
+            .iter()
+            .filter_map(|(column, value)| {
+                let data_type = types.get(column)?.as_deref()?;
+                let text = match value {
+                    Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                };
+                Some((column.as_str(), text, data_type))
+            })
+            .collect();
+        for (column, text, data_type) in &texts {
+            columns.push(quote_identifier(column));
+            params.push(text);
+            values.push(format!("${}::text::{}", params.len(), data_type));
+        }
+
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            target.quoted(),
+            columns.join(", "),
+            values.join(", ")
+        );
+        if let Err(e) = client.execute(&sql, &params).await {
+            // The table may have changed, read its columns again next time
+            wide_columns.forget(&target);
+            return Err(e).with_context(|| "Failed to insert wide row");
+        }
+
+        debug!(
+            "Inserted wide row: device={}, columns={}",
+            self.device_id,
+            texts.len()
+        );
+        Ok(())
+    }
+
+    /// Add the columns the table lacks for fields of the row, when
+    /// `auto_add_columns` is set
+    /// Run outside any transaction, so the table is locked only briefly
+    /// and added columns stay when a batch is rolled back
+    pub async fn add_columns(
+        &self,
+        client: &impl GenericClient,
+        wide_columns: &WideColumns,
+    ) -> Result<()> {
+        if !self.auto_add_columns {
+            return Ok(());
+        }
+        let table = self.target();
+        let types = wide_columns.get(client, &table).await?;
+
+        // Including columns found missing before a mapping could add them
+        let missing: Vec<&(String, Value)> = self
+            .fields
+            .iter()
+            .filter(|(column, _)| !matches!(types.get(column), Some(Some(_))))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        for (column, value) in &missing {
+            let data_type = match value {
+                Value::Number(_) => "double precision",
+                Value::Bool(_) => "boolean",
+                _ => "text",
+            };
+            let sql = format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                table.quoted(),
+                quote_identifier(column),
+                data_type
+            );
+            client
+                .batch_execute(&sql)
+                .await
+                .with_context(|| format!("Failed to add column {} to {}", column, table))?;
+            info!("Added column {} ({}) to {}", column, data_type, table);
+        }
+        // Another instance may have added them first, with other types
+        wide_columns.read(client, &table).await?;
+        Ok(())
+    }
+
+    /// Type of the column of each field, the fields without one marked
+    async fn column_types(
+        &self,
+        client: &impl GenericClient,
+        wide_columns: &WideColumns,
+        table: &TableName,
+    ) -> Result<ColumnTypes> {
+        let mut types = wide_columns.get(client, table).await?;
+
+        let missing: Vec<&String> = self
+            .fields
+            .iter()
+            .map(|(column, _)| column)
+            .filter(|column| !types.contains_key(*column))
+            .collect();
+        if !missing.is_empty() {
+            for column in missing {
+                warn!("{} has no column {}, leaving the field out", table, column);
+                types.insert(column.clone(), None);
+            }
+            wide_columns.set(table, types.clone());
+        }
+        Ok(types)
+    }
+}
+
+/// Add the columns missing for the wide rows among `messages`, before
+/// they are written in a transaction
+pub async fn add_wide_columns(
+    client: &Client,
+    wide_columns: &WideColumns,
+    messages: &[ParsedMessage],
+) -> Result<()> {
+    for message in messages {
+        if let ParsedMessage::WideRow(row) = message {
+            row.add_columns(client, wide_columns).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Type of every column of `table`, as written in a cast
+async fn table_columns(client: &impl GenericClient, table: &TableName) -> Result<ColumnTypes> {
+    let rows = client
+        .query(
+            "SELECT attname::text, format_type(atttypid, atttypmod) FROM pg_attribute \
+             WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped",
+            &[&table.quoted()],
+        )
+        .await
+        .with_context(|| format!("Failed to read the columns of {}", table))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get(0), Some(row.get(1))))
+        .collect())
+}
+
+/// Write a batch of parsed messages inside a single transaction, the
+/// telemetry readings with one COPY per table and set of columns
+/// Readings skipping duplicates or written by their mapping's
+/// `insert_sql`, and other records, are inserted one by one
+pub async fn copy_batch(
+    client: &mut Client,
+    statements: &StatementCache,
+    wide_columns: &WideColumns,
+    messages: &[ParsedMessage],
+) -> Result<()> {
+    add_wide_columns(client, wide_columns, messages).await?;
+    let transaction = client
+        .transaction()
+        .await
+        .with_context(|| "Failed to start transaction")?;
+
+    let mut groups: BTreeMap<(String, Vec<&str>), Vec<&TelemetryReading>> = BTreeMap::new();
+    for message in messages {
+        match message {
+            ParsedMessage::TelemetryReading(reading)
+                if reading.dedupe_key.is_none() && reading.custom_insert.is_none() =>
+            {
+                let columns = reading.columns().iter().map(|(name, _, _)| *name).collect();
+                groups
+                    .entry((reading.target().quoted(), columns))
+                    .or_default()
+                    .push(reading);
+            }
+            ParsedMessage::TelemetryReading(reading) => {
+                reading.insert(&transaction, statements).await?
+            }
+            ParsedMessage::RawMessage(msg) => msg.insert(&transaction).await?,
+            ParsedMessage::WideRow(row) => row.insert(&transaction, wide_columns).await?,
+        }
+    }
+
+    for ((table, columns), readings) in &groups {
+        let types: Vec<Type> = readings[0]
+            .columns()
+            .into_iter()
+            .map(|(_, ty, _)| ty)
+            .collect();
+        let sql = format!("COPY {} ({}) FROM STDIN BINARY", table, columns.join(", "));
+        let sink = transaction
+            .copy_in(&sql)
+            .await
+            .with_context(|| format!("Failed to start copying telemetry into {}", table))?;
+
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+        for reading in readings {
+            let values: Vec<&(dyn ToSql + Sync)> = reading
+                .columns()
+                .into_iter()
+                .map(|(_, _, value)| value)
+                .collect();
+            writer
+                .as_mut()
+                .write(&values)
+                .await
+                .with_context(|| format!("Failed to copy telemetry into {}", table))?;
+        }
+        writer
+            .finish()
+            .await
+            .with_context(|| format!("Failed to copy telemetry into {}", table))?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .with_context(|| "Failed to commit transaction")?;
+
+    debug!(
+        "Committed batch of {} records, {} copied into {} tables",
+        messages.len(),
+        groups.values().map(Vec::len).sum::<usize>(),
+        groups.len()
+    );
+
+    Ok(())
+}
+
+/// A mapping's own insert statement, with `{name}` placeholders for the
+/// values bound to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertSql {
+    /// The statement with the placeholders numbered `$1`, `$2`, ...
+    pub sql: String,
+    /// Name of each parameter, a name used twice is bound once
+    pub names: Vec<String>,
+}
+
+impl InsertSql {
+    /// Number the `{name}` placeholders of `template`, names are letters,
+    /// digits, underscores and dots for nested payload fields
+    /// Other braces, and anything in a `'...'`, `E'...'` or dollar-quoted
+    /// literal such as `'{}'::jsonb`, are left as they are
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut sql = String::with_capacity(template.len());
+        let mut names: Vec<String> = Vec::new();
+
+        let bytes = template.as_bytes();
+        // Start of the template not yet copied to `sql`
+        let mut copied = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let word = i > 0 && identifier_byte(bytes[i - 1]);
+            match bytes[i] {
+                b'\'' => {
+                    let escapes = word
+                        && matches!(bytes[i - 1], b'E' | b'e')
+                        && (i < 2 || !identifier_byte(bytes[i - 2]));
+                    i = string_end(bytes, i + 1, escapes)?;
+                }
+                b'$' if !word => match dollar_tag(bytes, i) {
+                    Some(tag_end) => {
+                        let tag = &template[i..tag_end];
+                        let Some(end) = template[tag_end..].find(tag) else {
+                            bail!("insert_sql has an unterminated {} quoted string", tag);
+                        };
+                        i = tag_end + end + tag.len();
+                    }
+                    // Numbered parameters would be bound to whatever name
+                    // got the number
+                    None if bytes.get(i + 1).is_some_and(u8::is_ascii_digit) => {
+                        bail!("insert_sql must use {{name}} placeholders rather than $1, $2, ...");
+                    }
+                    None => i += 1,
+                },
+                b'{' => {
+                    let after = &template[i + 1..];
+                    let name = after
+                        .find('}')
+                        .map(|end| &after[..end])
+                        .filter(|name| placeholder_name(name));
+                    let Some(name) = name else {
+                        i += 1;
+                        continue;
+                    };
+                    let index = match names.iter().position(|known| known == name) {
+                        Some(index) => index,
+                        None => {
+                            names.push(name.to_string());
+                            names.len() - 1
+                        }
+                    };
+                    sql.push_str(&template[copied..i]);
+                    sql.push_str(&format!("${}", index + 1));
+                    i += name.len() + 2;
+                    copied = i;
+                }
+                _ => i += 1,
+            }
+        }
+        sql.push_str(&template[copied..]);
+
+        if sql.trim().is_empty() {
+            bail!("insert_sql is empty");
+        }
+        if names.is_empty() {
+            bail!("insert_sql has no {{name}} placeholders");
+        }
+        Ok(Self { sql, names })
+    }
+}
+
+fn identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$'
+}
+
+/// Index past the quote closing a string literal starting at `start`,
+/// with backslash escapes in `E'...'` strings
+fn string_end(bytes: &[u8], start: usize, escapes: bool) -> Result<usize> {
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if escapes => i += 2,
+            // A doubled quote is a quote in the string
+            b'\'' if bytes.get(i + 1) == Some(&b'\'') => i += 2,
+            b'\'' => return Ok(i + 1),
+            _ => i += 1,
+        }
+    }
+    bail!("insert_sql has an unterminated string literal")
+}
+
+/// Index past the opening `$tag$` of a dollar-quoted string at `start`
+fn dollar_tag(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if bytes.get(i).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    while bytes
+        .get(i)
+        .is_some_and(|&byte| byte.is_ascii_alphanumeric() || byte == b'_')
+    {
+        i += 1;
+    }
+    (bytes.get(i) == Some(&b'$')).then_some(i + 1)
+}
+
+/// Whether `name` in braces is a placeholder
+fn placeholder_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && !name.ends_with('.')
+}
+
+/// A value bound to a parameter of `insert_sql`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Text(String),
+    Time(DateTime<Utc>),
+    /// An object or array of the payload
+    Json(Value),
+}
+
+impl From<&Value> for SqlValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => SqlValue::Null,
+            Value::Bool(value) => SqlValue::Bool(*value),
+            Value::Number(value) => value.as_f64().map_or(SqlValue::Null, SqlValue::Number),
+            Value::String(value) => SqlValue::Text(value.clone()),
+            other => SqlValue::Json(other.clone()),
+        }
+    }
+}
+
+impl SqlValue {
+    fn kind(&self) -> &'static str {
+        match self {
+            SqlValue::Null => "null",
+            SqlValue::Bool(_) => "boolean",
+            SqlValue::Number(_) => "number",
+            SqlValue::Text(_) => "text",
+            SqlValue::Time(_) => "timestamp",
+            SqlValue::Json(_) => "JSON",
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            SqlValue::Null => String::new(),
+            SqlValue::Bool(value) => value.to_string(),
+            SqlValue::Number(value) => value.to_string(),
+            SqlValue::Text(value) => value.clone(),
+            SqlValue::Time(value) => value.to_rfc3339_opts(SecondsFormat::Micros, true),
+            SqlValue::Json(value) => value.to_string(),
+        }
+    }
+
+    /// The value as a parameter of type `ty`, `None` when it does not
+    /// convert
+    /// Every value converts to text, and NULL to any type bound
+    fn param(&self, ty: &Type) -> Option<Box<dyn ToSql + Sync + Send>> {
+        if *self == SqlValue::Null {
+            return null(ty);
+        }
+        if matches!(*ty, Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME) {
+            return Some(Box::new(self.text()));
+        }
+        if matches!(*ty, Type::JSON | Type::JSONB) {
+            let json = match self {
+                SqlValue::Json(value) => value.clone(),
+                SqlValue::Bool(value) => Value::Bool(*value),
+                SqlValue::Number(value) => serde_json::Number::from_f64(*value)?.into(),
+                other => Value::String(other.text()),
+            };
+            return Some(Box::new(json));
+        }
+
+        let whole = |value: f64| (value.fract() == 0.0).then_some(value);
+        match (self, ty) {
+            (SqlValue::Bool(value), &Type::BOOL) => Some(Box::new(*value)),
+            (SqlValue::Number(value), &Type::FLOAT8) => Some(Box::new(*value)),
+            (SqlValue::Number(value), &Type::FLOAT4) => Some(Box::new(*value as f32)),
+            (SqlValue::Number(value), &Type::INT2) => {
+                Some(Box::new(i16::try_from(whole(*value)? as i64).ok()?))
+            }
+            (SqlValue::Number(value), &Type::INT4) => {
+                Some(Box::new(i32::try_from(whole(*value)? as i64).ok()?))
+            }
+            (SqlValue::Number(value), &Type::INT8) => Some(Box::new(whole(*value)? as i64)),
+            (SqlValue::Time(value), &Type::TIMESTAMPTZ) => Some(Box::new(*value)),
+            (SqlValue::Time(value), &Type::TIMESTAMP) => Some(Box::new(value.naive_utc())),
+            _ => None,
+        }
+    }
+}
+
+/// NULL as a parameter of type `ty`
+fn null(ty: &Type) -> Option<Box<dyn ToSql + Sync + Send>> {
+    match *ty {
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => Some(Box::new(None::<String>)),
+        Type::JSON | Type::JSONB => Some(Box::new(None::<Value>)),
+        Type::BOOL => Some(Box::new(None::<bool>)),
+        Type::FLOAT8 => Some(Box::new(None::<f64>)),
+        Type::FLOAT4 => Some(Box::new(None::<f32>)),
+        Type::INT2 => Some(Box::new(None::<i16>)),
+        Type::INT4 => Some(Box::new(None::<i32>)),
+        Type::INT8 => Some(Box::new(None::<i64>)),
+        Type::TIMESTAMPTZ => Some(Box::new(None::<DateTime<Utc>>)),
+        Type::TIMESTAMP => Some(Box::new(None::<NaiveDateTime>)),
+        _ => None,
+    }
+}
+
+/// Parameter types `insert_sql` binds values to, others need a cast from
+/// text in the statement
+pub fn bindable(ty: &Type) -> bool {
+    matches!(
+        *ty,
+        Type::TEXT
+            | Type::VARCHAR
+            | Type::BPCHAR
+            | Type::NAME
+            | Type::JSON
+            | Type::JSONB
+            | Type::BOOL
+            | Type::FLOAT8
+            | Type::FLOAT4
+            | Type::INT2
+            | Type::INT4
+            | Type::INT8
+            | Type::TIMESTAMPTZ
+            | Type::TIMESTAMP
+    )
+}
+
+/// A reading written by its mapping's `insert_sql`, with the value of each
+/// of the statement's parameters
+#[derive(Debug, Clone)]
+pub struct CustomInsert {
+    pub sql: Arc<InsertSql>,
+    pub params: Vec<SqlValue>,
+}
+
+impl CustomInsert {
+    async fn execute(
+        &self,
+        client: &impl GenericClient,
+        statements: &StatementCache,
+        reading: &TelemetryReading,
+    ) -> Result<()> {
+        let statement = statements
+            .prepare(client, &self.sql.sql)
+            .await
+            .with_context(|| "Failed to prepare insert_sql")?;
+
+        let mut boxed = Vec::with_capacity(self.params.len());
+        for ((value, ty), name) in self
+            .params
+            .iter()
+            .zip(statement.params())
+            .zip(&self.sql.names)
+        {
+            match value.param(ty) {
+                Some(param) => boxed.push(param),
+                None => bail!(
+                    "Cannot bind {{{}}} ({} {}) to a parameter of type {}, \
+                     cast it from text in insert_sql, e.g. {{{}}}::text::{}",
+                    name,
+                    value.kind(),
+                    value.text(),
+                    ty,
+                    name,
+                    ty
+                ),
+            }
+        }
+        let params: Vec<&(dyn ToSql + Sync)> = boxed
+            .iter()
+            .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+            .collect();
+
+        let inserted = client
+            .execute(&statement, &params)
+            .await
+            .with_context(|| "Failed to insert telemetry reading with insert_sql")?;
+
+        // Mostly an ON CONFLICT DO NOTHING skipping a stored reading
+        if inserted == 0 {
+            debug!(
+                "insert_sql wrote no rows: device={}, sensor={}",
+                reading.device_id, reading.sensor_name
+            );
+            metrics().duplicates_skipped.inc();
+            return Ok(());
+        }
+
+        debug!(
+            "Inserted telemetry with insert_sql: device={}, sensor={}, value={}",
+            reading.device_id, reading.sensor_name, reading.value
+        );
+        Ok(())
+    }
+}
+
+/// Record a message from `device_id` in the device registry
+pub async fn register_device(
+    client: &impl GenericClient,
+    device_id: &str,
+    topic: &str,
+    seen: DateTime<Utc>,
+) -> Result<()> {
+    client
+        .execute(
+            "INSERT INTO devices (device_id, first_seen, last_seen, last_topic, message_count) \
+             VALUES ($1, $2, $2, $3, 1) \
+             ON CONFLICT (device_id) DO UPDATE SET \
+             last_seen = GREATEST(devices.last_seen, EXCLUDED.last_seen), \
+             last_topic = EXCLUDED.last_topic, \
+             message_count = devices.message_count + 1",
+            &[&device_id, &seen, &topic],
+        )
+        .await
+        .with_context(|| "Failed to update device registry")?;
+
+    debug!("Registered device: device={}, topic={}", device_id, topic);
+
+    Ok(())
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::avro::Schema;
+use crate::config::{DecoderConfig, DecoderKind};
+use crate::mapping::TopicMapping;
+use crate::parser::{parse_message, parse_value, ParsedMessage};
+use crate::sink::BoxFuture;
+
+/// Turns message payloads into records
+pub trait Decoder: Send + Sync {
+    fn decode(&self, topic: &str, payload: &[u8]) -> Vec<ParsedMessage>;
+
+    /// Payload fields read by mapping placeholders and dedupe keys, the
+    /// payload parsed as JSON by default
+    fn fields(&self, payload: &[u8]) -> Option<Value> {
+        serde_json::from_slice(payload).ok()
+    }
+
+    /// Records of a payload whose fields `fields` returned, decoding the
+    /// payload again by default
+    fn decode_fields(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        fields: Option<&Value>,
+    ) -> Vec<ParsedMessage> {
+        let _ = fields;
+        self.decode(topic, payload)
+    }
+
+    /// What has to be fetched before `payload` can be decoded, such as its
+    /// schema, `None` when nothing
+    fn fetch<'a>(&'a self, payload: &'a [u8]) -> Option<BoxFuture<'a, Result<()>>> {
+        let _ = payload;
+        None
+    }
+}
+
+/// Records of a message as the bridge stores them, decoded by the sensors
+/// or preset of its mapping or else by `decoder`, and pivoted into one row
+/// for mappings with `pivot`
+/// Returned with the payload's fields when the records or the mapping read
+/// them, so the payload is decoded once
+pub async fn decode_message(
+    mapping: Option<&TopicMapping>,
+    decoder: &dyn Decoder,
+    topic: &str,
+    payload: &[u8],
+) -> (Vec<ParsedMessage>, Option<Value>) {
+    let records = mapping.and_then(|mapping| mapping.decode(topic, payload));
+    let fields = if records.is_none() || mapping.is_some_and(|m| m.reads_fields()) {
+        fields(decoder, topic, payload).await
+    } else {
+        None
+    };
+    let mut records =
+        records.unwrap_or_else(|| decoder.decode_fields(topic, payload, fields.as_ref()));
+
+    // One row replaces the readings, the raw message is kept
+    if let Some(mapping) = mapping.filter(|m| m.spec().pivot) {
+        records.retain(|record| matches!(record, ParsedMessage::RawMessage(_)));
+        match fields.as_ref().map(|fields| mapping.pivot(topic, fields)) {
+            Some(Ok(row)) => records.push(ParsedMessage::WideRow(row)),
+            Some(Err(e)) => warn!("Failed to pivot message on {}: {}", topic, e),
+            None => {}
+        }
+    }
+    (records, fields)
+}
+
+/// Fields of `payload`, after fetching what decoding it needs
+async fn fields(decoder: &dyn Decoder, topic: &str, payload: &[u8]) -> Option<Value> {
+    if let Some(fetch) = decoder.fetch(payload) {
+        if let Err(e) = fetch.await {
+            warn!("Failed to decode payload on {}: {:#}", topic, e);
+            return None;
+        }
+    }
+    decoder.fields(payload)
+}
+
+/// The built-in decoder, see [`parse_message`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonDecoder;
+
+impl Decoder for JsonDecoder {
+    fn decode(&self, topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+        parse_message(topic, payload)
+    }
+
+    fn decode_fields(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        fields: Option<&Value>,
+    ) -> Vec<ParsedMessage> {
+        match std::str::from_utf8(payload) {
+            Ok(text) => parse_value(topic, text.to_string(), fields),
+            Err(e) => {
+                warn!("Failed to decode payload as UTF-8: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// CBOR payloads, read like the equivalent JSON
+/// The audit trail stores the payload converted to JSON
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborDecoder;
+
+#[cfg(feature = "cbor")]
+impl Decoder for CborDecoder {
+    fn decode(&self, topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+        decode_value(topic, self.fields(payload).as_ref())
+    }
+
+    fn decode_fields(
+        &self,
+        topic: &str,
+        _payload: &[u8],
+        fields: Option<&Value>,
+    ) -> Vec<ParsedMessage> {
+        decode_value(topic, fields)
+    }
+
+    fn fields(&self, payload: &[u8]) -> Option<Value> {
+        ciborium::from_reader(payload).ok()
+    }
+}
+
+/// Protobuf payloads of one message type, read like the message's JSON
+/// mapping with the field names of the `.proto` file
+#[cfg(feature = "protobuf")]
+#[derive(Debug, Clone)]
+pub struct ProtobufDecoder {
+    message: prost_reflect::MessageDescriptor,
+}
+
+#[cfg(feature = "protobuf")]
+impl ProtobufDecoder {
+    /// Load `message` from a compiled descriptor set
+    pub fn load(descriptor_set: &str, message: &str) -> Result<Self> {
+        let bytes = std::fs::read(descriptor_set)
+            .with_context(|| format!("Failed to read descriptor set: {}", descriptor_set))?;
+        let pool = prost_reflect::DescriptorPool::decode(bytes.as_slice())
+            .with_context(|| format!("Failed to parse descriptor set: {}", descriptor_set))?;
+
+        match pool.get_message_by_name(message) {
+            Some(message) => Ok(Self { message }),
+            None => bail!("Message {} is not in {}", message, descriptor_set),
+        }
+    }
+}
+
+#[cfg(feature = "protobuf")]
+impl Decoder for ProtobufDecoder {
+    fn decode(&self, topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+        decode_value(topic, self.fields(payload).as_ref())
+    }
+
+    fn decode_fields(
+        &self,
+        topic: &str,
+        _payload: &[u8],
+        fields: Option<&Value>,
+    ) -> Vec<ParsedMessage> {
+        decode_value(topic, fields)
+    }
+
+    fn fields(&self, payload: &[u8]) -> Option<Value> {
+        let message = prost_reflect::DynamicMessage::decode(self.message.clone(), payload).ok()?;
+
+        // Zero values are readings too, and 64-bit numbers stay numbers
+        let options = prost_reflect::SerializeOptions::new()
+            .stringify_64_bit_integers(false)
+            .use_proto_field_name(true)
+            .skip_default_fields(false);
+        message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .ok()
+    }
+}
+
+/// Avro payloads, either plain datums of one schema or in the Confluent
+/// wire format with the schema looked up in a schema registry
+pub struct AvroDecoder {
+    source: AvroSource,
+}
+
+enum AvroSource {
+    Schema(Schema),
+    Registry {
+        url: String,
+        http: reqwest::Client,
+        /// Schemas fetched so far, by ID
+        schemas: Mutex<HashMap<u32, Arc<Schema>>>,
+    },
+}
+
+impl AvroDecoder {
+    /// Payloads written with the schema in the `.avsc` file at `path`
+    pub fn load(path: &str) -> Result<Self> {
+        let schema = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read Avro schema: {}", path))?;
+        let schema = Schema::parse(&schema)
+            .with_context(|| format!("Failed to parse Avro schema: {}", path))?;
+        Ok(Self {
+            source: AvroSource::Schema(schema),
+        })
+    }
+
+    /// Payloads prefixed with the ID of their schema in the registry at `url`
+    pub fn registry(url: &str) -> Self {
+        Self {
+            source: AvroSource::Registry {
+                url: url.trim_end_matches('/').to_string(),
+                http: reqwest::Client::new(),
+                schemas: Mutex::new(HashMap::new()),
+            },
+        }
+    }
+
+    fn value(&self, payload: &[u8]) -> Result<Value> {
+        match &self.source {
+            AvroSource::Schema(schema) => schema.decode(payload),
+            AvroSource::Registry { schemas, .. } => {
+                let (id, datum) = wire_format(payload)?;
+                let schema = schemas.lock().unwrap().get(&id).cloned();
+                match schema {
+                    Some(schema) => schema.decode(datum),
+                    None => bail!("Schema {} was not fetched", id),
+                }
+            }
+        }
+    }
+
+    /// Fetch schema `id` from the registry, kept for the messages after
+    async fn fetch_schema(&self, id: u32) -> Result<()> {
+        let AvroSource::Registry { url, http, schemas } = &self.source else {
+            unreachable!("registry schema without a registry");
+        };
+
+        let body: Value = async {
+            http.get(format!("{}/schemas/ids/{}", url, id))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())?
+                .json()
+                .await
+        }
+        .await
+        .with_context(|| format!("Failed to fetch schema {} from {}", id, url))?;
+
+        if let Some(kind) = body["schemaType"].as_str().filter(|kind| *kind != "AVRO") {
+            bail!("Schema {} is a {} schema", id, kind);
+        }
+        let Some(schema) = body["schema"].as_str() else {
+            bail!("Invalid response for schema {}", id);
+        };
+        let schema = Arc::new(
+            Schema::parse(schema).with_context(|| format!("Failed to parse schema {}", id))?,
+        );
+
+        schemas.lock().unwrap().insert(id, schema);
+        Ok(())
+    }
+}
+
+/// Schema ID and datum of a payload in the schema registry wire format,
+/// a zero byte then the ID as a big-endian u32
+fn wire_format(payload: &[u8]) -> Result<(u32, &[u8])> {
+    let Some((&0, rest)) = payload.split_first() else {
+        bail!("Not in the schema registry wire format");
+    };
+    let Some((id, datum)) = rest.split_first_chunk::<4>() else {
+        bail!("Not in the schema registry wire format");
+    };
+    Ok((u32::from_be_bytes(*id), datum))
+}
+
+impl Decoder for AvroDecoder {
+    fn decode(&self, topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+        match self.value(payload) {
+            Ok(value) => decode_value(topic, Some(&value)),
+            Err(e) => {
+                warn!("Failed to decode Avro payload on {}: {:#}", topic, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn fields(&self, payload: &[u8]) -> Option<Value> {
+        match self.value(payload) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Failed to decode Avro payload: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn decode_fields(
+        &self,
+        topic: &str,
+        _payload: &[u8],
+        fields: Option<&Value>,
+    ) -> Vec<ParsedMessage> {
+        // Failures were reported by `fields`
+        match fields {
+            Some(fields) => decode_value(topic, Some(fields)),
+            None => Vec::new(),
+        }
+    }
+
+    fn fetch<'a>(&'a self, payload: &'a [u8]) -> Option<BoxFuture<'a, Result<()>>> {
+        let AvroSource::Registry { schemas, .. } = &self.source else {
+            return None;
+        };
+        let (id, _) = wire_format(payload).ok()?;
+        if schemas.lock().unwrap().contains_key(&id) {
+            return None;
+        }
+        Some(Box::pin(self.fetch_schema(id)))
+    }
+}
+
+/// Records of a binary payload decoded into `value`
+fn decode_value(topic: &str, value: Option<&Value>) -> Vec<ParsedMessage> {
+    match value {
+        Some(value) => parse_value(topic, value.to_string(), Some(value)),
+        None => {
+            warn!("Failed to decode payload on {}", topic);
+            Vec::new()
+        }
+    }
+}
+
+/// Decoders by the name mappings select them with
+/// `json` is always available, `cbor` when built with the cbor feature
+#[derive(Clone)]
+pub struct DecoderRegistry {
+    decoders: HashMap<String, Arc<dyn Decoder>>,
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            decoders: HashMap::new(),
+        };
+        registry.register("json", Arc::new(JsonDecoder));
+        #[cfg(feature = "cbor")]
+        registry.register("cbor", Arc::new(CborDecoder));
+        registry
+    }
+}
+
+impl DecoderRegistry {
+    /// The built-in decoders and those in the configuration
+    pub fn from_config(decoders: &[DecoderConfig]) -> Result<Self> {
+        let mut registry = Self::default();
+        for config in decoders {
+            let decoder = build(config)?;
+            registry.register(&config.name, decoder);
+        }
+        Ok(registry)
+    }
+
+    /// Add a decoder, replacing any decoder of the same name
+    pub fn register(&mut self, name: &str, decoder: Arc<dyn Decoder>) {
+        self.decoders.insert(name.to_string(), decoder);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Decoder>> {
+        self.decoders.get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.decoders.contains_key(name)
+    }
+
+    /// Decoder the mapping of a message selects, `default` when it selects
+    /// none or an unknown one
+    pub fn select(
+        &self,
+        mapping: Option<&TopicMapping>,
+        default: &Arc<dyn Decoder>,
+    ) -> Arc<dyn Decoder> {
+        let Some(name) = mapping.and_then(|mapping| mapping.spec().decoder.as_deref()) else {
+            return default.clone();
+        };
+
+        self.get(name).unwrap_or_else(|| {
+            warn!("Unknown decoder {}, using the default decoder", name);
+            default.clone()
+        })
+    }
+}
+
+fn build(config: &DecoderConfig) -> Result<Arc<dyn Decoder>> {
+    match config.kind {
+        DecoderKind::Json => Ok(Arc::new(JsonDecoder)),
+        #[cfg(feature = "cbor")]
+        DecoderKind::Cbor => Ok(Arc::new(CborDecoder)),
+        #[cfg(not(feature = "cbor"))]
+        DecoderKind::Cbor => bail!(
+            "Decoder {} needs anvil built with the cbor feature",
+            config.name
+        ),
+        #[cfg(feature = "protobuf")]
+        DecoderKind::Protobuf => {
+            let (Some(descriptor_set), Some(message)) = (&config.descriptor_set, &config.message)
+            else {
+                bail!("Decoder {} needs descriptor_set and message", config.name);
+            };
+            Ok(Arc::new(ProtobufDecoder::load(descriptor_set, message)?))
+        }
+        #[cfg(not(feature = "protobuf"))]
+        DecoderKind::Protobuf => bail!(
+            "Decoder {} needs anvil built with the protobuf feature",
+            config.name
+        ),
+        DecoderKind::Avro => match (&config.schema, &config.schema_registry) {
+            (Some(schema), None) => Ok(Arc::new(AvroDecoder::load(schema)?)),
+            (None, Some(url)) => Ok(Arc::new(AvroDecoder::registry(url))),
+            _ => bail!(
+                "Decoder {} needs either schema or schema_registry",
+                config.name
+            ),
+        },
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::DiscoveryConfig;
+use crate::mapping::{MappingSpec, SensorSpec, TopicMapping};
+
+/// Field read by value templates such as `{{ value_json.temperature }}` or
+/// `{{ value_json['temperature'] | float }}`
+static VALUE_JSON_FIELD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^\{\{\s*value_json(?:\.(\w+)|\[\s*['"]([^'"]+)['"]\s*\])\s*(?:\|[^}]*)?\}\}$"#)
+        .unwrap()
+});
+
+/// Plain value templates such as `{{ value }}` or `{{ value | float }}`
+static VALUE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\{\{\s*value\s*(?:\|[^}]*)?\}\}$").unwrap());
+
+/// Sensors announced through Home Assistant MQTT discovery
+/// Each `<prefix>/sensor/[<node_id>/]<object_id>/config` message announces
+/// a sensor, an empty message removes it
+pub struct HomeAssistant {
+    prefix: String,
+    /// Sensors by the topic of their discovery message
+    sensors: BTreeMap<String, DiscoveredSensor>,
+}
+
+struct DiscoveredSensor {
+    state_topic: String,
+    device_id: String,
+    sensor: SensorSpec,
+}
+
+impl HomeAssistant {
+    /// Discovery as configured, `None` when disabled
+    pub fn new(config: &DiscoveryConfig) -> Option<Self> {
+        config.home_assistant.then(|| Self {
+            prefix: config.prefix.trim_end_matches('/').to_string(),
+            sensors: BTreeMap::new(),
+        })
+    }
+
+    /// Filters of the discovery messages, with and without a node id
+    pub fn filters(&self) -> Vec<String> {
+        vec![
+            format!("{}/sensor/+/config", self.prefix),
+            format!("{}/sensor/+/+/config", self.prefix),
+        ]
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        self.filters()
+            .iter()
+            .any(|filter| rumqttc::matches(topic, filter))
+    }
+
+    /// Add, replace or remove the sensor announced on `topic`
+    /// Returns whether the generated mappings changed
+    pub fn handle(&mut self, topic: &str, payload: &[u8]) -> bool {
+        if payload.is_empty() {
+            let removed = self.sensors.remove(topic).is_some();
+            if removed {
+                info!("Home Assistant sensor removed: {}", topic);
+            }
+            return removed;
+        }
+
+        let sensor = match self.parse(topic, payload) {
+            Ok(sensor) => sensor,
+            Err(reason) => {
+                warn!("Ignoring Home Assistant discovery on {}: {}", topic, reason);
+                return self.sensors.remove(topic).is_some();
+            }
+        };
+
+        info!(
+            "Home Assistant sensor {} of {} on {}",
+            sensor.sensor.name, sensor.device_id, sensor.state_topic
+        );
+        self.sensors.insert(topic.to_string(), sensor);
+        true
+    }
+
+    /// One mapping per state topic, holding every sensor published on it
+    pub fn mappings(&self) -> Vec<TopicMapping> {
+        let mut specs: BTreeMap<&str, MappingSpec> = BTreeMap::new();
+        for discovered in self.sensors.values() {
+            specs
+                .entry(&discovered.state_topic)
+                .or_insert_with(|| MappingSpec {
+                    topic: discovered.state_topic.clone(),
+                    device_id: Some(discovered.device_id.clone()),
+                    ..MappingSpec::default()
+                })
+                .sensors
+                .push(discovered.sensor.clone());
+        }
+
+        specs
+            .into_values()
+            .filter_map(|spec| match TopicMapping::new(spec) {
+                Ok(mapping) => Some(mapping),
+                Err(e) => {
+                    warn!("Ignoring discovered mapping: {:#}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn parse(&self, topic: &str, payload: &[u8]) -> Result<DiscoveredSensor, String> {
+        let config: Value =
+            serde_json::from_slice(payload).map_err(|e| format!("invalid JSON: {}", e))?;
+
+        // Levels after `<prefix>/sensor`, the last one being `config`
+        let levels: Vec<&str> = topic[self.prefix.len()..]
+            .trim_start_matches('/')
+            .split('/')
+            .skip(1)
+            .collect();
+        let (node_id, object_id) = match levels[..] {
+            [object_id, _] => (None, object_id),
+            [node_id, object_id, _] => (Some(node_id), object_id),
+            _ => return Err("unexpected topic".to_string()),
+        };
+
+        let state_topic =
+            field(&config, "state_topic", "stat_t").ok_or_else(|| "no state_topic".to_string())?;
+        let state_topic = expand_base(&config, state_topic);
+        if state_topic.contains(['+', '#']) {
+            return Err(format!("state_topic {} is a filter", state_topic));
+        }
+
+        let field_name = match field(&config, "value_template", "val_tpl") {
+            None => None,
+            Some(template) => match value_field(template) {
+                Some(field) => field,
+                None => return Err(format!("unsupported value_template {}", template)),
+            },
+        };
+
+        let device_id = config
+            .get("device")
+            .or_else(|| config.get("dev"))
+            .and_then(|device| device.get("identifiers").or_else(|| device.get("ids")))
+            .and_then(|ids| match ids {
+                Value::Array(ids) => ids.first().and_then(Value::as_str),
+                Value::String(id) => Some(id.as_str()),
+                _ => None,
+            })
+            .or(node_id)
+            .unwrap_or(object_id)
+            .to_string();
+
+        let sensor = SensorSpec {
+            name: object_id.to_string(),
+            field: field_name,
+            unit: field(&config, "unit_of_measurement", "unit_of_meas").map(str::to_string),
+            device_class: field(&config, "device_class", "dev_cla").map(str::to_string),
+        };
+
+        Ok(DiscoveredSensor {
+            state_topic,
+            device_id,
+            sensor,
+        })
+    }
+}
+
+/// A string option, written in full or abbreviated
+fn field<'a>(config: &'a Value, name: &str, abbreviation: &str) -> Option<&'a str> {
+    config
+        .get(name)
+        .or_else(|| config.get(abbreviation))
+        .and_then(Value::as_str)
+}
+
+/// Replace the `~` at either end of a topic with the base topic
+fn expand_base(config: &Value, topic: &str) -> String {
+    let Some(base) = config.get("~").and_then(Value::as_str) else {
+        return topic.to_string();
+    };
+
+    if let Some(rest) = topic.strip_prefix('~') {
+        format!("{}{}", base, rest)
+    } else if let Some(rest) = topic.strip_suffix('~') {
+        format!("{}{}", rest, base)
+    } else {
+        topic.to_string()
+    }
+}
+
+/// Payload field read by a value template, `Some(None)` for the whole
+/// payload and `None` for templates that cannot be followed
+fn value_field(template: &str) -> Option<Option<String>> {
+    let template = template.trim();
+    if VALUE.is_match(template) {
+        return Some(None);
+    }
+
+    let captures = VALUE_JSON_FIELD.captures(template)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .map(|field| Some(field.as_str().to_string()))
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use rumqttc::{ConnectReturnCode, Event, EventLoop, Packet, SubscribeReasonCode};
+use tokio_postgres::Client as PgClient;
+
+use crate::config::{Config, MqttConfig};
+use crate::db;
+use crate::mqtt;
+use crate::tenant::TenantResolver;
+
+/// How long a single network check may take before it is reported as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a single preflight check
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            passed: true,
+            detail: detail.into(),
+        }
+    }
+
+    fn fail(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            passed: false,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Run every preflight check against the configured broker and database
+/// Checks never abort early so the report covers as much as possible
+pub async fn run(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    check_mqtt(&config.mqtt, &mut checks).await;
+    check_database(config, &mut checks).await;
+
+    checks
+}
+
+async fn check_mqtt(config: &MqttConfig, checks: &mut Vec<Check>) {
+    let broker = format!("{}:{}", config.host, config.port);
+    let client_id = format!("{}-doctor", config.client_id);
+    let (client, mut eventloop) = mqtt::create_client(config, &client_id);
+
+    let connack = tokio::time::timeout(CHECK_TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(ack))) => return Ok(ack.code),
+                Ok(_) => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    })
+    .await;
+
+    match connack {
+        Ok(Ok(ConnectReturnCode::Success)) => {
+            checks.push(Check::pass("MQTT connect", broker));
+        }
+        Ok(Ok(code)) => {
+            checks.push(Check::fail("MQTT connect", format!("refused: {:?}", code)));
+            return;
+        }
+        Ok(Err(e)) => {
+            checks.push(Check::fail("MQTT connect", e));
+            return;
+        }
+        Err(_) => {
+            checks.push(Check::fail("MQTT connect", "timed out"));
+            return;
+        }
+    }
+
+    let qos = mqtt::qos(config.qos);
+    for mapping in &config.topics {
+        let name = format!("MQTT subscribe {}", mapping.filter());
+
+        if let Err(e) = client.subscribe(mapping.filter(), qos).await {
+            checks.push(Check::fail(name, e.to_string()));
+            continue;
+        }
+
+        checks.push(match wait_for_suback(&mut eventloop).await {
+            Ok(()) => Check::pass(name, format!("{:?}", qos)),
+            Err(e) => Check::fail(name, e),
+        });
+    }
+
+    let _ = client.disconnect().await;
+}
+
+async fn wait_for_suback(eventloop: &mut EventLoop) -> Result<(), String> {
+    let suback = tokio::time::timeout(CHECK_TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::SubAck(ack))) => return Ok(ack),
+                Ok(_) => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    })
+    .await
+    .map_err(|_| "timed out".to_string())??;
+
+    if suback
+        .return_codes
+        .iter()
+        .any(|code| matches!(code, SubscribeReasonCode::Failure))
+    {
+        return Err("rejected by broker".to_string());
+    }
+
+    Ok(())
+}
+
+async fn check_database(config: &Config, checks: &mut Vec<Check>) {
+    let url = &config.database.url;
+    let client = match tokio::time::timeout(CHECK_TIMEOUT, db::connect(url)).await {
+        Ok(Ok(client)) => {
+            checks.push(Check::pass("PostgreSQL connect", "connected"));
+            client
+        }
+        Ok(Err(e)) => {
+            checks.push(Check::fail("PostgreSQL connect", format!("{:#}", e)));
+            return;
+        }
+        Err(_) => {
+            checks.push(Check::fail("PostgreSQL connect", "timed out"));
+            return;
+        }
+    };
+
+    checks.push(check_timescaledb(&client).await);
+
+    let mut tables: Vec<String> = db::TABLES.iter().map(|t| t.to_string()).collect();
+    if config.devices.registry {
+        tables.push(db::DEVICES_TABLE.to_string());
+    }
+
+    match TenantResolver::new(&config.tenants) {
+        Ok(Some(tenants)) => {
+            for schema in tenants.schemas() {
+                for table in db::TABLES {
+                    tables.push(format!("{}.{}", schema, table));
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => checks.push(Check::fail("Tenant routing", format!("{:#}", e))),
+    }
+
+    for table in &tables {
+        checks.push(check_insert_privilege(&client, table).await);
+    }
+}
+
+async fn check_timescaledb(client: &PgClient) -> Check {
+    let name = "TimescaleDB extension";
+
+    match client
+        .query_opt(
+            "SELECT extversion FROM pg_extension WHERE extname = 'timescaledb'",
+            &[],
+        )
+        .await
+    {
+        Ok(Some(row)) => Check::pass(name, format!("version {}", row.get::<_, String>(0))),
+        Ok(None) => Check::fail(name, "not installed in this database"),
+        Err(e) => Check::fail(name, e.to_string()),
+    }
+}
+
+async fn check_insert_privilege(client: &PgClient, table: &str) -> Check {
+    let name = format!("INSERT on {}", table);
+
+    // to_regclass returns NULL for missing tables instead of raising an error
+    match client
+        .query_one(
+            "SELECT CASE WHEN to_regclass($1::text) IS NULL THEN NULL \
+             ELSE has_table_privilege($1::text, 'INSERT') END",
+            &[&table],
+        )
+        .await
+    {
+        Ok(row) => match row.get::<_, Option<bool>>(0) {
+            Some(true) => Check::pass(name, "granted"),
+            Some(false) => Check::fail(name, "permission denied"),
+            None => Check::fail(name, "table does not exist"),
+        },
+        Err(e) => Check::fail(name, e.to_string()),
+    }
+}
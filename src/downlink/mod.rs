@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use rumqttc::AsyncClient;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+use tracing::{debug, error, info, warn};
+
+use crate::config::DownlinkConfig;
+use crate::db::{quote_identifier, TableName};
+use crate::mqtt::qos;
+
+/// Commands published per transaction
+const BATCH_SIZE: i64 = 100;
+
+/// Publishes the commands added to the commands table and marks them
+/// delivered, or records why they could not be published
+struct Downlink {
+    client: Client,
+    mqtt: AsyncClient,
+    table: TableName,
+    topic: String,
+    qos: rumqttc::QoS,
+    retain: bool,
+    poll_interval: Duration,
+}
+
+/// Connect to the database, listen for new commands and publish them
+/// until the bridge shuts down
+pub async fn start(config: &DownlinkConfig, database_url: &str, mqtt: AsyncClient) -> Result<()> {
+    let table = TableName::parse(&config.table)
+        .with_context(|| format!("Invalid downlink table: {}", config.table))?;
+
+    // Notifications arrive on the connection, so it is driven here rather
+    // than by `db::connect`
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls)
+        .await
+        .with_context(|| "Failed to connect to database")?;
+    let (notify_tx, notify_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            match std::future::poll_fn(|cx| connection.poll_message(cx)).await {
+                Some(Ok(AsyncMessage::Notification(_))) => {
+                    let _ = notify_tx.send(());
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    error!("Downlink database connection error: {}", e);
+                    break;
+                }
+                None => break,
+            }
+        }
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {}", quote_identifier(&config.channel)))
+        .await
+        .with_context(|| format!("Failed to listen on channel: {}", config.channel))?;
+
+    let downlink = Downlink {
+        client,
+        mqtt,
+        table,
+        topic: config.topic.clone(),
+        qos: qos(config.qos),
+        retain: config.retain,
+        poll_interval: Duration::from_millis(config.poll_interval_ms.max(1)),
+    };
+    tokio::spawn(downlink.run(notify_rx));
+
+    Ok(())
+}
+
+impl Downlink {
+    async fn run(mut self, mut notifications: mpsc::UnboundedReceiver<()>) {
+        loop {
+            if let Err(e) = self.deliver().await {
+                error!("Failed to deliver commands: {:#}", e);
+            }
+
+            tokio::select! {
+                notification = notifications.recv() => {
+                    if notification.is_none() {
+                        warn!("Downlink stopped, the database connection was lost");
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep(self.poll_interval) => {}
+            }
+        }
+    }
+
+    /// Publish every pending command, in the order they were added
+    async fn deliver(&mut self) -> Result<()> {
+        let table = self.table.quoted();
+        let select = format!(
+            "SELECT id::bigint, row_to_json(c)::text FROM {} c \
+             WHERE delivered_at IS NULL AND error IS NULL \
+             ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+            table
+        );
+        let delivered = format!(
+            "UPDATE {} SET delivered_at = now() WHERE id = ANY($1::bigint[])",
+            table
+        );
+        let failed = format!("UPDATE {} SET error = $2 WHERE id = $1::bigint", table);
+
+        loop {
+            let transaction = self
+                .client
+                .transaction()
+                .await
+                .with_context(|| "Failed to begin transaction")?;
+            let rows = transaction
+                .query(&select, &[&BATCH_SIZE])
+                .await
+                .with_context(|| format!("Failed to read commands from {}", self.table))?;
+            if rows.is_empty() {
+                return Ok(());
+            }
+
+            let mut published = Vec::with_capacity(rows.len());
+            let mut stopped = None;
+            for row in &rows {
+                let id: i64 = row.get(0);
+                let command: Value = serde_json::from_str(row.get(1))
+                    .with_context(|| format!("Invalid command {}", id))?;
+
+                let (topic, payload) = match message(&self.topic, &command) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Cannot publish command {}: {:#}", id, e);
+                        transaction
+                            .execute(&failed, &[&id, &format!("{:#}", e)])
+                            .await
+                            .with_context(|| "Failed to record command error")?;
+                        continue;
+                    }
+                };
+
+                // The client only fails once the bridge is shutting down,
+                // the command stays pending
+                if let Err(e) = self
+                    .mqtt
+                    .publish(&topic, self.qos, self.retain, payload)
+                    .await
+                {
+                    stopped = Some(e);
+                    break;
+                }
+                debug!("Published command {} to {}", id, topic);
+                published.push(id);
+            }
+
+            transaction
+                .execute(&delivered, &[&published])
+                .await
+                .with_context(|| "Failed to mark commands delivered")?;
+            transaction
+                .commit()
+                .await
+                .with_context(|| "Failed to commit transaction")?;
+
+            if !published.is_empty() {
+                info!("Published {} commands", published.len());
+            }
+            if let Some(e) = stopped {
+                bail!("Failed to publish command: {}", e);
+            }
+        }
+    }
+}
+
+/// Topic and payload of a command, its `payload` column as is when it
+/// holds text
+fn message(template: &str, command: &Value) -> Result<(String, Vec<u8>)> {
+    let topic = fill_template(template, command)?;
+    let payload = match command.get("payload") {
+        Some(Value::String(text)) => text.clone().into_bytes(),
+        Some(Value::Null) | None => bail!("No payload"),
+        Some(value) => value.to_string().into_bytes(),
+    };
+    Ok((topic, payload))
+}
+
+/// Replace the `{column}` placeholders of `template` with the values of
+/// the command's columns
+fn fill_template(template: &str, command: &Value) -> Result<String> {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 1..start + end];
+        let value = match command.get(name) {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Number(value)) => value.to_string(),
+            _ => bail!("No value for {{{}}} in topic {}", name, template),
+        };
+        if value.is_empty() || value.contains(['+', '#']) {
+            bail!(
+                "Invalid value {} for {{{}}} in topic {}",
+                value,
+                name,
+                template
+            );
+        }
+
+        filled.push_str(&rest[..start]);
+        filled.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    filled.push_str(rest);
+
+    Ok(filled)
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tracing::error;
+
+use crate::admin::{self, AdminState};
+use crate::db::Database;
+use crate::ingest::{self, IngestState};
+use crate::metrics::metrics;
+use crate::state::BridgeState;
+
+/// The event loop wakes at least once per MQTT keep-alive (30s), so a
+/// longer silence means it is stuck rather than idle
+const EVENT_LOOP_STALL: Duration = Duration::from_secs(90);
+
+/// Longest a check waits for a closed database connection to be opened
+/// again
+const DATABASE_CHECK: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct AppState {
+    bridge: Arc<BridgeState>,
+    database: Arc<Database>,
+}
+
+/// Bind the HTTP listener
+/// Binding happens before the bridge starts so a taken port fails startup
+pub async fn bind(address: &str) -> Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind HTTP server to {}", address))
+}
+
+/// Serve the HTTP endpoints until the process exits
+pub async fn serve(
+    listener: TcpListener,
+    bridge: Arc<BridgeState>,
+    database: Arc<Database>,
+    admin: Option<AdminState>,
+    ingest: Option<IngestState>,
+) {
+    let mut app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+        .with_state(AppState { bridge, database });
+
+    if let Some(admin) = admin {
+        app = app.merge(admin::router(admin));
+    }
+
+    if let Some(ingest) = ingest {
+        app = app.merge(ingest::router(ingest));
+    }
+
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("HTTP server error: {}", e);
+    }
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+/// Liveness: fails when the event loop is stuck or the database cannot
+/// be reached, a closed connection being opened again first
+/// A standby has no event loop yet and is alive while it waits
+async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
+    let since_last_poll = state.bridge.since_last_poll();
+    let event_loop_ok = state.bridge.standby() || since_last_poll < EVENT_LOOP_STALL;
+    let database_ok = database_ok(&state).await;
+
+    let status = if event_loop_ok && database_ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = json!({
+        "status": if status == StatusCode::OK { "ok" } else { "unhealthy" },
+        "uptime_secs": state.bridge.uptime().as_secs(),
+        "event_loop_idle_secs": since_last_poll.as_secs(),
+        "database": if database_ok { "connected" } else { "closed" },
+        "standby": state.bridge.standby(),
+    });
+
+    (status, Json(body))
+}
+
+/// Readiness: both the broker and the database are connected and the
+/// workers keep up with incoming messages
+/// A standby is never ready, it does not take messages until it leads
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let standby = state.bridge.standby();
+    let mqtt_ok = state.bridge.mqtt_connected();
+    let database_ok = database_ok(&state).await;
+    let backlog_ok = !state.bridge.backlog_full();
+
+    let status = if !standby && mqtt_ok && database_ok && backlog_ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = json!({
+        "status": match (standby, status == StatusCode::OK) {
+            (true, _) => "standby",
+            (false, true) => "ready",
+            (false, false) => "not ready",
+        },
+        "mqtt": if mqtt_ok { "connected" } else { "disconnected" },
+        "database": if database_ok { "connected" } else { "closed" },
+        "backlog": state.bridge.backlog(),
+    });
+
+    (status, Json(body))
+}
+
+/// Bridge status with per-subscription statistics
+async fn status(State(state): State<AppState>) -> impl IntoResponse {
+    let database_ok = database_ok(&state).await;
+    Json(json!({
+        "uptime_secs": state.bridge.uptime().as_secs(),
+        "role": if state.bridge.standby() { "standby" } else { "active" },
+        "mqtt": if state.bridge.mqtt_connected() { "connected" } else { "disconnected" },
+        "database": if database_ok { "connected" } else { "closed" },
+        "backlog": state.bridge.backlog(),
+        "subscriptions": state.bridge.stats.snapshot(),
+    }))
+}
+
+/// Whether the database can be reached, a closed connection being opened
+/// again first
+async fn database_ok(state: &AppState) -> bool {
+    tokio::time::timeout(DATABASE_CHECK, state.database.connected())
+        .await
+        .unwrap_or(false)
+}
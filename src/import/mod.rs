@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde_json::{Map, Number, Value};
+use tokio_postgres::Client;
+use tracing::{debug, warn};
+
+use crate::db::{self, StatementCache, TableAllowlist, WideColumns};
+use crate::decoder::{decode_message, Decoder, DecoderRegistry, JsonDecoder};
+use crate::mapping::{OutOfRange, TopicMapping};
+use crate::matcher::MappingSet;
+use crate::parser::ParsedMessage;
+use crate::tenant::TenantResolver;
+
+/// Supported backfill file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// One JSON object per line
+    Ndjson,
+    /// Comma separated values with a header row
+    Csv,
+}
+
+impl ImportFormat {
+    /// Guess the format from the file extension
+    pub fn detect(path: &str) -> Option<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("ndjson") | Some("jsonl") | Some("json") => Some(Self::Ndjson),
+            Some("csv") => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+pub struct ImportOptions {
+    pub format: Option<ImportFormat>,
+    pub default_topic: Option<String>,
+    pub batch_size: usize,
+    pub skip_raw: bool,
+    /// Route records to their tenant like the live bridge does
+    pub tenants: Option<TenantResolver>,
+    pub allowlist: TableAllowlist,
+    /// Mappings records are matched to by topic, like live messages
+    pub mappings: MappingSet,
+    /// Decoders mappings select by name
+    pub decoders: DecoderRegistry,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub records: usize,
+    pub skipped: usize,
+    pub rows: usize,
+    pub batches: usize,
+}
+
+/// Import a single exported log file
+/// Every record is decoded and routed by its mapping the way the live
+/// bridge does and written to the database in batches of `batch_size` rows
+pub async fn import_file(
+    client: &mut Client,
+    path: &str,
+    options: &ImportOptions,
+) -> Result<ImportSummary> {
+    let format = match options.format.or_else(|| ImportFormat::detect(path)) {
+        Some(format) => format,
+        None => bail!("Cannot detect format of {}, use --format", path),
+    };
+
+    let file = File::open(path).with_context(|| format!("Failed to open import file: {}", path))?;
+
+    let records: Box<dyn Iterator<Item = Result<Map<String, Value>>>> = match format {
+        ImportFormat::Ndjson => Box::new(read_ndjson(file)),
+        ImportFormat::Csv => Box::new(read_csv(file)?),
+    };
+
+    let mut summary = ImportSummary::default();
+    let mut batch: Vec<ParsedMessage> = Vec::new();
+    let statements = StatementCache::default();
+    let wide_columns = WideColumns::default();
+
+    for record in records {
+        summary.records += 1;
+
+        let (topic, payload) = match record
+            .and_then(|fields| split_record(fields, options.default_topic.as_deref()))
+        {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Skipping record {} in {}: {}", summary.records, path, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        let mapping = options.mappings.find(&topic);
+        let mut parsed = match decode(mapping, &options.decoders, &topic, payload.as_bytes()).await
+        {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Skipping record {} in {}: {:#}", summary.records, path, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        if let Some(tenants) = &options.tenants {
+            if let Err(tenant) = tenants.assign(&topic, &mut parsed) {
+                warn!(
+                    "Skipping record {} in {}: tenant {} is not allowed",
+                    summary.records, path, tenant
+                );
+                summary.skipped += 1;
+                continue;
+            }
+        }
+        if options.skip_raw {
+            parsed.retain(|message| !matches!(message, ParsedMessage::RawMessage(_)));
+        }
+        if let Some(Err(e)) = parsed
+            .iter()
+            .map(|message| options.allowlist.check(&message.target()))
+            .find(Result::is_err)
+        {
+            warn!("Skipping record {} in {}: {}", summary.records, path, e);
+            summary.skipped += 1;
+            continue;
+        }
+        batch.extend(parsed);
+
+        if batch.len() >= options.batch_size {
+            flush(client, &statements, &wide_columns, &mut batch, &mut summary).await?;
+        }
+    }
+
+    flush(client, &statements, &wide_columns, &mut batch, &mut summary).await?;
+
+    Ok(summary)
+}
+
+/// Records of a message decoded by its mapping, with the readings
+/// adjusted by it, without the readings it drops
+async fn decode(
+    mapping: Option<&TopicMapping>,
+    decoders: &DecoderRegistry,
+    topic: &str,
+    payload: &[u8],
+) -> Result<Vec<ParsedMessage>> {
+    let default: Arc<dyn Decoder> = Arc::new(JsonDecoder);
+    let decoder = decoders.select(mapping, &default);
+    let (records, json) = decode_message(mapping, decoder.as_ref(), topic, payload).await;
+
+    let Some(mapping) = mapping.filter(|m| m.adjusts_readings()) else {
+        return Ok(records);
+    };
+    let mut kept = Vec::with_capacity(records.len());
+    for mut record in records {
+        if let ParsedMessage::TelemetryReading(reading) = &mut record {
+            let out_of_range = mapping.apply(topic, json.as_ref(), reading)?;
+            if out_of_range == Some(OutOfRange::Drop) {
+                debug!(
+                    "Dropping reading of {} on {} out of range",
+                    reading.device_id, topic
+                );
+                continue;
+            }
+        }
+        kept.push(record);
+    }
+    Ok(kept)
+}
+
+async fn flush(
+    client: &mut Client,
+    statements: &StatementCache,
+    wide_columns: &WideColumns,
+    batch: &mut Vec<ParsedMessage>,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    db::insert_batch(client, statements, wide_columns, batch)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to import batch ending at record {}",
+                summary.records
+            )
+        })?;
+
+    summary.rows += batch.len();
+    summary.batches += 1;
+    debug!("Imported batch {} ({} rows)", summary.batches, batch.len());
+    batch.clear();
+
+    Ok(())
+}
+
+/// Split a record into its topic and the payload handed to the parser
+/// An explicit `payload` field is used verbatim, otherwise the remaining
+/// fields form the JSON payload
+fn split_record(
+    mut fields: Map<String, Value>,
+    default_topic: Option<&str>,
+) -> Result<(String, String)> {
+    let topic = match fields.remove("topic") {
+        Some(Value::String(topic)) if !topic.is_empty() => topic,
+        _ => match default_topic {
+            Some(topic) => topic.to_string(),
+            None => bail!("record has no topic and --topic was not given"),
+        },
+    };
+
+    let payload = match fields.remove("payload") {
+        Some(Value::String(payload)) => payload,
+        Some(value) => value.to_string(),
+        None => Value::Object(fields).to_string(),
+    };
+
+    Ok((topic, payload))
+}
+
+fn read_ndjson(file: File) -> impl Iterator<Item = Result<Map<String, Value>>> {
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| {
+            let line = line.with_context(|| "Failed to read line")?;
+            match serde_json::from_str::<Value>(&line) {
+                Ok(Value::Object(fields)) => Ok(fields),
+                Ok(_) => bail!("line is not a JSON object"),
+                Err(e) => bail!("invalid JSON: {}", e),
+            }
+        })
+}
+
+fn read_csv(file: File) -> Result<impl Iterator<Item = Result<Map<String, Value>>>> {
+    let mut reader = csv::Reader::from_reader(file);
+    let headers = reader
+        .headers()
+        .with_context(|| "Failed to read CSV header")?
+        .clone();
+
+    Ok(reader.into_records().map(move |record| {
+        let record = record.with_context(|| "Failed to read CSV record")?;
+        let mut fields = Map::new();
+
+        for (name, cell) in headers.iter().zip(record.iter()) {
+            let cell = cell.trim();
+            if !cell.is_empty() {
+                fields.insert(name.to_string(), csv_value(cell));
+            }
+        }
+
+        Ok(fields)
+    }))
+}
+
+/// Convert a CSV cell to JSON, keeping integers intact so unix
+/// timestamps are still recognised by the parser
+fn csv_value(cell: &str) -> Value {
+    if let Ok(int) = cell.parse::<i64>() {
+        return Value::Number(int.into());
+    }
+
+    if let Some(num) = cell.parse::<f64>().ok().and_then(Number::from_f64) {
+        return Value::Number(num);
+    }
+
+    Value::String(cell.to_string())
+}
@@ -0,0 +1,194 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Result};
+use axum::body::Bytes;
+use axum::extract::{Path, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::json;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::config::{WorkerPartition, WorkersConfig};
+use crate::matcher::MappingSet;
+use crate::session::Ack;
+use crate::state::BridgeState;
+
+/// A message received from any source, handled by the workers
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// MQTT packet to acknowledge once handled, with a persistent session
+    pub ack: Option<Ack>,
+}
+
+/// Queue a worker takes messages from, shared by all workers unless
+/// messages are partitioned
+pub type WorkerQueue = Arc<Mutex<mpsc::Receiver<Message>>>;
+
+/// Hands received messages to the workers, shared by all sources
+#[derive(Clone)]
+pub struct Ingest {
+    /// Emptied on shutdown so the queues close once drained
+    queues: Arc<RwLock<Vec<mpsc::Sender<Message>>>>,
+    mappings: Arc<RwLock<MappingSet>>,
+    state: Arc<BridgeState>,
+}
+
+impl Ingest {
+    /// Create the queues, returns the queue of each worker
+    pub fn new(
+        config: &WorkersConfig,
+        mappings: Arc<RwLock<MappingSet>>,
+        state: Arc<BridgeState>,
+    ) -> (Self, Vec<WorkerQueue>) {
+        let count = config.count.max(1);
+        let queues = match config.partition {
+            WorkerPartition::Device => count,
+            WorkerPartition::None => 1,
+        };
+
+        let mut senders = Vec::with_capacity(queues);
+        let mut receivers = Vec::with_capacity(queues);
+        for _ in 0..queues {
+            let (tx, rx) = mpsc::channel((config.queue_size / queues).max(1));
+            senders.push(tx);
+            receivers.push(Arc::new(Mutex::new(rx)));
+        }
+
+        let workers = (0..count)
+            .map(|id| receivers[id % queues].clone())
+            .collect();
+        let ingest = Self {
+            queues: Arc::new(RwLock::new(senders)),
+            mappings,
+            state,
+        };
+
+        (ingest, workers)
+    }
+
+    /// Queue a message, waiting while its queue is full
+    pub async fn submit(&self, message: Message) -> Result<()> {
+        let queue = {
+            let queues = self.queues.read().unwrap();
+            if queues.is_empty() {
+                bail!("The bridge is shutting down");
+            }
+            if self.state.standby() {
+                bail!("The bridge is a standby waiting for leadership");
+            }
+            queues[self.partition(&message.topic, queues.len())].clone()
+        };
+
+        self.state.enqueued();
+        if queue.send(message).await.is_err() {
+            self.state.dequeued();
+            bail!("The workers have stopped");
+        }
+
+        Ok(())
+    }
+
+    /// Stop accepting messages, the workers stop once the queues are empty
+    pub fn close(&self) {
+        self.queues.write().unwrap().clear();
+    }
+
+    /// Mappings used to route messages, shared with the workers
+    pub fn mappings(&self) -> Arc<RwLock<MappingSet>> {
+        self.mappings.clone()
+    }
+
+    /// Whether any mapping covers `topic`
+    pub fn is_mapped(&self, topic: &str) -> bool {
+        self.mappings.read().unwrap().find(topic).is_some()
+    }
+
+    /// Queue for a message on `topic`, the same for every message of a
+    /// device
+    /// The device is the mapping's `{device_id}` capture, or the topic
+    fn partition(&self, topic: &str, queues: usize) -> usize {
+        if queues == 1 {
+            return 0;
+        }
+
+        let mappings = self.mappings.read().unwrap();
+        let device = mappings
+            .find(topic)
+            .and_then(|mapping| mapping.capture(topic, "device_id"))
+            .unwrap_or(topic);
+
+        let mut hasher = DefaultHasher::new();
+        device.hash(&mut hasher);
+        (hasher.finish() % queues as u64) as usize
+    }
+}
+
+/// Shared state of the HTTP ingest endpoint
+#[derive(Clone)]
+pub struct IngestState {
+    pub ingest: Ingest,
+    pub token: Option<String>,
+}
+
+/// `POST /ingest/{topic}` feeds the body to the workers as a message on
+/// `topic`, guarded by the bearer token when one is configured
+/// Serve it behind a TLS terminating proxy for devices posting over HTTPS
+pub fn router(state: IngestState) -> Router {
+    Router::new()
+        .route("/ingest/{*topic}", post(ingest))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+async fn require_token(State(state): State<IngestState>, request: Request, next: Next) -> Response {
+    if let Some(token) = &state.token {
+        let authorized = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token);
+
+        if !authorized {
+            return error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+        }
+    }
+
+    next.run(request).await
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+async fn ingest(
+    State(state): State<IngestState>,
+    Path(topic): Path<String>,
+    body: Bytes,
+) -> Response {
+    // Only topics the bridge would subscribe to are accepted
+    if !state.ingest.is_mapped(&topic) {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("no mapping for topic {}", topic),
+        );
+    }
+
+    let message = Message {
+        topic: topic.clone(),
+        payload: body.to_vec(),
+        ack: None,
+    };
+
+    match state.ingest.submit(message).await {
+        Ok(()) => (StatusCode::ACCEPTED, Json(json!({ "topic": topic }))).into_response(),
+        Err(e) => error(StatusCode::SERVICE_UNAVAILABLE, format!("{:#}", e)),
+    }
+}
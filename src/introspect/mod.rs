@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use tokio_postgres::Client as PgClient;
+use tracing::debug;
+
+use crate::config::{Config, OversizedPayload, TenantRouting};
+use crate::db::{bindable, TableName, RAW_MESSAGES_TABLE, TELEMETRY_TABLE};
+use crate::mapping::TopicMapping;
+use crate::preset::Preset;
+use crate::tenant::TenantResolver;
+
+/// Kind of value the bridge writes to a column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    Timestamp,
+    Text,
+    Double,
+    BigInt,
+}
+
+impl ColumnType {
+    /// `information_schema` data types the driver can write this kind to
+    fn accepts(self, data_type: &str) -> bool {
+        match self {
+            ColumnType::Timestamp => data_type == "timestamp with time zone",
+            ColumnType::Text => matches!(data_type, "text" | "character varying" | "character"),
+            ColumnType::Double => data_type == "double precision",
+            ColumnType::BigInt => data_type == "bigint",
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ColumnType::Timestamp => "timestamptz",
+            ColumnType::Text => "text",
+            ColumnType::Double => "double precision",
+            ColumnType::BigInt => "bigint",
+        }
+    }
+}
+
+/// Columns written to a table, with what needs each of them
+#[derive(Default)]
+struct TableColumns {
+    columns: BTreeMap<&'static str, (ColumnType, String)>,
+}
+
+impl TableColumns {
+    fn add(&mut self, column: &'static str, kind: ColumnType, needed_by: &str) {
+        self.columns
+            .entry(column)
+            .or_insert_with(|| (kind, needed_by.to_string()));
+    }
+}
+
+/// Verify every table the mappings write to has the columns they write,
+/// with types the bridge can insert, and that their `insert_sql` prepares
+/// Tables whose name depends on the message cannot be checked up front
+pub async fn check(client: &PgClient, config: &Config) -> Result<()> {
+    let tables = required_columns(config)?;
+
+    let mut problems = Vec::new();
+    for (table, required) in tables.values() {
+        let existing = columns(client, table).await?;
+        if existing.is_empty() {
+            problems.push(format!("{}: table does not exist", table));
+            continue;
+        }
+
+        for (column, (kind, needed_by)) in &required.columns {
+            match existing.get(*column) {
+                None => problems.push(format!(
+                    "{}: missing column {} ({}), needed by {}",
+                    table,
+                    column,
+                    kind.as_str(),
+                    needed_by
+                )),
+                Some(data_type) if !kind.accepts(data_type) => problems.push(format!(
+                    "{}: column {} is {}, expected {}, needed by {}",
+                    table,
+                    column,
+                    data_type,
+                    kind.as_str(),
+                    needed_by
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+
+    // Statements of their own are checked by the server
+    for mapping in &config.mqtt.topics {
+        let Some(sql) = mapping.insert_sql() else {
+            continue;
+        };
+        let statement = match client.prepare(&sql.sql).await {
+            Ok(statement) => statement,
+            Err(e) => {
+                problems.push(format!(
+                    "mapping {}: insert_sql: {:#}",
+                    mapping.name(),
+                    anyhow::Error::from(e)
+                ));
+                continue;
+            }
+        };
+        for (name, ty) in sql.names.iter().zip(statement.params()) {
+            if !bindable(ty) {
+                problems.push(format!(
+                    "mapping {}: insert_sql parameter {{{}}} is {}, cast it from text, \
+                     e.g. {{{}}}::text::{}",
+                    mapping.name(),
+                    name,
+                    ty,
+                    name,
+                    ty
+                ));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Database tables do not match the configuration:\n  {}",
+            problems.join("\n  ")
+        );
+    }
+
+    debug!("Checked the columns of {} tables", tables.len());
+    Ok(())
+}
+
+/// Data type of every column of `table`, empty when the table does not
+/// exist or is not visible to the bridge's user
+async fn columns(client: &PgClient, table: &TableName) -> Result<BTreeMap<String, String>> {
+    let rows = client
+        .query(
+            "SELECT column_name::text, data_type::text FROM information_schema.columns \
+             WHERE table_schema = $1 AND table_name = $2",
+            &[&table.schema_or_default(), &table.table],
+        )
+        .await
+        .with_context(|| format!("Failed to read the columns of {}", table))?;
+
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// Columns written per table by the bridge and its mappings, by table name
+fn required_columns(config: &Config) -> Result<BTreeMap<String, (TableName, TableColumns)>> {
+    let tenants = TenantResolver::new(&config.tenants)?;
+    let tenant_column = tenants.is_some() && config.tenants.routing == TenantRouting::Column;
+    let tenant_schemas = tenants.map(|tenants| tenants.schemas()).unwrap_or_default();
+
+    let mut tables = BTreeMap::new();
+    let mut add = |table: TableName, column: &'static str, kind: ColumnType, needed_by: &str| {
+        // Tenant rows go to the same table in the tenant's schema
+        let copies = tenant_schemas.iter().map(|schema| TableName {
+            schema: Some(schema.clone()),
+            table: table.table.clone(),
+        });
+        for table in std::iter::once(table.clone()).chain(copies) {
+            tables
+                .entry(table.to_string())
+                .or_insert_with(|| (table, TableColumns::default()))
+                .1
+                .add(column, kind, needed_by);
+        }
+    };
+
+    let raw_messages = TableName::new(RAW_MESSAGES_TABLE);
+    for column in ["timestamp", "topic", "payload"] {
+        let kind = match column {
+            "timestamp" => ColumnType::Timestamp,
+            _ => ColumnType::Text,
+        };
+        add(raw_messages.clone(), column, kind, "raw messages");
+    }
+    if tenant_column {
+        add(
+            raw_messages.clone(),
+            "tenant_id",
+            ColumnType::Text,
+            "tenant routing",
+        );
+    }
+    if config.payloads.max_size > 0 && config.payloads.oversized != OversizedPayload::Drop {
+        add(
+            raw_messages,
+            "payload_size",
+            ColumnType::BigInt,
+            "payloads.oversized",
+        );
+    }
+
+    // Readings no mapping routes elsewhere, and discovered sensors
+    let mut targets = vec![(TableName::new(TELEMETRY_TABLE), None)];
+    for mapping in &config.mqtt.topics {
+        if mapping.insert_sql().is_some() {
+            continue;
+        }
+        if mapping.spec().table.is_none() {
+            targets.push((TableName::new(TELEMETRY_TABLE), Some(mapping)));
+        } else if let Some(table) = mapping.fixed_table() {
+            targets.push((table, Some(mapping)));
+        }
+        if let Some(table) = &mapping.spec().dead_letter_table {
+            targets.push((TableName::parse(table)?, Some(mapping)));
+        }
+    }
+
+    for (table, mapping) in targets {
+        let needed_by = mapping.map_or("telemetry readings".to_string(), |mapping| {
+            format!("mapping {}", mapping.name())
+        });
+        for (column, kind) in reading_columns(config, mapping) {
+            add(table.clone(), column, kind, &needed_by);
+        }
+        if tenant_column {
+            add(
+                table.clone(),
+                "tenant_id",
+                ColumnType::Text,
+                "tenant routing",
+            );
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Columns of the readings of `mapping`, of readings without a mapping
+/// when `None`
+fn reading_columns(
+    config: &Config,
+    mapping: Option<&TopicMapping>,
+) -> Vec<(&'static str, ColumnType)> {
+    // Pivoted rows have a column per payload field, found as they arrive
+    if mapping.is_some_and(|mapping| mapping.spec().pivot) {
+        return vec![
+            ("timestamp", ColumnType::Timestamp),
+            ("device_id", ColumnType::Text),
+            ("topic", ColumnType::Text),
+        ];
+    }
+
+    let mut columns = vec![
+        ("timestamp", ColumnType::Timestamp),
+        ("device_id", ColumnType::Text),
+        ("sensor_name", ColumnType::Text),
+        ("value", ColumnType::Double),
+        ("topic", ColumnType::Text),
+    ];
+
+    let Some(mapping) = mapping else {
+        if config.discovery.home_assistant {
+            columns.push(("unit", ColumnType::Text));
+            columns.push(("device_class", ColumnType::Text));
+        }
+        return columns;
+    };
+
+    let spec = mapping.spec();
+    if spec.dedupe_key.is_some() {
+        columns.push(("dedupe_key", ColumnType::Text));
+    }
+    // The Tasmota preset names the units and classes of the fields it knows
+    let tasmota = spec.preset == Some(Preset::TasmotaSensor);
+    if tasmota || spec.sensors.iter().any(|sensor| sensor.unit.is_some()) {
+        columns.push(("unit", ColumnType::Text));
+    }
+    if tasmota
+        || spec
+            .sensors
+            .iter()
+            .any(|sensor| sensor.device_class.is_some())
+    {
+        columns.push(("device_class", ColumnType::Text));
+    }
+    if !spec.validate.is_empty() {
+        columns.push(("quality", ColumnType::Text));
+        columns.push(("quality_reason", ColumnType::Text));
+    }
+    if spec.clock_correction.is_some() {
+        columns.push(("clock_offset", ColumnType::Double));
+    }
+    columns
+}
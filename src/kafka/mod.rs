@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::message::BorrowedMessage;
+use rdkafka::{ClientConfig, Message as _};
+use tracing::{error, warn};
+
+use crate::config::{KafkaConfig, KafkaOffsetReset};
+use crate::ingest::{Ingest, Message};
+
+/// Pause after a consume error, librdkafka reconnects in the background
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Join the consumer group and hand messages on the configured topics to
+/// the workers until the bridge shuts down
+pub fn start(config: &KafkaConfig, ingest: Ingest) -> Result<()> {
+    let offset_reset = match config.offset_reset {
+        KafkaOffsetReset::Earliest => "earliest",
+        KafkaOffsetReset::Latest => "latest",
+    };
+
+    let mut client = ClientConfig::new();
+    for (key, value) in &config.options {
+        client.set(key, value);
+    }
+    // Offsets are stored once a message is queued, so an unqueued message
+    // is read again after a restart
+    client
+        .set("bootstrap.servers", config.brokers.join(","))
+        .set("group.id", &config.group_id)
+        .set("auto.offset.reset", offset_reset)
+        .set("enable.auto.commit", "true")
+        .set("enable.auto.offset.store", "false");
+
+    let consumer: StreamConsumer = client
+        .create()
+        .with_context(|| "Failed to create Kafka consumer")?;
+
+    let topics: Vec<&str> = config.topics.iter().map(String::as_str).collect();
+    consumer
+        .subscribe(&topics)
+        .with_context(|| format!("Failed to subscribe to Kafka topics: {}", topics.join(", ")))?;
+
+    tokio::spawn(read(consumer, config.key_suffix, ingest));
+    Ok(())
+}
+
+async fn read(consumer: StreamConsumer, key_suffix: bool, ingest: Ingest) {
+    loop {
+        let message = match consumer.recv().await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Kafka error: {}", e);
+                // Errors repeat until the brokers are reachable again
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = ingest.submit(to_message(&message, key_suffix)).await {
+            warn!("Stopped reading from Kafka: {}", e);
+            break;
+        }
+        if let Err(e) = consumer.store_offset_from_message(&message) {
+            error!("Failed to store Kafka offset: {}", e);
+        }
+    }
+}
+
+/// The topic a message is matched on, `<topic>/<key>` with `key_suffix`
+/// when the message has a UTF-8 key
+fn to_message(message: &BorrowedMessage<'_>, key_suffix: bool) -> Message {
+    let key = key_suffix
+        .then(|| message.key().and_then(|key| std::str::from_utf8(key).ok()))
+        .flatten();
+
+    let topic = match key {
+        Some(key) => format!("{}/{}", message.topic(), key),
+        None => message.topic().to_string(),
+    };
+
+    Message {
+        topic,
+        payload: message.payload().unwrap_or_default().to_vec(),
+        ack: None,
+    }
+}
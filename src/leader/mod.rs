@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tokio_postgres::Client;
+use tracing::{info, warn};
+
+use crate::config::HaConfig;
+use crate::db;
+use crate::mqtt::BridgeCommand;
+use crate::systemd;
+
+/// The advisory lock making this instance the leader, held for as long as
+/// its connection lives
+pub struct Leadership {
+    client: Client,
+    interval: Duration,
+}
+
+/// Wait until this instance holds the lock, trying again every interval
+/// while another instance leads
+pub async fn acquire(config: &HaConfig, database_url: &str) -> Result<Leadership> {
+    let interval = Duration::from_millis(config.interval_ms.max(100));
+    let mut keepalive = systemd::Keepalive::new();
+    let mut client = None;
+    let mut waiting = false;
+
+    loop {
+        match try_lock(&mut client, database_url, config.lock_key).await {
+            Ok(true) => {
+                info!("Acquired leadership (lock {})", config.lock_key);
+                let client = client.expect("connected");
+                return Ok(Leadership { client, interval });
+            }
+            Ok(false) if !waiting => {
+                info!("Another instance is the leader, waiting as standby");
+                // Standing by is this instance's job until the leader fails
+                systemd::notify("READY=1\nSTATUS=Standby, waiting for leadership");
+                waiting = true;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Failed to take the leader lock: {:#}", e);
+                client = None;
+            }
+        }
+
+        let retry = tokio::time::sleep(interval);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                _ = keepalive.tick() => systemd::notify("WATCHDOG=1"),
+            }
+        }
+    }
+}
+
+/// Try to take the lock on `client`, connecting first when needed
+async fn try_lock(client: &mut Option<Client>, database_url: &str, key: i64) -> Result<bool> {
+    let client = match client {
+        Some(client) => client,
+        None => {
+            let connected = db::connect(database_url).await?;
+            // The server notices a vanished leader within seconds rather
+            // than hours, and releases its lock
+            connected
+                .batch_execute(
+                    "SET tcp_keepalives_idle = 5; SET tcp_keepalives_interval = 2; \
+                     SET tcp_keepalives_count = 3",
+                )
+                .await
+                .with_context(|| "Failed to set keepalives")?;
+            client.insert(connected)
+        }
+    };
+
+    let row = client
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&key])
+        .await
+        .with_context(|| "Failed to take advisory lock")?;
+    Ok(row.get(0))
+}
+
+impl Leadership {
+    /// Check the lock connection every interval and shut the bridge down
+    /// once it is lost, as a standby may already have taken over
+    pub async fn watch(self, commands: mpsc::Sender<BridgeCommand>) {
+        let error = loop {
+            tokio::time::sleep(self.interval).await;
+
+            let check = self.client.simple_query("SELECT 1");
+            match tokio::time::timeout(self.interval, check).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => break format!("{}", e),
+                Err(_) => break "the database did not answer".to_string(),
+            }
+        };
+
+        let _ = commands
+            .send(BridgeCommand::Shutdown {
+                reason: format!("Lost leadership: {}", error),
+            })
+            .await;
+    }
+}
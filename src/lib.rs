@@ -0,0 +1,55 @@
+//! MQTT to TimescaleDB telemetry bridge
+//!
+//! The `anvil` binary is a thin CLI over this crate. Embedders build a
+//! [`Bridge`] and can plug in their own [`Decoder`] and [`Sink`].
+
+pub mod admin;
+pub mod alerts;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod avro;
+pub mod bench;
+pub mod bridge;
+pub mod budget;
+pub mod catchup;
+pub mod config;
+pub mod db;
+pub mod decoder;
+pub mod discovery;
+pub mod doctor;
+pub mod downlink;
+pub mod http;
+pub mod import;
+pub mod ingest;
+pub mod introspect;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod leader;
+pub mod logging;
+pub mod mapping;
+pub mod matcher;
+pub mod metrics;
+pub mod mqtt;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod parser;
+pub mod preset;
+pub mod retention;
+pub mod secrets;
+pub mod session;
+pub mod simulate;
+pub mod sink;
+pub mod state;
+pub mod stats;
+pub mod suggest;
+pub mod systemd;
+pub mod tail;
+pub mod tenant;
+pub mod watchdog;
+
+pub use bridge::{Bridge, BridgeBuilder};
+pub use config::Config;
+pub use decoder::{Decoder, DecoderRegistry, JsonDecoder};
+pub use mapping::TopicMapping;
+pub use parser::ParsedMessage;
+pub use sink::{PostgresSink, Sink};
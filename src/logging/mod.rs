@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{Builder as RollingBuilder, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogFormat, LogRotation, LoggingConfig};
+
+/// Initialize tracing from the logging configuration
+/// The returned guard flushes the log file on drop and must be kept
+/// alive for the lifetime of the process
+pub fn init(config: &LoggingConfig) -> Result<Option<WorkerGuard>> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .with_context(|| format!("Invalid log level: {}", config.level))?;
+
+    let (writer, guard) = match &config.file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(file_appender(config, path)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+
+    match config.format {
+        LogFormat::Json => builder.json().init(),
+        // Escape codes only make sense on a terminal
+        LogFormat::Pretty => builder.with_ansi(config.file.is_none()).init(),
+    }
+
+    Ok(guard)
+}
+
+fn file_appender(
+    config: &LoggingConfig,
+    path: &str,
+) -> Result<tracing_appender::rolling::RollingFileAppender> {
+    let path = Path::new(path);
+    let directory = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid log file path: {}", path.display()))?;
+
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create log directory: {}", directory.display()))?;
+
+    let rotation = match config.rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+
+    let mut builder = RollingBuilder::new()
+        .rotation(rotation)
+        .filename_prefix(file_name);
+    if config.max_files > 0 {
+        builder = builder.max_log_files(config.max_files);
+    }
+
+    builder
+        .build(directory)
+        .with_context(|| format!("Failed to open log file: {}", path.display()))
+}
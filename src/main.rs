@@ -1,13 +1,17 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use colored::Colorize;
+use tracing::info;
 
-mod config;
-mod db;
-mod mqtt;
-mod parser;
-
-use config::Config;
+use anvil::bench::{self, BenchOptions};
+use anvil::config::{Config, LoggingConfig};
+use anvil::db::{self, TableAllowlist};
+use anvil::import::{self, ImportFormat, ImportOptions};
+use anvil::matcher::MappingSet;
+use anvil::simulate::{self, SensorSpec, SimulateOptions};
+use anvil::tail::{self, TailOptions};
+use anvil::tenant::TenantResolver;
+use anvil::{admin, doctor, logging, suggest, Bridge, DecoderRegistry};
 
 #[derive(Parser)]
 #[command(name = "anvil")]
@@ -37,6 +41,228 @@ enum Commands {
         /// PostgreSQL connection string
         #[arg(long)]
         db_url: Option<String>,
+
+        /// Mappings YAML file or directory of `*.yaml` files
+        #[arg(long)]
+        mappings: Option<String>,
+    },
+
+    /// Backfill telemetry from exported NDJSON or CSV files
+    Import {
+        /// Files to import
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// PostgreSQL connection string
+        #[arg(long)]
+        db_url: Option<String>,
+
+        /// Input format (detected from the file extension by default)
+        #[arg(long, value_enum)]
+        format: Option<ImportFormat>,
+
+        /// Topic used for records without a topic column
+        #[arg(long)]
+        topic: Option<String>,
+
+        /// Number of rows written per transaction
+        #[arg(long, default_value_t = 500)]
+        batch_size: usize,
+
+        /// Do not store the imported records in raw_messages
+        #[arg(long)]
+        skip_raw: bool,
+    },
+
+    /// Print a live view of incoming messages and the readings parsed from them
+    Tail {
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// MQTT broker host
+        #[arg(long)]
+        mqtt_host: Option<String>,
+
+        /// MQTT broker port
+        #[arg(long)]
+        mqtt_port: Option<u16>,
+
+        /// Only show topics matching this MQTT filter (wildcards allowed)
+        #[arg(short, long)]
+        filter: Option<String>,
+
+        /// Only show messages that produced no readings
+        #[arg(long)]
+        unmatched_only: bool,
+
+        /// Mappings YAML file or directory of `*.yaml` files
+        #[arg(long)]
+        mappings: Option<String>,
+    },
+
+    /// Publish synthetic telemetry to the broker for testing
+    Simulate {
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// MQTT broker host
+        #[arg(long)]
+        mqtt_host: Option<String>,
+
+        /// MQTT broker port
+        #[arg(long)]
+        mqtt_port: Option<u16>,
+
+        /// Number of simulated devices
+        #[arg(short, long, default_value_t = 3)]
+        devices: usize,
+
+        /// Sensors as name[:min:max[:wave]], wave is sine, square, sawtooth or random
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            default_value = "temperature:35:39,ph:7.2:7.6,o2:15:21:random"
+        )]
+        sensors: Vec<SensorSpec>,
+
+        /// Messages per second for each device
+        #[arg(short, long, default_value_t = 1.0)]
+        rate: f64,
+
+        /// Period of the generated waves in seconds
+        #[arg(long, default_value_t = 60.0)]
+        period: f64,
+
+        /// Topic prefix, the device id is appended as the last level
+        #[arg(long, default_value = "device/simulated")]
+        topic_prefix: String,
+
+        /// Stop after publishing this many messages per device
+        #[arg(short = 'n', long)]
+        count: Option<u64>,
+    },
+
+    /// Check broker and database connectivity and permissions
+    Doctor {
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// MQTT broker host
+        #[arg(long)]
+        mqtt_host: Option<String>,
+
+        /// MQTT broker port
+        #[arg(long)]
+        mqtt_port: Option<u16>,
+
+        /// PostgreSQL connection string
+        #[arg(long)]
+        db_url: Option<String>,
+
+        /// Mappings YAML file or directory of `*.yaml` files
+        #[arg(long)]
+        mappings: Option<String>,
+    },
+
+    /// Measure parser and database write throughput
+    Bench {
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// PostgreSQL connection string
+        #[arg(long)]
+        db_url: Option<String>,
+
+        /// Number of messages to generate
+        #[arg(short = 'n', long, default_value_t = 10_000)]
+        messages: usize,
+
+        /// Number of simulated devices
+        #[arg(short, long, default_value_t = 10)]
+        devices: usize,
+
+        /// Sensors per message as name[:min:max[:wave]]
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            default_value = "temperature:35:39,ph:7.2:7.6,o2:15:21:random"
+        )]
+        sensors: Vec<SensorSpec>,
+
+        /// Messages written together (1 matches the live bridge)
+        #[arg(short, long, default_value_t = 1)]
+        batch_size: usize,
+
+        /// Write batches with COPY, as while catching up on a backlog
+        #[arg(long)]
+        copy: bool,
+
+        /// Keep the generated rows instead of deleting them afterwards
+        #[arg(long)]
+        keep: bool,
+    },
+
+    /// Stop storing the messages of a mapping, or of all mappings, on a
+    /// running bridge
+    Pause {
+        /// Mapping to pause, every mapping when omitted
+        mapping: Option<String>,
+
+        /// Hold messages back and store them on resume instead of dropping them
+        #[arg(long)]
+        buffer: bool,
+
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// Admin API of the bridge (http.bind on this host by default)
+        #[arg(long)]
+        url: Option<String>,
+    },
+
+    /// Resume storing the messages of a mapping, or of all mappings, on a
+    /// running bridge
+    Resume {
+        /// Mapping to resume, every mapping when omitted
+        mapping: Option<String>,
+
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// Admin API of the bridge (http.bind on this host by default)
+        #[arg(long)]
+        url: Option<String>,
+    },
+
+    /// Draft a mapping for a topic from a payload the running bridge
+    /// captured on it, as no mapping read anything from it
+    SuggestMapping {
+        /// Topic the payload arrived on
+        topic: String,
+
+        /// Sample payload to draft from instead of the captured one
+        #[arg(long)]
+        payload: Option<String>,
+
+        /// Path to configuration file
+        #[arg(short, long, default_value = "anvil.toml")]
+        config: String,
+
+        /// Admin API of the bridge (http.bind on this host by default)
+        #[arg(long)]
+        url: Option<String>,
     },
 
     /// Generate a sample configuration file
@@ -49,24 +275,131 @@ enum Commands {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
-
     let cli = Cli::parse();
 
+    // The bridge configures tracing from its config file once loaded
+    if !matches!(cli.command, Commands::Start { .. }) {
+        logging::init(&LoggingConfig::default())?;
+    }
+
     match cli.command {
         Commands::Start {
             config,
             mqtt_host,
             mqtt_port,
             db_url,
+            mappings,
+        } => {
+            start_bridge(config, mqtt_host, mqtt_port, db_url, mappings).await?;
+        }
+        Commands::Import {
+            files,
+            config,
+            db_url,
+            format,
+            topic,
+            batch_size,
+            skip_raw,
+        } => {
+            let options = ImportOptions {
+                format,
+                default_topic: topic,
+                batch_size: batch_size.max(1),
+                skip_raw,
+                tenants: None,
+                allowlist: TableAllowlist::default(),
+                mappings: MappingSet::default(),
+                decoders: DecoderRegistry::default(),
+            };
+            import_files(config, db_url, files, options).await?;
+        }
+        Commands::Tail {
+            config,
+            mqtt_host,
+            mqtt_port,
+            filter,
+            unmatched_only,
+            mappings,
+        } => {
+            let options = TailOptions {
+                filter,
+                unmatched_only,
+            };
+            tail_messages(config, mqtt_host, mqtt_port, mappings, options).await?;
+        }
+        Commands::Simulate {
+            config,
+            mqtt_host,
+            mqtt_port,
+            devices,
+            sensors,
+            rate,
+            period,
+            topic_prefix,
+            count,
+        } => {
+            let options = SimulateOptions {
+                devices,
+                sensors,
+                rate,
+                period,
+                topic_prefix,
+                count,
+            };
+            simulate_devices(config, mqtt_host, mqtt_port, options).await?;
+        }
+        Commands::Doctor {
+            config,
+            mqtt_host,
+            mqtt_port,
+            db_url,
+            mappings,
+        } => {
+            run_doctor(config, mqtt_host, mqtt_port, db_url, mappings).await?;
+        }
+        Commands::Bench {
+            config,
+            db_url,
+            messages,
+            devices,
+            sensors,
+            batch_size,
+            copy,
+            keep,
+        } => {
+            let options = BenchOptions {
+                messages,
+                devices,
+                sensors,
+                batch_size,
+                copy,
+                keep,
+            };
+            run_bench(config, db_url, options).await?;
+        }
+        Commands::Pause {
+            mapping,
+            buffer,
+            config,
+            url,
         } => {
-            start_bridge(config, mqtt_host, mqtt_port, db_url).await?;
+            let mode = if buffer { "buffer" } else { "drop" };
+            set_paused(config, url, mapping, Some(mode)).await?;
+        }
+        Commands::Resume {
+            mapping,
+            config,
+            url,
+        } => {
+            set_paused(config, url, mapping, None).await?;
+        }
+        Commands::SuggestMapping {
+            topic,
+            payload,
+            config,
+            url,
+        } => {
+            suggest_mapping(config, url, topic, payload).await?;
         }
         Commands::Config { output } => {
             generate_config(&output)?;
@@ -81,13 +414,10 @@ async fn start_bridge(
     mqtt_host_override: Option<String>,
     mqtt_port_override: Option<u16>,
     db_url_override: Option<String>,
+    mappings_override: Option<String>,
 ) -> Result<()> {
-    println!("{}", "Anvil Telemetry Bridge".bright_cyan().bold());
-    println!("{}", "======================".bright_cyan());
-    println!();
-
     // Load configuration
-    let mut config = Config::load(&config_path)?;
+    let mut config = Config::load(&config_path).await?;
 
     // Apply CLI overrides
     if let Some(host) = mqtt_host_override {
@@ -100,40 +430,309 @@ async fn start_bridge(
         config.database.url = url;
     }
 
+    let _log_guard = logging::init(&config.logging)?;
+    let banner = config.logging.banner;
+
+    let mut builder = Bridge::builder().config(config).config_file(config_path);
+    if let Some(path) = mappings_override {
+        builder = builder.mappings_file(path);
+    }
+
+    // Run the bridge
+    builder.run().await?;
+
+    if banner {
+        println!("{}", "\nShutting down...".yellow());
+    } else {
+        info!("Shutting down");
+    }
+    Ok(())
+}
+
+async fn import_files(
+    config_path: String,
+    db_url_override: Option<String>,
+    files: Vec<String>,
+    mut options: ImportOptions,
+) -> Result<()> {
+    let mut config = Config::load(&config_path).await?;
+    if let Some(url) = db_url_override {
+        config.database.url = url;
+    }
+    config.load_mappings()?;
+    options.tenants = TenantResolver::new(&config.tenants)?;
+    options.allowlist = TableAllowlist::new(&config.database)?;
+    options.decoders = DecoderRegistry::from_config(&config.decoders)?;
+    options.mappings = MappingSet::new(config.mqtt.topics);
+
+    let mut db_client = db::connect(&config.database.url).await?;
+    println!("{}", "✓ Connected to TimescaleDB".green());
+    println!();
+
+    for file in &files {
+        println!("{} {}", "Importing".bright_green(), file.cyan());
+        let summary = import::import_file(&mut db_client, file, &options).await?;
+        println!(
+            "  {} {} records, {} rows in {} batches ({} skipped)",
+            "✓".green(),
+            summary.records.to_string().yellow(),
+            summary.rows.to_string().yellow(),
+            summary.batches,
+            summary.skipped
+        );
+    }
+
+    Ok(())
+}
+
+async fn tail_messages(
+    config_path: String,
+    mqtt_host_override: Option<String>,
+    mqtt_port_override: Option<u16>,
+    mappings_override: Option<String>,
+    options: TailOptions,
+) -> Result<()> {
+    let mut config = Config::load(&config_path).await?;
+    if let Some(host) = mqtt_host_override {
+        config.mqtt.host = host;
+    }
+    if let Some(port) = mqtt_port_override {
+        config.mqtt.port = port;
+    }
+    if mappings_override.is_some() {
+        config.mqtt.mappings = mappings_override;
+    }
+    config.load_mappings()?;
+
     println!(
-        "{} {}",
-        "MQTT Broker:".bright_green(),
-        format!("{}:{}", config.mqtt.host, config.mqtt.port).yellow()
+        "{} {} {}",
+        "Tailing".bright_green(),
+        format!("{}:{}", config.mqtt.host, config.mqtt.port).yellow(),
+        "(Ctrl+C to stop)".dimmed()
     );
+    println!();
+
+    tail::run(config.mqtt, options).await
+}
+
+async fn simulate_devices(
+    config_path: String,
+    mqtt_host_override: Option<String>,
+    mqtt_port_override: Option<u16>,
+    options: SimulateOptions,
+) -> Result<()> {
+    let mut config = Config::load(&config_path).await?;
+    if let Some(host) = mqtt_host_override {
+        config.mqtt.host = host;
+    }
+    if let Some(port) = mqtt_port_override {
+        config.mqtt.port = port;
+    }
+
     println!(
-        "{} {} topics",
-        "Subscribed to:".bright_green(),
-        config.mqtt.topics.len().to_string().yellow()
+        "{} {} devices x {} sensors at {} msg/s to {}",
+        "Simulating".bright_green(),
+        options.devices.to_string().yellow(),
+        options.sensors.len().to_string().yellow(),
+        options.rate.to_string().yellow(),
+        format!("{}/<device>", options.topic_prefix).cyan()
     );
-    for topic in &config.mqtt.topics {
-        println!("  {} {}", "→".dimmed(), topic.cyan());
-    }
+
+    let published = simulate::run(config.mqtt, options).await?;
+
+    println!(
+        "{} {} messages",
+        "✓ Published".green(),
+        published.to_string().yellow()
+    );
+
+    Ok(())
+}
+
+async fn run_doctor(
+    config_path: String,
+    mqtt_host_override: Option<String>,
+    mqtt_port_override: Option<u16>,
+    db_url_override: Option<String>,
+    mappings_override: Option<String>,
+) -> Result<()> {
+    println!("{}", "Anvil Doctor".bright_cyan().bold());
+    println!("{}", "============".bright_cyan());
     println!();
 
-    // Initialize database connection
-    let db_client = db::connect(&config.database.url).await?;
-    println!("{}", "✓ Connected to TimescaleDB".green());
+    let mut config = Config::load(&config_path).await?;
+    if let Some(host) = mqtt_host_override {
+        config.mqtt.host = host;
+    }
+    if let Some(port) = mqtt_port_override {
+        config.mqtt.port = port;
+    }
+    if let Some(url) = db_url_override {
+        config.database.url = url;
+    }
+    if mappings_override.is_some() {
+        config.mqtt.mappings = mappings_override;
+    }
+    config.load_mappings()?;
 
-    // Initialize MQTT client
-    let mqtt_bridge = mqtt::MqttBridge::new(config.mqtt.clone(), db_client).await?;
-    println!("{}", "✓ Connected to MQTT broker".green());
+    let checks = doctor::run(&config).await;
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+
+    for check in &checks {
+        let mark = if check.passed {
+            "✓".green()
+        } else {
+            "✗".red()
+        };
+        let detail = if check.passed {
+            check.detail.dimmed()
+        } else {
+            check.detail.red()
+        };
+        println!("{} {:<width$}  {}", mark, check.name, detail, width = width);
+    }
+
+    let failed = checks.iter().filter(|c| !c.passed).count();
     println!();
 
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, checks.len());
+    }
+
     println!(
         "{}",
-        "Bridge is running. Press Ctrl+C to stop...".bright_green()
+        format!("✓ All {} checks passed", checks.len()).green()
+    );
+    Ok(())
+}
+
+async fn run_bench(
+    config_path: String,
+    db_url_override: Option<String>,
+    options: BenchOptions,
+) -> Result<()> {
+    let mut config = Config::load(&config_path).await?;
+    if let Some(url) = db_url_override {
+        config.database.url = url;
+    }
+
+    config.load_mappings()?;
+
+    println!(
+        "{} {} messages from {} devices, batch size {}{}",
+        "Benchmarking".bright_green(),
+        options.messages.to_string().yellow(),
+        options.devices.to_string().yellow(),
+        options.batch_size.to_string().yellow(),
+        if options.copy { " with COPY" } else { "" }
     );
     println!();
 
-    // Run the bridge
-    mqtt_bridge.run().await?;
+    let report = bench::run(&config, &options).await?;
+
+    println!("{:<16}{}", "Messages:", report.messages);
+    println!("{:<16}{}", "Rows:", report.rows);
+    if report.failed > 0 {
+        println!(
+            "{:<16}{}",
+            "Failed writes:",
+            report.failed.to_string().red()
+        );
+    }
+    println!("{:<16}{:.3?}", "Parse time:", report.parse_time);
+    println!("{:<16}{:.3?}", "Insert time:", report.insert_time);
+    println!(
+        "{:<16}{}",
+        "Throughput:",
+        format!("{:.0} rows/s", report.rows_per_sec()).yellow()
+    );
+    println!(
+        "{:<16}{} ({:.1} rows/batch)",
+        "Batches:",
+        report.batch_latencies.len(),
+        report.rows_per_batch()
+    );
+    println!(
+        "{:<16}p50 {:.3?}  p99 {:.3?}",
+        "Batch latency:",
+        report.percentile(0.50),
+        report.percentile(0.99)
+    );
+
+    Ok(())
+}
+
+/// Pause (with a mode) or resume a mapping, all mappings without one,
+/// through the admin API of the running bridge
+async fn set_paused(
+    config_path: String,
+    url_override: Option<String>,
+    mapping: Option<String>,
+    mode: Option<&str>,
+) -> Result<()> {
+    let config = Config::load(&config_path).await?;
+    let url = url_override.unwrap_or_else(|| admin::local_url(&config));
+
+    let action = if mode.is_some() { "pause" } else { "resume" };
+    let path = match mapping {
+        Some(_) => format!("/admin/mappings/{}", action),
+        None => format!("/admin/{}", action),
+    };
+    let mut query = Vec::new();
+    if let Some(name) = &mapping {
+        query.push(("name", name.as_str()));
+    }
+    if let Some(mode) = mode {
+        query.push(("mode", mode));
+    }
+
+    let token = config.http.admin_token.as_deref();
+    let reply = admin::request(reqwest::Method::POST, &url, token, &path, &query).await?;
+
+    let target = mapping.as_deref().unwrap_or("all mappings");
+    let done = if mode.is_some() { "Paused" } else { "Resumed" };
+    if reply["changed"].as_bool() == Some(false) {
+        println!("{} {} (no change)", "✓".green(), target.cyan());
+    } else {
+        println!("{} {} {}", "✓".green(), done, target.cyan());
+    }
+
+    Ok(())
+}
+
+/// Print a draft mapping for `topic`, from `payload` or else the sample the
+/// running bridge kept of its unmatched messages
+async fn suggest_mapping(
+    config_path: String,
+    url_override: Option<String>,
+    topic: String,
+    payload: Option<String>,
+) -> Result<()> {
+    let payload = match payload {
+        Some(payload) => payload,
+        None => {
+            let config = Config::load(&config_path).await?;
+            let url = url_override.unwrap_or_else(|| admin::local_url(&config));
+            let token = config.http.admin_token.as_deref();
+            let reply =
+                admin::request(reqwest::Method::GET, &url, token, "/admin/unmatched", &[]).await?;
+
+            let captured = reply.as_array().and_then(|topics| {
+                topics
+                    .iter()
+                    .find(|entry| entry["topic"].as_str() == Some(topic.as_str()))
+            });
+            match captured.and_then(|entry| entry["sample"].as_str()) {
+                Some(sample) => sample.to_string(),
+                None => anyhow::bail!(
+                    "No unmatched message seen on {}, pass one with --payload",
+                    topic
+                ),
+            }
+        }
+    };
 
-    println!("{}", "\nShutting down...".yellow());
+    print!("{}", suggest::mapping(&topic, payload.as_bytes()));
     Ok(())
 }
 
@@ -0,0 +1,1120 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::substitute_env;
+use crate::db::{
+    valid_identifier, CustomInsert, InsertSql, Quality, SqlValue, TableName, TelemetryReading,
+    WideRow,
+};
+use crate::parser::{
+    extract_device_id, extract_timestamp, field_path, parse_sensors, parse_timestamp, sensor_paths,
+    ParsedMessage,
+};
+use crate::preset::Preset;
+
+/// A subscription and how its messages are stored
+/// Written in the config either as a plain topic filter or as a table
+/// with options
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "MappingEntry", into = "MappingEntry")]
+pub struct TopicMapping {
+    spec: MappingSpec,
+    /// `topic` with captures replaced by `+`, as subscribed to
+    filter: String,
+    /// Level and name of each `{name}` capture in `topic`
+    capture_levels: Vec<(usize, String)>,
+    table_regex: Option<Regex>,
+    /// Parts of `dedupe_key`
+    dedupe_fields: Vec<String>,
+    /// Nested fields of `timestamp.field`
+    timestamp_path: Option<Vec<String>>,
+    /// Nested fields of each sensor's `field`
+    sensor_paths: Vec<Option<Vec<String>>>,
+    /// Zone of `timestamp` values without an offset
+    timezone: Tz,
+    /// `pattern` of each `validate` rule
+    validate_patterns: Vec<Option<Regex>>,
+    /// `insert_sql` with its placeholders numbered
+    insert_sql: Option<Arc<InsertSql>>,
+    /// Clock skew by device for `clock_correction: auto`, kept by every
+    /// copy of the mapping
+    clock_skews: Arc<Mutex<HashMap<String, ClockSkew>>>,
+}
+
+/// Options of a mapping as written in the config
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MappingSpec {
+    /// Topic filter, a level written as `{name}` matches any single level
+    /// and captures it for use in `table`
+    /// Defaults to the standard topic of `preset`
+    #[serde(default)]
+    pub topic: String,
+    /// Target table for readings, optionally `schema.table`, `{name}`
+    /// placeholders are filled from topic captures, `device_id` or
+    /// top-level payload fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<String>,
+    /// Tables an interpolated `table` may resolve to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_tables: Vec<String>,
+    /// Pattern an interpolated `table` must match in full
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table_pattern: Option<String>,
+    /// Fields identifying a reading, joined with `+`, e.g.
+    /// `device_id + timestamp`, the sensor name is always included
+    /// Readings whose key is already stored are skipped, which needs a
+    /// unique index over `dedupe_key` and `timestamp` on the target table,
+    /// so payloads must carry their own timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedupe_key: Option<String>,
+    /// Device of the readings taken by `sensors`, found in the topic or
+    /// payload when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Readings taken from each payload, instead of one per numeric field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensors: Vec<SensorSpec>,
+    /// Firmware whose payloads the mapping reads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<Preset>,
+    /// Decoder for the payloads, by name, the bridge's default decoder
+    /// when unset
+    /// Not used with `sensors` or `preset`, which read JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoder: Option<String>,
+    /// Where and how payloads carry the time of their readings, the
+    /// `timestamp` or `ts` field as RFC 3339 or a Unix timestamp when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<TimestampSpec>,
+    /// Oldest accepted reading, in seconds before now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_past: Option<u64>,
+    /// Newest accepted reading, in seconds after now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_future: Option<u64>,
+    /// What happens to readings outside `max_past` and `max_future`,
+    /// `clamp` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub out_of_range: Option<OutOfRange>,
+    /// Table receiving readings with `out_of_range: dead_letter`, with the
+    /// columns of `telemetry`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_letter_table: Option<String>,
+    /// Checks setting the `quality` of readings, a reading breaking no
+    /// rule is `good`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub validate: Vec<ValidationRule>,
+    /// Also publish each stored reading as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub republish: Option<RepublishSpec>,
+    /// Store each message as one row of `table`, every payload field in the
+    /// column of the same name, instead of a row per reading
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pivot: bool,
+    /// Payload fields left out of pivoted rows
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Add a column to the pivot table for each new payload field, the
+    /// field is left out otherwise
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_add_columns: bool,
+    /// Shift the timestamps of devices whose clock is off, the seconds
+    /// added are stored in a `clock_offset` column
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_correction: Option<ClockCorrection>,
+    /// Statement writing each reading instead of the generated insert,
+    /// with `{name}` placeholders bound to the reading's columns, e.g.
+    /// `{value}`, `{payload}` for the whole payload, topic captures and
+    /// payload fields, `{meta.site}` for nested ones
+    /// Names without a value are bound as NULL, and tenant schemas are not
+    /// applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insert_sql: Option<String>,
+}
+
+/// A check on the readings of one sensor, a reading breaking it gets the
+/// rule's `quality`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationRule {
+    pub sensor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// Pattern the value as text must match, e.g. `^\d+$` for whole numbers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// The only values the sensor reports, e.g. the states of a valve
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed: Vec<f64>,
+    /// `bad` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<Quality>,
+}
+
+/// Where the readings of a mapping are republished
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RepublishSpec {
+    /// Topic template, filled like `table` with `{sensor_name}` as well
+    pub topic: String,
+    /// One of the configured `brokers`, the bridge's broker when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker: Option<String>,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+/// Handling of readings timestamped outside a mapping's bounds, as sent by
+/// devices with a wrong clock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfRange {
+    /// Store the reading as taken now
+    #[default]
+    Clamp,
+    /// Skip the reading
+    Drop,
+    /// Store the reading unchanged in `dead_letter_table`
+    DeadLetter,
+}
+
+impl OutOfRange {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutOfRange::Clamp => "clamp",
+            OutOfRange::Drop => "drop",
+            OutOfRange::DeadLetter => "dead_letter",
+        }
+    }
+}
+
+/// How the clocks of a mapping's devices are corrected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClockCorrection {
+    pub mode: ClockMode,
+    /// Seconds added to every timestamp in `fixed` mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<f64>,
+    /// Weight of each new sample in the `auto` estimate, from 0 to 1
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    /// Skews under this many seconds are left alone, as they are more
+    /// likely network delay than a wrong clock
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+}
+
+fn default_smoothing() -> f64 {
+    0.1
+}
+
+fn default_tolerance() -> f64 {
+    2.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockMode {
+    /// Add `offset` to every timestamp
+    Fixed,
+    /// Estimate the skew of each device from the time its messages are
+    /// received
+    Auto,
+}
+
+/// Estimated clock skew of a device
+#[derive(Debug, Clone, Copy)]
+struct ClockSkew {
+    seconds: f64,
+    /// Device time of the last sample, the readings of a message are
+    /// one sample
+    sampled: DateTime<Utc>,
+}
+
+/// Payload fields never pivoted into columns, as they are columns of
+/// every wide row or name them
+const PIVOT_RESERVED: &[&str] = &[
+    "timestamp",
+    "ts",
+    "device_id",
+    "deviceId",
+    "device",
+    "topic",
+    "tenant_id",
+];
+
+/// A sample this many seconds off the estimate restarts it, as after the
+/// device's clock was reset
+const CLOCK_RESET_SECS: f64 = 3600.0;
+
+/// The payload field holding the time of a reading
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimestampSpec {
+    /// Payload field, `.` separating nested fields, `timestamp` or `ts`
+    /// when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// chrono strftime format of string values, e.g. `%d/%m/%Y %H:%M:%S`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// IANA zone of values without an offset, e.g. `Europe/Warsaw`, UTC
+    /// when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+/// A reading taken from the payloads of a mapping
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SensorSpec {
+    pub name: String,
+    /// Payload field holding the value, `.` separating nested fields, the
+    /// whole payload is the value when unset, e.g. `21.5`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+}
+
+/// Contents of a mappings YAML file
+#[derive(Deserialize)]
+struct MappingsFile {
+    mappings: Vec<TopicMapping>,
+}
+
+/// Load the mappings in a YAML file, or in every `*.yaml` and `*.yml` file
+/// of a directory in file name order
+/// Each mapping is returned with the file it came from
+pub fn load(path: &str) -> Result<Vec<(String, TopicMapping)>> {
+    let files = if Path::new(path).is_dir() {
+        let mut files: Vec<_> = std::fs::read_dir(path)
+            .with_context(|| format!("Failed to read mappings directory: {}", path))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()
+            .with_context(|| format!("Failed to read mappings directory: {}", path))?;
+        files.retain(|file| {
+            file.is_file()
+                && file
+                    .extension()
+                    .is_some_and(|extension| extension == "yaml" || extension == "yml")
+        });
+        files.sort();
+        files
+    } else {
+        vec![Path::new(path).to_path_buf()]
+    };
+
+    let mut mappings = Vec::new();
+    for file in files {
+        let source = file.display().to_string();
+        let contents = std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read mappings file: {}", source))?;
+        let contents = substitute_env(&contents)
+            .with_context(|| format!("Failed to expand mappings file: {}", source))?;
+        let parsed: MappingsFile = serde_yaml::from_str(&contents)
+            .with_context(|| format!("Failed to parse mappings file: {}", source))?;
+
+        mappings.extend(
+            parsed
+                .mappings
+                .into_iter()
+                .map(|mapping| (source.clone(), mapping)),
+        );
+    }
+
+    Ok(mappings)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum MappingEntry {
+    Topic(String),
+    Mapping(Box<MappingSpec>),
+}
+
+impl TryFrom<MappingEntry> for TopicMapping {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: MappingEntry) -> Result<Self> {
+        match entry {
+            MappingEntry::Topic(topic) => Self::new(MappingSpec {
+                topic,
+                ..MappingSpec::default()
+            }),
+            MappingEntry::Mapping(spec) => Self::new(*spec),
+        }
+    }
+}
+
+impl From<TopicMapping> for MappingEntry {
+    fn from(mapping: TopicMapping) -> Self {
+        let plain = MappingSpec {
+            topic: mapping.spec.topic.clone(),
+            ..MappingSpec::default()
+        };
+
+        if mapping.spec == plain {
+            MappingEntry::Topic(mapping.spec.topic)
+        } else {
+            MappingEntry::Mapping(Box::new(mapping.spec))
+        }
+    }
+}
+
+impl TopicMapping {
+    pub fn new(mut spec: MappingSpec) -> Result<Self> {
+        if spec.topic.is_empty() {
+            match spec.preset {
+                Some(preset) => spec.topic = preset.topic().to_string(),
+                None => bail!("Every mapping needs a topic"),
+            }
+        }
+        let topic = &spec.topic;
+
+        let mut filter_levels = Vec::new();
+        let mut capture_levels = Vec::new();
+        for (index, level) in topic.split('/').enumerate() {
+            match placeholder(level) {
+                Some(name) if valid_identifier(name) => {
+                    filter_levels.push("+");
+                    capture_levels.push((index, name.to_string()));
+                }
+                Some(_) => bail!("Invalid capture {} in topic {}", level, topic),
+                None if level.contains(['{', '}']) => {
+                    bail!("Captures must span a whole level in topic {}", topic)
+                }
+                None => filter_levels.push(level),
+            }
+        }
+
+        if let Some(table) = &spec.table {
+            if placeholders(table).is_empty() {
+                TableName::parse(table)
+                    .with_context(|| format!("Invalid table for mapping {}", topic))?;
+            } else if spec.allowed_tables.is_empty() && spec.table_pattern.is_none() {
+                bail!(
+                    "Table {} of {} needs allowed_tables or table_pattern",
+                    table,
+                    topic
+                );
+            }
+        }
+
+        for allowed in &spec.allowed_tables {
+            TableName::parse(allowed)
+                .with_context(|| format!("Invalid allowed_tables entry for mapping {}", topic))?;
+        }
+
+        let table_regex = spec
+            .table_pattern
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| anyhow!("Invalid table_pattern for {}: {}", topic, e))
+            })
+            .transpose()?;
+
+        let dedupe_fields: Vec<String> = match &spec.dedupe_key {
+            Some(key) => key
+                .split('+')
+                .map(|field| field.trim().to_string())
+                .collect(),
+            None => Vec::new(),
+        };
+        if dedupe_fields.iter().any(String::is_empty) {
+            bail!("Invalid dedupe_key for mapping {}", topic);
+        }
+
+        if spec.sensors.iter().any(|sensor| sensor.name.is_empty()) {
+            bail!("Every sensor of mapping {} needs a name", topic);
+        }
+
+        if let Some(republish) = &spec.republish {
+            if republish.topic.is_empty() || republish.topic.contains(['+', '#']) {
+                bail!("Invalid republish topic for mapping {}", topic);
+            }
+        }
+
+        if spec.decoder.is_some() && (spec.preset.is_some() || !spec.sensors.is_empty()) {
+            bail!(
+                "Mapping {} cannot combine decoder with sensors or preset",
+                topic
+            );
+        }
+
+        let bounded = spec.max_past.is_some() || spec.max_future.is_some();
+        if spec.out_of_range.is_some() && !bounded {
+            bail!(
+                "Mapping {} needs max_past or max_future for out_of_range",
+                topic
+            );
+        }
+        match (&spec.dead_letter_table, spec.out_of_range) {
+            (Some(table), Some(OutOfRange::DeadLetter)) => {
+                TableName::parse(table)
+                    .with_context(|| format!("Invalid dead_letter_table for mapping {}", topic))?;
+            }
+            (None, Some(OutOfRange::DeadLetter)) => {
+                bail!("Mapping {} needs a dead_letter_table", topic)
+            }
+            (Some(_), _) => bail!(
+                "Mapping {} has a dead_letter_table without out_of_range: dead_letter",
+                topic
+            ),
+            (None, _) => {}
+        }
+
+        if spec.pivot {
+            if spec.table.is_none()
+                || spec
+                    .table
+                    .as_deref()
+                    .is_some_and(|t| !placeholders(t).is_empty())
+            {
+                bail!("Mapping {} needs a fixed table to pivot into", topic);
+            }
+            let per_reading = !spec.sensors.is_empty()
+                || spec.preset.is_some()
+                || spec.dedupe_key.is_some()
+                || !spec.validate.is_empty()
+                || bounded
+                || spec.clock_correction.is_some()
+                || spec.republish.is_some();
+            if per_reading {
+                bail!(
+                    "Mapping {} cannot pivot with sensors, preset, dedupe_key, validate, \
+                     max_past, max_future, clock_correction or republish",
+                    topic
+                );
+            }
+        } else if spec.auto_add_columns || !spec.exclude.is_empty() {
+            bail!(
+                "Mapping {} needs pivot for auto_add_columns and exclude",
+                topic
+            );
+        }
+
+        if let Some(correction) = &spec.clock_correction {
+            if spec.timestamp.is_none() {
+                bail!("Mapping {} needs a timestamp for clock_correction", topic);
+            }
+            match (correction.mode, correction.offset) {
+                (ClockMode::Fixed, None) => {
+                    bail!(
+                        "Mapping {} needs an offset for fixed clock_correction",
+                        topic
+                    )
+                }
+                (ClockMode::Auto, Some(_)) => {
+                    bail!("Mapping {} has an offset with auto clock_correction", topic)
+                }
+                _ => {}
+            }
+            if !(correction.smoothing > 0.0 && correction.smoothing <= 1.0) {
+                bail!("Invalid clock_correction smoothing for mapping {}", topic);
+            }
+        }
+
+        let insert_sql = match &spec.insert_sql {
+            Some(sql) => {
+                let table = spec.table.is_some()
+                    || spec.table_pattern.is_some()
+                    || !spec.allowed_tables.is_empty()
+                    || spec.dead_letter_table.is_some();
+                if spec.pivot || table {
+                    bail!(
+                        "Mapping {} cannot combine insert_sql with pivot, table, \
+                         table_pattern, allowed_tables or dead_letter_table",
+                        topic
+                    );
+                }
+                let sql = InsertSql::parse(sql)
+                    .map_err(|e| anyhow!("Invalid insert_sql for mapping {}: {}", topic, e))?;
+                Some(Arc::new(sql))
+            }
+            None => None,
+        };
+
+        let validate_patterns = spec
+            .validate
+            .iter()
+            .map(|rule| {
+                rule.pattern
+                    .as_ref()
+                    .map(|pattern| {
+                        Regex::new(pattern).map_err(|e| {
+                            anyhow!("Invalid pattern for {} in {}: {}", rule.sensor, topic, e)
+                        })
+                    })
+                    .transpose()
+            })
+            .collect::<Result<_>>()?;
+
+        let timezone = match spec.timestamp.as_ref().and_then(|ts| ts.timezone.as_ref()) {
+            Some(zone) => zone
+                .parse()
+                .map_err(|e| anyhow!("Invalid timezone for {}: {}", topic, e))?,
+            None => Tz::UTC,
+        };
+
+        let timestamp_path = spec
+            .timestamp
+            .as_ref()
+            .and_then(|ts| ts.field.as_ref())
+            .map(|field| field_path(field));
+        let sensor_paths = sensor_paths(&spec.sensors);
+
+        Ok(Self {
+            filter: filter_levels.join("/"),
+            capture_levels,
+            table_regex,
+            dedupe_fields,
+            timestamp_path,
+            sensor_paths,
+            timezone,
+            validate_patterns,
+            insert_sql,
+            clock_skews: Arc::default(),
+            spec,
+        })
+    }
+
+    /// A mapping storing to the default tables
+    pub fn from_topic(topic: &str) -> Self {
+        Self {
+            spec: MappingSpec {
+                topic: topic.to_string(),
+                ..MappingSpec::default()
+            },
+            filter: topic.to_string(),
+            capture_levels: Vec::new(),
+            table_regex: None,
+            dedupe_fields: Vec::new(),
+            timestamp_path: None,
+            sensor_paths: Vec::new(),
+            timezone: Tz::UTC,
+            validate_patterns: Vec::new(),
+            insert_sql: None,
+            clock_skews: Arc::default(),
+        }
+    }
+
+    pub fn spec(&self) -> &MappingSpec {
+        &self.spec
+    }
+
+    /// Name the mapping is reported and managed under
+    pub fn name(&self) -> &str {
+        &self.spec.topic
+    }
+
+    /// Filter subscribed to on the broker
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Whether `topic` matches the filter, a system topic such as
+    /// `$SYS/broker/load` only when the filter starts with its first level
+    pub fn matches(&self, topic: &str) -> bool {
+        match (topic.strip_prefix('$'), self.filter.strip_prefix('$')) {
+            (Some(topic), Some(filter)) => rumqttc::matches(topic, filter),
+            (Some(_), None) => false,
+            (None, _) => rumqttc::matches(topic, &self.filter),
+        }
+    }
+
+    /// Values of the `{name}` levels in `topic`
+    fn captures(&self, topic: &str) -> HashMap<String, String> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        self.capture_levels
+            .iter()
+            .filter_map(|(index, name)| {
+                let level = levels.get(*index)?;
+                Some((name.clone(), level.to_string()))
+            })
+            .collect()
+    }
+
+    /// Value of the `{name}` level in `topic`
+    pub fn capture<'a>(&self, topic: &'a str, name: &str) -> Option<&'a str> {
+        let (index, _) = self
+            .capture_levels
+            .iter()
+            .find(|(_, capture)| capture == name)?;
+        topic.split('/').nth(*index)
+    }
+
+    /// Records of a message on `topic`, `None` when the mapping leaves
+    /// decoding to the bridge's decoder
+    pub fn decode(&self, topic: &str, payload: &[u8]) -> Option<Vec<ParsedMessage>> {
+        if let Some(preset) = self.spec.preset {
+            return Some(preset.decode(self, topic, payload));
+        }
+        if self.spec.sensors.is_empty() {
+            return None;
+        }
+
+        Some(parse_sensors(
+            topic,
+            payload,
+            self.spec.device_id.as_deref(),
+            &self.spec.sensors,
+            &self.sensor_paths,
+        ))
+    }
+
+    /// The message as one row, for mappings with `pivot`
+    /// Numbers, strings and booleans at the top level of the payload are
+    /// stored, other values and the fields naming the device and time are
+    /// left out
+    pub fn pivot(&self, topic: &str, payload: &Value) -> Result<WideRow> {
+        let table = self
+            .fixed_table()
+            .ok_or_else(|| anyhow!("Mapping {} has no table to pivot into", self.name()))?;
+        let timestamp = match self.timestamp(Some(payload))? {
+            Some(timestamp) => timestamp,
+            None => extract_timestamp(payload),
+        };
+        let device_id = match (&self.spec.device_id, self.capture(topic, "device_id")) {
+            (Some(device_id), _) => device_id.clone(),
+            (None, Some(device_id)) => device_id.to_string(),
+            (None, None) => extract_device_id(topic, payload),
+        };
+
+        let time_field = match &self.timestamp_path {
+            Some(path) => path.first().map(String::as_str),
+            None => None,
+        };
+        let skipped = |key: &str| {
+            PIVOT_RESERVED.contains(&key)
+                || Some(key) == time_field
+                || self.spec.exclude.iter().any(|excluded| excluded == key)
+        };
+
+        let fields = payload
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, value)| {
+                !skipped(key)
+                    && valid_identifier(key)
+                    && matches!(value, Value::Number(_) | Value::String(_) | Value::Bool(_))
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        Ok(WideRow {
+            device_id,
+            topic: topic.to_string(),
+            timestamp,
+            tenant: None,
+            table,
+            fields,
+            auto_add_columns: self.spec.auto_add_columns,
+        })
+    }
+
+    /// The target table when it does not depend on the message
+    pub fn fixed_table(&self) -> Option<TableName> {
+        let table = self.spec.table.as_ref()?;
+        if !placeholders(table).is_empty() {
+            return None;
+        }
+        TableName::parse(table).ok()
+    }
+
+    /// Whether payload fields are read besides the records, to pivot the
+    /// message, adjust its readings or republish them
+    pub fn reads_fields(&self) -> bool {
+        self.spec.pivot || self.adjusts_readings() || self.spec.republish.is_some()
+    }
+
+    /// Whether [`apply`](Self::apply) changes the readings of the mapping
+    pub fn adjusts_readings(&self) -> bool {
+        // Pivoted rows are complete once built
+        if self.spec.pivot {
+            return false;
+        }
+        self.spec.table.is_some()
+            || self.spec.dedupe_key.is_some()
+            || self.spec.timestamp.is_some()
+            || self.spec.max_past.is_some()
+            || self.spec.max_future.is_some()
+            || !self.spec.validate.is_empty()
+            || self.insert_sql.is_some()
+    }
+
+    /// `insert_sql` with its placeholders numbered, `None` when the
+    /// readings are inserted by the bridge
+    pub fn insert_sql(&self) -> Option<&InsertSql> {
+        self.insert_sql.as_deref()
+    }
+
+    /// Set the timestamp, table, dedupe key and `insert_sql` parameters of
+    /// a reading received on `topic`
+    /// Returns the handling of a reading timestamped out of bounds, a
+    /// dropped reading must not be stored
+    pub fn apply(
+        &self,
+        topic: &str,
+        payload: Option<&Value>,
+        reading: &mut TelemetryReading,
+    ) -> Result<Option<OutOfRange>> {
+        if let Some(timestamp) = self.timestamp(payload)? {
+            reading.timestamp = timestamp;
+            if let Some(offset) = self.clock_offset(&reading.device_id, timestamp, Utc::now()) {
+                reading.timestamp += Duration::milliseconds((offset * 1000.0) as i64);
+                reading.clock_offset = Some(offset);
+            }
+        }
+
+        let out_of_range = self.out_of_range(reading.timestamp, Utc::now());
+        match out_of_range {
+            Some(OutOfRange::Clamp) => reading.timestamp = Utc::now(),
+            Some(OutOfRange::Drop) => return Ok(out_of_range),
+            Some(OutOfRange::DeadLetter) | None => {}
+        }
+
+        reading.table = match (out_of_range, &self.spec.dead_letter_table) {
+            (Some(OutOfRange::DeadLetter), Some(table)) => Some(TableName::parse(table)?),
+            _ => self.resolve_table(topic, payload, reading)?,
+        };
+        reading.dedupe_key = self.dedupe_key(topic, payload, reading)?;
+        if !self.spec.validate.is_empty() {
+            reading.quality = Some(self.quality(reading));
+        }
+        if let Some(sql) = &self.insert_sql {
+            reading.custom_insert = Some(CustomInsert {
+                sql: sql.clone(),
+                params: self.insert_params(sql, topic, payload, reading),
+            });
+        }
+        Ok(out_of_range)
+    }
+
+    /// Value of each parameter of `sql`, by the reading's columns, then the
+    /// topic's captures, then the payload's fields
+    fn insert_params(
+        &self,
+        sql: &InsertSql,
+        topic: &str,
+        payload: Option<&Value>,
+        reading: &TelemetryReading,
+    ) -> Vec<SqlValue> {
+        let text = |value: &Option<String>| value.clone().map_or(SqlValue::Null, SqlValue::Text);
+        let captures = self.captures(topic);
+
+        sql.names
+            .iter()
+            .map(|name| match name.as_str() {
+                "timestamp" => SqlValue::Time(reading.timestamp),
+                "device_id" => SqlValue::Text(reading.device_id.clone()),
+                "sensor_name" | "sensor" => SqlValue::Text(reading.sensor_name.clone()),
+                "value" => SqlValue::Number(reading.value),
+                "topic" => SqlValue::Text(reading.topic.clone()),
+                "unit" => text(&reading.unit),
+                "device_class" => text(&reading.device_class),
+                "quality" => reading
+                    .quality
+                    .as_ref()
+                    .map_or(SqlValue::Null, |(quality, _)| {
+                        SqlValue::Text(quality.as_str().to_string())
+                    }),
+                "quality_reason" => text(
+                    &reading
+                        .quality
+                        .as_ref()
+                        .and_then(|(_, reason)| reason.clone()),
+                ),
+                "clock_offset" => reading
+                    .clock_offset
+                    .map_or(SqlValue::Null, SqlValue::Number),
+                "dedupe_key" => text(&reading.dedupe_key),
+                "tenant_id" => text(&reading.tenant.as_ref().map(|tenant| tenant.id.clone())),
+                "payload" => payload.map_or(SqlValue::Null, SqlValue::from),
+                name => match captures.get(name) {
+                    Some(value) => SqlValue::Text(value.clone()),
+                    None => payload
+                        .and_then(|payload| {
+                            name.split('.')
+                                .try_fold(payload, |value, key| value.get(key))
+                        })
+                        .map_or(SqlValue::Null, SqlValue::from),
+                },
+            })
+            .collect()
+    }
+
+    /// Seconds to add to a device's `timestamp` by `clock_correction`
+    fn clock_offset(
+        &self,
+        device_id: &str,
+        timestamp: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Option<f64> {
+        let correction = self.spec.clock_correction.as_ref()?;
+        let seconds = match correction.mode {
+            ClockMode::Fixed => correction.offset.unwrap_or_default(),
+            ClockMode::Auto => {
+                let sample =
+                    now.signed_duration_since(timestamp).num_milliseconds() as f64 / 1000.0;
+                let mut skews = self.clock_skews.lock().unwrap();
+                let skew = skews.entry(device_id.to_string()).or_insert(ClockSkew {
+                    seconds: sample,
+                    sampled: timestamp,
+                });
+
+                if (sample - skew.seconds).abs() > CLOCK_RESET_SECS {
+                    skew.seconds = sample;
+                } else if skew.sampled != timestamp {
+                    skew.seconds += correction.smoothing * (sample - skew.seconds);
+                }
+                skew.sampled = timestamp;
+                skew.seconds
+            }
+        };
+
+        Some(if seconds.abs() < correction.tolerance {
+            0.0
+        } else {
+            seconds
+        })
+    }
+
+    /// Quality of a reading by the `validate` rules, the worst of the rules
+    /// it breaks along with the first of those
+    pub fn quality(&self, reading: &TelemetryReading) -> (Quality, Option<String>) {
+        let mut quality = (Quality::Good, None);
+        let rules = self.spec.validate.iter().zip(&self.validate_patterns);
+        for (rule, pattern) in rules.filter(|(rule, _)| rule.sensor == reading.sensor_name) {
+            let Some(broken) = broken_rule(rule, pattern.as_ref(), reading.value) else {
+                continue;
+            };
+
+            let rule_quality = rule.quality.unwrap_or(Quality::Bad);
+            if rule_quality > quality.0 {
+                quality = (
+                    rule_quality,
+                    Some(format!(
+                        "{} {} {}",
+                        reading.sensor_name, reading.value, broken
+                    )),
+                );
+            }
+        }
+        quality
+    }
+
+    /// Handling of a reading taken at `timestamp`, `None` within bounds
+    fn out_of_range(&self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Option<OutOfRange> {
+        let age = now.signed_duration_since(timestamp).num_seconds();
+        let too_old = self
+            .spec
+            .max_past
+            .is_some_and(|max| age > i64::try_from(max).unwrap_or(i64::MAX));
+        let too_new = self
+            .spec
+            .max_future
+            .is_some_and(|max| -age > i64::try_from(max).unwrap_or(i64::MAX));
+
+        (too_old || too_new).then(|| self.spec.out_of_range.unwrap_or_default())
+    }
+
+    /// Time of the readings in `payload` as set by `timestamp`
+    pub fn timestamp(&self, payload: Option<&Value>) -> Result<Option<DateTime<Utc>>> {
+        let Some(spec) = &self.spec.timestamp else {
+            return Ok(None);
+        };
+
+        let value = match (&self.timestamp_path, payload) {
+            (_, None) => None,
+            (Some(path), Some(payload)) => {
+                path.iter().try_fold(payload, |value, key| value.get(key))
+            }
+            (None, Some(payload)) => payload.get("timestamp").or_else(|| payload.get("ts")),
+        };
+        let field = spec.field.as_deref().unwrap_or("timestamp");
+        let Some(value) = value else {
+            bail!("No {} in payload for mapping {}", field, self.name());
+        };
+
+        match parse_timestamp(value, spec.format.as_deref(), self.timezone) {
+            Some(timestamp) => Ok(Some(timestamp)),
+            None => bail!("Invalid {} {} for mapping {}", field, value, self.name()),
+        }
+    }
+
+    /// Key identifying a reading across redeliveries, from the fields
+    /// named in `dedupe_key`
+    pub fn dedupe_key(
+        &self,
+        topic: &str,
+        payload: Option<&Value>,
+        reading: &TelemetryReading,
+    ) -> Result<Option<String>> {
+        if self.dedupe_fields.is_empty() {
+            return Ok(None);
+        }
+
+        let mut parts = self
+            .dedupe_fields
+            .iter()
+            .map(|field| {
+                let value = match field.as_str() {
+                    "device_id" => reading.device_id.clone(),
+                    "sensor_name" | "sensor" => reading.sensor_name.clone(),
+                    "timestamp" => reading
+                        .timestamp
+                        .to_rfc3339_opts(SecondsFormat::Micros, true),
+                    "topic" => reading.topic.clone(),
+                    "value" => reading.value.to_string(),
+                    name => match self.capture(topic, name) {
+                        Some(value) => value.to_string(),
+                        None => match payload.and_then(|payload| payload.get(name)) {
+                            Some(Value::String(value)) => value.clone(),
+                            Some(Value::Number(value)) => value.to_string(),
+                            _ => bail!("No value for {} in dedupe_key of {}", name, self.name()),
+                        },
+                    },
+                };
+                Ok(value)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Readings of one message only differ by sensor
+        if !self
+            .dedupe_fields
+            .iter()
+            .any(|field| field == "sensor_name" || field == "sensor")
+        {
+            parts.push(reading.sensor_name.clone());
+        }
+
+        Ok(Some(parts.join("|")))
+    }
+
+    /// Topic a reading received on `topic` is republished to, `None`
+    /// without `republish`
+    pub fn republish_topic(
+        &self,
+        topic: &str,
+        payload: Option<&Value>,
+        reading: &TelemetryReading,
+    ) -> Result<Option<String>> {
+        let Some(republish) = &self.spec.republish else {
+            return Ok(None);
+        };
+
+        let captures = self.captures(topic);
+        let mut filled = republish.topic.clone();
+        for name in placeholders(&republish.topic) {
+            let value = match captures.get(name) {
+                Some(value) => value.clone(),
+                None if name == "device_id" => reading.device_id.clone(),
+                None if name == "sensor_name" || name == "sensor" => reading.sensor_name.clone(),
+                None => match payload.and_then(|payload| payload.get(name)) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(Value::Number(value)) => value.to_string(),
+                    _ => bail!("No value for {{{}}} in topic {}", name, republish.topic),
+                },
+            };
+            if value.is_empty() || value.contains(['+', '#']) {
+                bail!(
+                    "Invalid value {} for {{{}}} in topic {}",
+                    value,
+                    name,
+                    republish.topic
+                );
+            }
+            filled = filled.replace(&format!("{{{}}}", name), &value);
+        }
+
+        Ok(Some(filled))
+    }
+
+    /// Table a reading received on `topic` is written to, `None` for the
+    /// default telemetry table
+    pub fn resolve_table(
+        &self,
+        topic: &str,
+        payload: Option<&Value>,
+        reading: &TelemetryReading,
+    ) -> Result<Option<TableName>> {
+        let Some(template) = &self.spec.table else {
+            return Ok(None);
+        };
+
+        let names = placeholders(template);
+        if names.is_empty() {
+            return TableName::parse(template).map(Some);
+        }
+
+        let captures = self.captures(topic);
+        let mut table = template.clone();
+        for name in names {
+            let value = match captures.get(name) {
+                Some(value) => value.clone(),
+                None if name == "device_id" => reading.device_id.clone(),
+                None => match payload.and_then(|payload| payload.get(name)) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(Value::Number(value)) => value.to_string(),
+                    _ => bail!("No value for {{{}}} in table {}", name, template),
+                },
+            };
+            table = table.replace(&format!("{{{}}}", name), &value.to_lowercase());
+        }
+
+        let allowed = self.spec.allowed_tables.contains(&table)
+            || self
+                .table_regex
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&table));
+
+        if !allowed {
+            bail!("Table {} is not allowed for mapping {}", table, self.name());
+        }
+
+        TableName::parse(&table).map(Some)
+    }
+}
+
+/// How `value` breaks `rule`, `None` when it passes
+fn broken_rule(rule: &ValidationRule, pattern: Option<&Regex>, value: f64) -> Option<String> {
+    if let Some(min) = rule.min.filter(|min| value < *min) {
+        return Some(format!("below min {}", min));
+    }
+    if let Some(max) = rule.max.filter(|max| value > *max) {
+        return Some(format!("above max {}", max));
+    }
+    if !rule.allowed.is_empty() && !rule.allowed.contains(&value) {
+        return Some("not an allowed value".to_string());
+    }
+    pattern
+        .filter(|pattern| !pattern.is_match(&value.to_string()))
+        .map(|pattern| format!("does not match {}", pattern.as_str()))
+}
+
+/// Name inside a `{name}` level
+fn placeholder(level: &str) -> Option<&str> {
+    level.strip_prefix('{')?.strip_suffix('}')
+}
+
+/// Names of the `{name}` placeholders in a template
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .collect()
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::str::Split;
+
+use crate::mapping::TopicMapping;
+
+/// Mappings in order of precedence, indexed by the levels of their filters
+/// so a topic is matched in one walk rather than against every mapping
+#[derive(Default)]
+pub struct MappingSet {
+    mappings: Vec<TopicMapping>,
+    root: Node,
+}
+
+/// A level of the filters, with the first mapping ending at it
+#[derive(Default)]
+struct Node {
+    levels: HashMap<String, Node>,
+    /// `+` at this level
+    any: Option<Box<Node>>,
+    /// First mapping whose filter ends here
+    exact: Option<usize>,
+    /// First mapping whose filter continues with `#`, matching this level
+    /// and everything below it
+    rest: Option<usize>,
+}
+
+impl MappingSet {
+    pub fn new(mappings: Vec<TopicMapping>) -> Self {
+        let mut root = Node::default();
+        for (index, mapping) in mappings.iter().enumerate() {
+            root.insert(mapping.filter().split('/'), index);
+        }
+        Self { mappings, root }
+    }
+
+    /// First mapping matching `topic`, as `TopicMapping::matches` would
+    /// find trying each in turn
+    /// A system topic such as `$SYS/broker/load` skips filters starting
+    /// with a wildcard
+    pub fn find(&self, topic: &str) -> Option<&TopicMapping> {
+        let mut levels = topic.split('/');
+        let index = if topic.starts_with('$') {
+            let first = levels.next()?;
+            self.root.levels.get(first)?.first(levels)?
+        } else {
+            self.root.first(levels)?
+        };
+        self.mappings.get(index)
+    }
+
+    pub fn mappings(&self) -> &[TopicMapping] {
+        &self.mappings
+    }
+}
+
+impl Node {
+    fn insert(&mut self, mut levels: Split<'_, char>, index: usize) {
+        match levels.next() {
+            None => {
+                self.exact.get_or_insert(index);
+            }
+            Some("#") => {
+                self.rest.get_or_insert(index);
+            }
+            Some("+") => self.any.get_or_insert_default().insert(levels, index),
+            Some(level) => self
+                .levels
+                .entry(level.to_string())
+                .or_default()
+                .insert(levels, index),
+        }
+    }
+
+    fn first(&self, mut levels: Split<'_, char>) -> Option<usize> {
+        let found = match levels.next() {
+            None => self.exact,
+            // Not a topic a broker would deliver
+            Some("#") => None,
+            Some(level) => {
+                let literal = self
+                    .levels
+                    .get(level)
+                    .and_then(|node| node.first(levels.clone()));
+                let any = self.any.as_ref().and_then(|node| node.first(levels));
+                earliest(literal, any)
+            }
+        };
+        earliest(found, self.rest)
+    }
+}
+
+fn earliest(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
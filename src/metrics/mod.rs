@@ -0,0 +1,257 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+/// Insert latency buckets in seconds, from sub-millisecond to multi-second stalls
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Ingest lag buckets in seconds, from network delay to hours of backlog
+const LAG_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+/// Bridge metrics exported on `/metrics`
+/// Topic labels use the matching subscription filter rather than the
+/// concrete topic so label cardinality stays bounded as devices are added
+pub struct Metrics {
+    registry: Registry,
+    pub messages_received: IntCounterVec,
+    pub parse_failures: IntCounterVec,
+    pub rows_inserted: IntCounterVec,
+    pub insert_errors: IntCounterVec,
+    pub insert_latency: HistogramVec,
+    pub mqtt_reconnects: IntCounter,
+    pub ingest_queue_depth: IntGauge,
+    pub worker_messages: IntCounterVec,
+    pub worker_busy: CounterVec,
+    pub insert_retries: IntCounter,
+    pub circuit_open: IntGauge,
+    pub spill_buffer_rows: IntGauge,
+    pub catching_up: IntGauge,
+    pub spill_dropped: IntCounter,
+    pub duplicates_skipped: IntCounter,
+    pub timestamps_out_of_range: IntCounterVec,
+    pub oversized_payloads: IntCounterVec,
+    pub ingest_lag: HistogramVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Global metrics instance
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "anvil_messages_received_total",
+                "MQTT messages received, by subscription",
+            ),
+            &["subscription"],
+        )
+        .expect("valid metric");
+        let parse_failures = IntCounterVec::new(
+            Opts::new(
+                "anvil_parse_failures_total",
+                "Messages that produced no telemetry readings, by subscription",
+            ),
+            &["subscription"],
+        )
+        .expect("valid metric");
+        let rows_inserted = IntCounterVec::new(
+            Opts::new("anvil_rows_inserted_total", "Rows inserted, by table"),
+            &["table"],
+        )
+        .expect("valid metric");
+        let insert_errors = IntCounterVec::new(
+            Opts::new("anvil_insert_errors_total", "Failed inserts, by table"),
+            &["table"],
+        )
+        .expect("valid metric");
+        let insert_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "anvil_insert_duration_seconds",
+                "Database insert latency, by table",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["table"],
+        )
+        .expect("valid metric");
+        let mqtt_reconnects = IntCounter::new(
+            "anvil_mqtt_reconnects_total",
+            "MQTT reconnect attempts after connection errors",
+        )
+        .expect("valid metric");
+        let ingest_queue_depth = IntGauge::new(
+            "anvil_ingest_queue_depth",
+            "Received messages waiting for a worker",
+        )
+        .expect("valid metric");
+        let worker_messages = IntCounterVec::new(
+            Opts::new("anvil_worker_messages_total", "Messages handled, by worker"),
+            &["worker"],
+        )
+        .expect("valid metric");
+        let worker_busy = CounterVec::new(
+            Opts::new(
+                "anvil_worker_busy_seconds_total",
+                "Time spent handling messages, by worker",
+            ),
+            &["worker"],
+        )
+        .expect("valid metric");
+        let insert_retries = IntCounter::new(
+            "anvil_insert_retries_total",
+            "Writes retried after a transient database error",
+        )
+        .expect("valid metric");
+        let circuit_open = IntGauge::new(
+            "anvil_circuit_open",
+            "1 while database writes are suspended after repeated failures",
+        )
+        .expect("valid metric");
+        let spill_buffer_rows = IntGauge::new(
+            "anvil_spill_buffer_rows",
+            "Rows held in memory until the database recovers",
+        )
+        .expect("valid metric");
+        let catching_up = IntGauge::new(
+            "anvil_catching_up",
+            "1 while a backlog after connecting is written in batches",
+        )
+        .expect("valid metric");
+        let spill_dropped = IntCounter::new(
+            "anvil_spill_dropped_rows_total",
+            "Rows dropped because the spill buffer was full",
+        )
+        .expect("valid metric");
+        let duplicates_skipped = IntCounter::new(
+            "anvil_duplicates_skipped_total",
+            "Readings not stored because their dedupe key was already stored",
+        )
+        .expect("valid metric");
+        let timestamps_out_of_range = IntCounterVec::new(
+            Opts::new(
+                "anvil_timestamps_out_of_range_total",
+                "Readings timestamped outside their mapping's bounds, by subscription and handling",
+            ),
+            &["subscription", "action"],
+        )
+        .expect("valid metric");
+        let oversized_payloads = IntCounterVec::new(
+            Opts::new(
+                "anvil_oversized_payloads_total",
+                "Messages over the payload size limit, by subscription",
+            ),
+            &["subscription"],
+        )
+        .expect("valid metric");
+        let ingest_lag = HistogramVec::new(
+            HistogramOpts::new(
+                "anvil_ingest_lag_seconds",
+                "Time from the device timestamp to receipt, by subscription, clocks ahead counted as 0",
+            )
+            .buckets(LAG_BUCKETS.to_vec()),
+            &["subscription"],
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(messages_received.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(parse_failures.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(rows_inserted.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(insert_errors.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(insert_latency.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(mqtt_reconnects.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(ingest_queue_depth.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(worker_messages.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(worker_busy.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(insert_retries.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(circuit_open.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(spill_buffer_rows.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(catching_up.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(spill_dropped.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(duplicates_skipped.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(timestamps_out_of_range.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(oversized_payloads.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(ingest_lag.clone()))
+            .expect("unique metric");
+
+        Self {
+            registry,
+            messages_received,
+            parse_failures,
+            rows_inserted,
+            insert_errors,
+            insert_latency,
+            mqtt_reconnects,
+            ingest_queue_depth,
+            worker_messages,
+            worker_busy,
+            insert_retries,
+            circuit_open,
+            spill_buffer_rows,
+            catching_up,
+            spill_dropped,
+            duplicates_skipped,
+            timestamps_out_of_range,
+            oversized_payloads,
+            ingest_lag,
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", e);
+        }
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
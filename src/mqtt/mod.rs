@@ -1,109 +1,527 @@
-use anyhow::{Context, Result};
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
-use tokio_postgres::Client as PgClient;
-use tracing::{debug, error, info};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::config::MqttConfig;
-use crate::parser::{parse_message, ParsedMessage};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, Event, EventLoop, MqttOptions, Outgoing, Packet, Publish, QoS,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+
+use crate::alerts::AlertEngine;
+use crate::config::{BrokerConfig, MqttConfig, OversizedPayload, PayloadsConfig};
+use crate::db::{self, Database, RawMessage, TelemetryReading};
+use crate::decoder::{decode_message, Decoder, DecoderRegistry, JsonDecoder};
+use crate::discovery::HomeAssistant;
+use crate::ingest::{Ingest, Message, WorkerQueue};
+use crate::mapping::{OutOfRange, TopicMapping};
+use crate::matcher::MappingSet;
+use crate::metrics::metrics;
+use crate::parser::ParsedMessage;
+use crate::session::{Ack, Receipt, Session};
+use crate::sink::{PostgresSink, Sink};
+use crate::state::{BridgeState, HeldMessage, Hold};
+use crate::systemd;
+use crate::tenant::TenantResolver;
+use crate::watchdog::Watchdog;
+
+/// Largest packet MQTT allows, payload size limits are applied by the
+/// bridge so oversized messages are not mistaken for connection errors
+const MAX_PACKET_SIZE: usize = 268_435_455;
+
+/// Longest wait for the broker to take the acknowledgements of the last
+/// messages stored on shutdown
+const ACK_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Create an MQTT client for the configured broker
+/// Callers other than the bridge pass their own client id so they don't
+/// take over the bridge's session
+pub fn create_client(config: &MqttConfig, client_id: &str) -> (AsyncClient, EventLoop) {
+    AsyncClient::new(client_options(config, client_id), 10)
+}
+
+fn client_options(config: &MqttConfig, client_id: &str) -> MqttOptions {
+    let mut mqttoptions = MqttOptions::new(client_id, &config.host, config.port);
+    mqttoptions.set_keep_alive(std::time::Duration::from_secs(30));
+    mqttoptions.set_clean_session(true);
+    mqttoptions.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+    if let Some(username) = &config.username {
+        mqttoptions.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+    mqttoptions
+}
+
+/// Client publishing to another broker, connected and reconnected in the
+/// background
+pub fn connect_publisher(broker: &BrokerConfig) -> AsyncClient {
+    let mut options = MqttOptions::new(&broker.client_id, &broker.host, broker.port);
+    options.set_keep_alive(std::time::Duration::from_secs(30));
+    options.set_clean_session(true);
+    options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+    if let Some(username) = &broker.username {
+        options.set_credentials(username, broker.password.as_deref().unwrap_or_default());
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    let name = broker.name.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = eventloop.poll().await {
+                error!("Connection error on broker {}: {}", name, e);
+                // Wait before reconnecting
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            }
+        }
+    });
+
+    client
+}
+
+/// Subscribe to every configured topic
+pub async fn subscribe(client: &AsyncClient, config: &MqttConfig) -> Result<()> {
+    let qos = qos(config.qos);
+
+    for mapping in &config.topics {
+        client
+            .subscribe(mapping.filter(), qos)
+            .await
+            .with_context(|| format!("Failed to subscribe to topic: {}", mapping.filter()))?;
+    }
+
+    Ok(())
+}
+
+/// First configured mapping whose filter matches `topic`
+pub fn matching_mapping<'a>(config: &'a MqttConfig, topic: &str) -> Option<&'a TopicMapping> {
+    config.topics.iter().find(|mapping| mapping.matches(topic))
+}
+
+pub fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtMostOnce,
+    }
+}
+
+/// Requests sent to the running bridge, e.g. from the admin API
+pub enum BridgeCommand {
+    /// Replace the subscription list, subscribing to new filters and
+    /// unsubscribing from removed ones
+    SetTopics {
+        topics: Vec<TopicMapping>,
+        reply: oneshot::Sender<Result<TopicChanges>>,
+    },
+    /// Stop processing messages and return `reason` as the error of
+    /// [`MqttBridge::run`]
+    Shutdown { reason: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopicChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Optional processing applied by the bridge on top of storing messages
+#[derive(Default)]
+pub struct BridgeOptions {
+    pub alerts: Option<Arc<AlertEngine>>,
+    /// Upsert publishing devices into the device registry
+    pub register_devices: bool,
+    pub watchdog: Option<Arc<Watchdog>>,
+    pub tenants: Option<TenantResolver>,
+    /// Decoder for payloads, the built-in JSON decoder when unset
+    pub decoder: Option<Arc<dyn Decoder>>,
+    /// Decoders mappings select by name
+    pub decoders: DecoderRegistry,
+    /// Destination for records, the database when unset
+    pub sink: Option<Arc<dyn Sink>>,
+    /// Generate mappings for sensors announced by Home Assistant discovery
+    pub discovery: Option<HomeAssistant>,
+    /// Size limit on payloads
+    pub payloads: PayloadsConfig,
+    /// Clients of the brokers mappings republish to, by name
+    pub publishers: HashMap<String, AsyncClient>,
+    /// Rows per transaction of messages written together, see
+    /// [`DatabaseConfig::commit_rows`](crate::config::DatabaseConfig::commit_rows)
+    pub commit_rows: usize,
+}
 
 pub struct MqttBridge {
-    _client: AsyncClient,
+    client: AsyncClient,
     eventloop: EventLoop,
-    db_client: PgClient,
+    config: MqttConfig,
+    state: Arc<BridgeState>,
+    commands: mpsc::Receiver<BridgeCommand>,
+    ingest: Ingest,
+    queues: Vec<WorkerQueue>,
+    discovery: Option<HomeAssistant>,
+    processor: Arc<Processor>,
+    /// Messages of the persistent session not yet done with
+    session: Option<Arc<Session>>,
+    /// Acknowledgements of handled messages from the workers
+    acks: mpsc::UnboundedReceiver<Ack>,
+    /// Acknowledgements waiting for room in the client's queue
+    unsent_acks: VecDeque<Ack>,
+    /// Acknowledgements handed to the client but not yet sent
+    acks_in_flight: usize,
 }
 
-impl MqttBridge {
-    pub async fn new(config: MqttConfig, db_client: PgClient) -> Result<Self> {
-        let mut mqttoptions = MqttOptions::new(&config.client_id, &config.host, config.port);
-        mqttoptions.set_keep_alive(std::time::Duration::from_secs(30));
-        mqttoptions.set_clean_session(true);
+/// Records of a message ready to be written
+struct Prepared {
+    message: Message,
+    mapping: Option<TopicMapping>,
+    records: Vec<ParsedMessage>,
+    /// Payload fields for republishing
+    json: Option<Value>,
+}
 
-        let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
+impl Prepared {
+    fn subscription(&self) -> &str {
+        self.mapping.as_ref().map_or("unknown", |m| m.name())
+    }
+}
+
+/// Workers storing the messages submitted to an [`Ingest`] the way the
+/// bridge does, without a broker connection
+/// Readings are not republished, and messages carry no acknowledgements
+pub struct Workers {
+    workers: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl Workers {
+    pub fn start(
+        config: &MqttConfig,
+        database: Arc<Database>,
+        state: Arc<BridgeState>,
+        ingest: &Ingest,
+        queues: Vec<WorkerQueue>,
+        options: BridgeOptions,
+    ) -> Self {
+        // Never polled, so nothing is ever published
+        let (client, _) = AsyncClient::new(client_options(config, &config.client_id), 10);
+        let (processor, _) = Processor::new(client, database, ingest, state, options, None);
+        let workers = queues
+            .into_iter()
+            .enumerate()
+            .map(|(id, queue)| tokio::spawn(processor.clone().work(id, queue)))
+            .collect();
+        Self { workers }
+    }
+
+    /// Close `ingest` and wait until the workers stored what was queued
+    pub async fn finish(self, ingest: &Ingest) {
+        ingest.close();
+        for worker in self.workers {
+            let _ = worker.await;
+        }
+    }
+}
 
-        // Subscribe to topics
-        let qos = match config.qos {
-            0 => QoS::AtMostOnce,
-            1 => QoS::AtLeastOnce,
-            2 => QoS::ExactlyOnce,
-            _ => QoS::AtMostOnce,
+/// Decodes, routes and stores received messages, shared by the workers
+struct Processor {
+    client: AsyncClient,
+    database: Arc<Database>,
+    /// Current mappings, replaced on reload and discovery
+    mappings: Arc<RwLock<MappingSet>>,
+    state: Arc<BridgeState>,
+    decoder: Arc<dyn Decoder>,
+    sink: Arc<dyn Sink>,
+    options: BridgeOptions,
+    session: Option<Arc<Session>>,
+    acks: mpsc::UnboundedSender<Ack>,
+}
+
+impl MqttBridge {
+    pub async fn new(
+        config: MqttConfig,
+        database: Arc<Database>,
+        state: Arc<BridgeState>,
+        commands: mpsc::Receiver<BridgeCommand>,
+        ingest: Ingest,
+        queues: Vec<WorkerQueue>,
+        mut options: BridgeOptions,
+    ) -> Result<Self> {
+        let session = match &config.session_file {
+            Some(path) => Some(Arc::new(Session::load(path, &config.client_id)?)),
+            None => None,
         };
+        let mut mqttoptions = client_options(&config, &config.client_id);
+        if session.is_some() {
+            // The broker keeps the subscriptions and messages not yet
+            // acknowledged while the bridge is down
+            mqttoptions.set_clean_session(false);
+            mqttoptions.set_manual_acks(true);
+        }
+        let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
 
-        for topic in &config.topics {
-            client
-                .subscribe(topic, qos)
-                .await
-                .with_context(|| format!("Failed to subscribe to topic: {}", topic))?;
+        subscribe(&client, &config).await?;
+
+        let discovery = options.discovery.take();
+        if let Some(discovery) = &discovery {
+            for filter in discovery.filters() {
+                client
+                    .subscribe(&filter, qos(config.qos))
+                    .await
+                    .with_context(|| format!("Failed to subscribe to topic: {}", filter))?;
+            }
         }
 
+        let (processor, acks) = Processor::new(
+            client.clone(),
+            database,
+            &ingest,
+            state.clone(),
+            options,
+            session.clone(),
+        );
+
         Ok(Self {
-            _client: client,
+            client,
             eventloop,
-            db_client,
+            config,
+            state,
+            commands,
+            ingest,
+            queues,
+            discovery,
+            processor,
+            session,
+            acks,
+            unsent_acks: VecDeque::new(),
+            acks_in_flight: 0,
         })
     }
 
+    /// Client of the bridge's broker connection, for publishing
+    pub fn client(&self) -> AsyncClient {
+        self.client.clone()
+    }
+
     pub async fn run(mut self) -> Result<()> {
-        // Set up Ctrl+C handler
+        // Set up Ctrl+C and SIGTERM handlers
         let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel::<()>(1);
 
         tokio::spawn(async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to listen for Ctrl+C");
+            shutdown_signal().await;
             let _ = shutdown_tx.send(()).await;
         });
+        let mut keepalive = systemd::Keepalive::new();
+        let mut catchup_tick = tokio::time::interval(Duration::from_secs(1));
+        catchup_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut failure = None;
+
+        if let Some(watchdog) = &self.processor.options.watchdog {
+            tokio::spawn(watchdog.clone().run(self.client.clone()));
+        }
+
+        let workers: Vec<_> = std::mem::take(&mut self.queues)
+            .into_iter()
+            .enumerate()
+            .map(|(id, queue)| tokio::spawn(self.processor.clone().work(id, queue)))
+            .collect();
+        let replay = tokio::spawn(self.processor.clone().replay());
 
         loop {
+            self.send_acks();
+
             tokio::select! {
                 event = self.eventloop.poll() => {
+                    self.state.touch();
                     match event {
-                        Ok(notification) => {
-                            if let Err(e) = self.handle_event(notification).await {
-                                error!("Error handling event: {}", e);
-                            }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            self.state.catchup.received();
+                            self.receive(publish).await;
                         }
+                        Ok(notification) => self.handle_event(notification),
                         Err(e) => {
                             error!("MQTT connection error: {}", e);
+                            systemd::notify(&format!("STATUS=MQTT connection error: {}", e));
+                            self.state.set_mqtt_connected(false);
+                            metrics().mqtt_reconnects.inc();
                             // Wait before reconnecting
                             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                         }
                     }
                 }
+                Some(ack) = self.acks.recv() => self.unsent_acks.push_back(ack),
+                Some(command) = self.commands.recv() => match command {
+                    BridgeCommand::Shutdown { reason } => {
+                        systemd::notify("STOPPING=1");
+                        failure = Some(reason);
+                        break;
+                    }
+                    command => self.handle_command(command),
+                },
+                _ = catchup_tick.tick() => {
+                    self.state.catchup.tick(self.state.backlog(), self.state.backlog_full());
+                }
+                // Pings stop when the event loop hangs, so systemd restarts
+                // the bridge
+                _ = keepalive.tick() => {
+                    systemd::notify("WATCHDOG=1");
+                }
                 _ = shutdown_rx.recv() => {
                     info!("Shutdown signal received");
+                    systemd::notify("STOPPING=1");
                     break;
                 }
             }
         }
 
-        Ok(())
+        // Let the workers store what is already queued
+        self.ingest.close();
+        for worker in workers {
+            let _ = worker.await;
+        }
+        if self.session.is_some() {
+            self.flush_acks().await;
+        }
+        replay.abort();
+        let held = self.state.held();
+        if held > 0 {
+            match self.session {
+                // Never acknowledged, the broker delivers them again
+                Some(_) => warn!(
+                    "Leaving {} messages held by paused subscriptions to the broker",
+                    held
+                ),
+                None => warn!("Discarding {} messages held by paused subscriptions", held),
+            }
+        }
+
+        match failure {
+            Some(reason) => Err(anyhow!(reason)),
+            None => Ok(()),
+        }
     }
 
-    async fn handle_event(&self, event: Event) -> Result<()> {
-        match event {
-            Event::Incoming(Packet::Publish(publish)) => {
-                let topic = &publish.topic;
-                let payload = &publish.payload;
+    /// Hand a received message to the workers, or to discovery
+    async fn receive(&mut self, publish: Publish) {
+        let ack = Ack {
+            pkid: publish.pkid,
+            qos: publish.qos,
+        };
+        let ack = self
+            .session
+            .as_ref()
+            .filter(|_| ack.qos != QoS::AtMostOnce)
+            .map(|session| (session, ack));
 
-                // Log at debug level only
-                debug!("Received message on topic: {}", topic);
+        if let Some((session, ack)) = ack {
+            match session.receive(ack) {
+                Receipt::New => {}
+                Receipt::Handling => {
+                    debug!("Message {} redelivered while handled, ignoring", ack.pkid);
+                    return;
+                }
+                Receipt::Stored => {
+                    debug!("Message {} redelivered after it was stored", ack.pkid);
+                    self.unsent_acks.push_back(ack);
+                    return;
+                }
+            }
+        }
+        let ack = ack.map(|(_, ack)| ack);
+
+        if self.is_discovery(&publish.topic) {
+            self.discover(&publish.topic, &publish.payload);
+            if let (Some(session), Some(ack)) = (&self.session, ack) {
+                session.handled(ack);
+                self.unsent_acks.push_back(ack);
+            }
+            return;
+        }
+
+        let message = Message {
+            topic: publish.topic,
+            payload: publish.payload.to_vec(),
+            ack,
+        };
+        // Waits while the queue is full, which stops reading from the
+        // broker until workers catch up
+        if let Err(e) = self.ingest.submit(message).await {
+            error!("{}, dropping message", e);
+        }
+    }
 
-                // Parse the message
-                let parsed_messages = parse_message(topic, payload);
+    /// Hand waiting acknowledgements to the client while it has room
+    fn send_acks(&mut self) {
+        while let Some(ack) = self.unsent_acks.front() {
+            if self.client.try_ack(&ack.publish()).is_err() {
+                break;
+            }
+            self.unsent_acks.pop_front();
+            self.acks_in_flight += 1;
+        }
+    }
 
-                // Insert into database
-                for message in parsed_messages {
-                    if let Err(e) = self.insert_message(message).await {
-                        error!("Failed to insert message: {}", e);
+    /// Send the acknowledgements of the messages stored before shutdown,
+    /// those not sent in time are redelivered on the next start
+    async fn flush_acks(&mut self) {
+        let flush = async {
+            loop {
+                while let Ok(ack) = self.acks.try_recv() {
+                    self.unsent_acks.push_back(ack);
+                }
+                self.send_acks();
+                if self.unsent_acks.is_empty() && self.acks_in_flight == 0 {
+                    break;
+                }
+                match self.eventloop.poll().await {
+                    // Left for the broker to redeliver
+                    Ok(Event::Incoming(Packet::Publish(_))) => {}
+                    Ok(event) => self.handle_event(event),
+                    Err(e) => {
+                        warn!("Failed to acknowledge the last messages: {}", e);
+                        break;
                     }
                 }
             }
-            Event::Incoming(Packet::ConnAck(_)) => {
-                info!("Connected to MQTT broker");
+        };
+        if tokio::time::timeout(ACK_FLUSH_TIMEOUT, flush)
+            .await
+            .is_err()
+        {
+            warn!("Timed out acknowledging the last messages");
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Incoming(Packet::ConnAck(ack)) => {
+                if ack.code == ConnectReturnCode::Success {
+                    info!("Connected to MQTT broker");
+                    if let Some(session) = &self.session {
+                        session.connected(ack.session_present, &mut self.eventloop.state);
+                    }
+                    self.state.set_mqtt_connected(true);
+                    self.state.catchup.connected();
+                    // The database is connected before the bridge starts
+                    systemd::notify("READY=1\nSTATUS=Connected to MQTT broker");
+                } else {
+                    error!("MQTT broker refused connection: {:?}", ack.code);
+                }
             }
             Event::Incoming(Packet::SubAck(_)) => {
                 info!("Successfully subscribed to topic");
             }
+            Event::Incoming(Packet::PubRel(release)) => {
+                if let Some(session) = &self.session {
+                    session.released(release.pkid);
+                }
+            }
+            Event::Outgoing(Outgoing::PubAck(_) | Outgoing::PubRec(_)) => {
+                self.acks_in_flight = self.acks_in_flight.saturating_sub(1);
+            }
             Event::Incoming(_) => {
                 // Ignore other incoming packets
             }
@@ -111,20 +529,735 @@ impl MqttBridge {
                 // Ignore outgoing packets
             }
         }
+    }
+
+    fn handle_command(&mut self, command: BridgeCommand) {
+        match command {
+            BridgeCommand::Shutdown { .. } => {}
+            BridgeCommand::SetTopics { topics, reply } => {
+                let previous = self.mappings();
+                self.config.topics = topics.clone();
+                self.state.set_topics(topics);
+
+                let changes = self.apply_mappings(&previous);
+                info!(
+                    "Updating subscriptions: {} added, {} removed",
+                    changes.added.len(),
+                    changes.removed.len()
+                );
+
+                // Requests are queued on a bounded channel drained by this
+                // event loop, so they must not be awaited from inside it
+                let client = self.client.clone();
+                let qos = qos(self.config.qos);
+                tokio::spawn(async move {
+                    let _ = reply.send(resubscribe(&client, qos, changes).await);
+                });
+            }
+        }
+    }
+
+    fn is_discovery(&self, topic: &str) -> bool {
+        self.discovery
+            .as_ref()
+            .is_some_and(|discovery| discovery.matches(topic))
+    }
+
+    /// Apply a discovery message, subscribing to new state topics
+    fn discover(&mut self, topic: &str, payload: &[u8]) {
+        let previous = self.mappings();
+        let Some(discovery) = &mut self.discovery else {
+            return;
+        };
+        if !discovery.handle(topic, payload) {
+            return;
+        }
+
+        let changes = self.apply_mappings(&previous);
+        let client = self.client.clone();
+        let qos = qos(self.config.qos);
+        tokio::spawn(async move {
+            if let Err(e) = resubscribe(&client, qos, changes).await {
+                error!("{:#}", e);
+            }
+        });
+    }
+
+    /// Configured mappings followed by discovered ones, so configured
+    /// mappings take precedence
+    fn mappings(&self) -> Vec<TopicMapping> {
+        let mut mappings = self.config.topics.clone();
+        if let Some(discovery) = &self.discovery {
+            mappings.extend(discovery.mappings());
+        }
+        mappings
+    }
+
+    /// Hand the current mappings to the workers, returns the filters to
+    /// subscribe to and unsubscribe from compared to `previous`
+    fn apply_mappings(&self, previous: &[TopicMapping]) -> TopicChanges {
+        let mappings = self.mappings();
+        let old_filters: Vec<&str> = previous.iter().map(|m| m.filter()).collect();
+        let new_filters: Vec<&str> = mappings.iter().map(|m| m.filter()).collect();
+
+        let changes = TopicChanges {
+            added: new_filters
+                .iter()
+                .filter(|f| !old_filters.contains(f))
+                .map(|f| f.to_string())
+                .collect(),
+            removed: old_filters
+                .iter()
+                .filter(|f| !new_filters.contains(f))
+                .map(|f| f.to_string())
+                .collect(),
+        };
+
+        *self.processor.mappings.write().unwrap() = MappingSet::new(mappings);
+        changes
+    }
+}
+
+/// Wait for Ctrl+C, or SIGTERM as sent by service managers
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.expect("Failed to listen for Ctrl+C"),
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to listen for Ctrl+C");
+}
+
+/// Unsubscribe from removed filters and subscribe to added ones
+async fn resubscribe(
+    client: &AsyncClient,
+    qos: QoS,
+    changes: TopicChanges,
+) -> Result<TopicChanges> {
+    for topic in &changes.removed {
+        client
+            .unsubscribe(topic)
+            .await
+            .with_context(|| format!("Failed to unsubscribe from topic: {}", topic))?;
+    }
+    for topic in &changes.added {
+        client
+            .subscribe(topic, qos)
+            .await
+            .with_context(|| format!("Failed to subscribe to topic: {}", topic))?;
+    }
+    Ok(changes)
+}
+
+impl Processor {
+    /// The processor with the receiver of its acknowledgements
+    fn new(
+        client: AsyncClient,
+        database: Arc<Database>,
+        ingest: &Ingest,
+        state: Arc<BridgeState>,
+        mut options: BridgeOptions,
+        session: Option<Arc<Session>>,
+    ) -> (Arc<Self>, mpsc::UnboundedReceiver<Ack>) {
+        let decoder = options
+            .decoder
+            .take()
+            .unwrap_or_else(|| Arc::new(JsonDecoder));
+        let sink = options
+            .sink
+            .take()
+            .unwrap_or_else(|| Arc::new(PostgresSink::new(database.clone(), Default::default())));
+
+        let (acks_tx, acks) = mpsc::unbounded_channel();
+        let processor = Arc::new(Self {
+            client,
+            database,
+            mappings: ingest.mappings(),
+            state,
+            decoder,
+            sink,
+            options,
+            session,
+            acks: acks_tx,
+        });
+        (processor, acks)
+    }
+
+    /// Handle messages from the queue until the bridge shuts down
+    async fn work(self: Arc<Self>, id: usize, queue: WorkerQueue) {
+        let worker = id.to_string();
+
+        loop {
+            let Some(messages) = self.next_messages(&queue).await else {
+                break;
+            };
+
+            let started = Instant::now();
+            let count = messages.len();
+            match <[Message; 1]>::try_from(messages) {
+                Ok([message]) => self.handle_message(message).await,
+                Err(messages) => self.handle_messages(messages).await,
+            }
+
+            metrics()
+                .worker_messages
+                .with_label_values(&[&worker])
+                .inc_by(count as u64);
+            metrics()
+                .worker_busy
+                .with_label_values(&[&worker])
+                .inc_by(started.elapsed().as_secs_f64());
+        }
+    }
+
+    /// The next message of the queue, with those that follow it up to a
+    /// batch while catching up on a backlog
+    async fn next_messages(&self, queue: &WorkerQueue) -> Option<Vec<Message>> {
+        // Only one idle worker waits on the queue at a time
+        let mut queue = queue.lock().await;
+        let message = queue.recv().await?;
+        self.state.dequeued();
+        let mut messages = vec![message];
+
+        let catchup = &self.state.catchup;
+        if catchup.active() {
+            let deadline = tokio::time::Instant::now() + catchup.batch_wait();
+            while messages.len() < catchup.batch_size() {
+                match tokio::time::timeout_at(deadline, queue.recv()).await {
+                    Ok(Some(message)) => {
+                        self.state.dequeued();
+                        messages.push(message);
+                    }
+                    Ok(None) | Err(_) => break,
+                }
+            }
+        }
+        Some(messages)
+    }
+
+    /// Let the broker know a message is done with, once remembered in the
+    /// session
+    fn acknowledge(&self, ack: Ack) {
+        if let Some(session) = &self.session {
+            session.handled(ack);
+        }
+        let _ = self.acks.send(ack);
+    }
+
+    /// Acknowledge a message once done with, stored or dropped on purpose
+    /// A message that failed to store is left unacknowledged for the
+    /// broker to redeliver on the next connection
+    fn finish(&self, message: &Message, stored: bool) {
+        let Some(ack) = message.ack else {
+            return;
+        };
+        if stored {
+            self.acknowledge(ack);
+        } else if let Some(session) = &self.session {
+            session.abandon(ack);
+        }
+    }
+
+    async fn handle_message(&self, message: Message) {
+        let Some(held) = self.parse(message).await else {
+            return;
+        };
+        if let Some(held) = self.hold(held) {
+            self.store(held).await;
+        }
+    }
+
+    /// The message when it is to be stored now, messages of paused
+    /// subscriptions are held or dropped
+    fn hold(&self, held: HeldMessage) -> Option<HeldMessage> {
+        match self.state.hold(held) {
+            Hold::Store(held) => Some(*held),
+            Hold::Held => None,
+            Hold::Dropped(message) => {
+                self.finish(&message, true);
+                None
+            }
+            Hold::Overflowed(oldest) => {
+                self.finish(&oldest, false);
+                None
+            }
+        }
+    }
 
-        Ok(())
+    /// Handle messages taken from the queue together, their records
+    /// written at once
+    async fn handle_messages(&self, messages: Vec<Message>) {
+        let mut held = Vec::with_capacity(messages.len());
+        for message in messages {
+            if let Some(parsed) = self.parse(message).await {
+                held.extend(self.hold(parsed));
+            }
+        }
+        self.store_bulk(held).await;
     }
 
-    async fn insert_message(&self, message: ParsedMessage) -> Result<()> {
-        match message {
-            ParsedMessage::TelemetryReading(reading) => {
-                reading.insert(&self.db_client).await?;
+    /// Decode a message into the records to store, `None` when it is
+    /// dropped
+    async fn parse(&self, message: Message) -> Option<HeldMessage> {
+        let topic = &message.topic;
+        let payload = &message.payload;
+
+        // Log at debug level only
+        debug!("Received message on topic: {}", topic);
+
+        let mapping = self.mappings.read().unwrap().find(topic).cloned();
+        let subscription = mapping.as_ref().map_or("unknown", |m| m.name());
+        metrics()
+            .messages_received
+            .with_label_values(&[subscription])
+            .inc();
+
+        let decoder = self.decoder(mapping.as_ref());
+        let limit = self.options.payloads.max_size;
+        let (mut parsed_messages, fields) = if limit > 0 && payload.len() > limit {
+            (self.oversized(topic, payload, subscription), None)
+        } else {
+            // Parse the message
+            let (parsed_messages, fields) =
+                decode_message(mapping.as_ref(), decoder.as_ref(), topic, payload).await;
+
+            let readings = parsed_messages
+                .iter()
+                .filter(|message| {
+                    matches!(
+                        message,
+                        ParsedMessage::TelemetryReading(_) | ParsedMessage::WideRow(_)
+                    )
+                })
+                .count();
+            if readings == 0 {
+                metrics()
+                    .parse_failures
+                    .with_label_values(&[subscription])
+                    .inc();
             }
-            ParsedMessage::RawMessage(msg) => {
-                msg.insert(&self.db_client).await?;
+            self.state.stats.record_message(subscription, readings);
+            if mapping.is_none() || readings == 0 {
+                self.state.stats.record_unmatched(
+                    topic,
+                    mapping.as_ref().map(|m| m.name()),
+                    payload,
+                );
             }
+
+            (parsed_messages, fields)
+        };
+
+        if let Some(tenants) = &self.options.tenants {
+            if let Err(tenant) = tenants.assign(topic, &mut parsed_messages) {
+                warn!(
+                    "Dropping message from rejected tenant {} on {}",
+                    tenant, topic
+                );
+                self.state.stats.record_dropped(subscription);
+                self.finish(&message, true);
+                return None;
+            }
+        }
+
+        let device_id = parsed_messages
+            .iter()
+            .find_map(ParsedMessage::device_id)
+            .map(str::to_string);
+
+        // Devices on paused subscriptions are still publishing
+        if let (Some(watchdog), Some(device_id)) = (&self.options.watchdog, &device_id) {
+            watchdog.seen(device_id, &self.client);
+        }
+
+        Some(HeldMessage {
+            message,
+            mapping,
+            records: parsed_messages,
+            fields,
+        })
+    }
+
+    /// Store the messages held while paused once their subscription resumes
+    async fn replay(self: Arc<Self>) {
+        loop {
+            self.state.resumed.notified().await;
+
+            let mut stored = 0;
+            while let Some(held) = self.state.next_resumed() {
+                self.store(held).await;
+                stored += 1;
+            }
+            if stored > 0 {
+                info!("Stored {} messages held while paused", stored);
+            }
+        }
+    }
+
+    /// Evaluate alerts, adjust and store the records of a message
+    async fn store(&self, held: HeldMessage) {
+        if let Some(prepared) = self.prepare(held).await {
+            self.write(prepared).await;
+        }
+    }
+
+    /// Store the records of many messages with bulk writes of whole
+    /// messages, the messages of a failing write are stored one at a time
+    async fn store_bulk(&self, held: Vec<HeldMessage>) {
+        let mut prepared = Vec::with_capacity(held.len());
+        for held in held {
+            if let Some(message) = self.prepare(held).await {
+                prepared.push(message);
+            }
+        }
+
+        // A message larger than `commit_rows` is still written whole
+        let commit_rows = self.options.commit_rows;
+        let mut batch = Vec::new();
+        let mut rows = 0;
+        for message in prepared {
+            let count = message.records.len();
+            if commit_rows > 0 && rows > 0 && rows + count > commit_rows {
+                self.write_bulk(std::mem::take(&mut batch)).await;
+                rows = 0;
+            }
+            rows += count;
+            batch.push(message);
+        }
+        self.write_bulk(batch).await;
+    }
+
+    /// Write the records of `prepared` in one transaction
+    async fn write_bulk(&self, prepared: Vec<Prepared>) {
+        let records: Vec<ParsedMessage> = prepared
+            .iter()
+            .flat_map(|message| message.records.iter().cloned())
+            .collect();
+        if records.is_empty() {
+            for message in &prepared {
+                self.finish(&message.message, true);
+            }
+            return;
+        }
+        let result = self.insert_bulk(&records).await;
+        if let Err(e) = result {
+            // Only the messages with a failing row should fail
+            warn!(
+                "Bulk write of {} rows failed, writing the messages one at a time: {:#}",
+                records.len(),
+                e
+            );
+            for message in prepared {
+                self.write(message).await;
+            }
+            return;
+        }
+
+        for message in &prepared {
+            self.stored(message, &message.records, &Ok(()));
+            self.state
+                .budget
+                .record_message(message.subscription(), false);
+            self.finish(&message.message, true);
+        }
+        debug!(
+            "Wrote {} rows of {} messages in bulk",
+            records.len(),
+            prepared.len()
+        );
+    }
+
+    /// Alerts evaluated and readings adjusted for storing, `None` when the
+    /// message is not to be stored
+    async fn prepare(&self, held: HeldMessage) -> Option<Prepared> {
+        let HeldMessage {
+            message,
+            mapping,
+            records: mut parsed_messages,
+            fields: json,
+        } = held;
+        let topic = &message.topic;
+        let subscription = mapping.as_ref().map_or("unknown", |m| m.name());
+
+        // Inserts of a mapping failing too often are not even attempted
+        if !self.state.budget.admit(subscription) {
+            debug!("Mapping {} is disabled, dropping message", subscription);
+            self.state.stats.record_dropped(subscription);
+            self.finish(&message, true);
+            return None;
         }
 
-        Ok(())
+        if let Some(alerts) = &self.options.alerts {
+            for message in &parsed_messages {
+                if let ParsedMessage::TelemetryReading(reading) = message {
+                    for (rule, event) in alerts.evaluate(reading) {
+                        alerts.dispatch(rule, event, &self.client);
+                    }
+                }
+            }
+        }
+
+        // Ingest lag by the device's own timestamp, invalid ones are
+        // reported by `apply`
+        if let Some(mapping) = mapping.as_ref().filter(|m| m.spec().timestamp.is_some()) {
+            if let Ok(Some(timestamp)) = mapping.timestamp(json.as_ref()) {
+                let lag = (Utc::now() - timestamp).num_milliseconds() as f64 / 1000.0;
+                metrics()
+                    .ingest_lag
+                    .with_label_values(&[subscription])
+                    .observe(lag.max(0.0));
+                self.state.stats.record_lag(subscription, lag);
+            }
+        }
+
+        if let Some(mapping) = mapping.as_ref().filter(|m| m.adjusts_readings()) {
+            parsed_messages.retain_mut(|message| match message {
+                ParsedMessage::TelemetryReading(reading) => {
+                    match mapping.apply(topic, json.as_ref(), reading) {
+                        Ok(None) => true,
+                        Ok(Some(out_of_range)) => {
+                            debug!(
+                                "Reading of {} on {} out of range, {}",
+                                reading.device_id,
+                                topic,
+                                out_of_range.as_str()
+                            );
+                            metrics()
+                                .timestamps_out_of_range
+                                .with_label_values(&[subscription, out_of_range.as_str()])
+                                .inc();
+                            if out_of_range == OutOfRange::Drop {
+                                self.state.stats.record_dropped(subscription);
+                                return false;
+                            }
+                            true
+                        }
+                        Err(e) => {
+                            self.state.budget.log_error(
+                                subscription,
+                                "Failed to route reading",
+                                &e,
+                            );
+                            self.state.stats.record_insert(subscription, Some(&e));
+                            false
+                        }
+                    }
+                }
+                ParsedMessage::RawMessage(_) | ParsedMessage::WideRow(_) => true,
+            });
+        }
+
+        if self.options.register_devices {
+            let device_id = parsed_messages.iter().find_map(ParsedMessage::device_id);
+            if let Some(device_id) = device_id {
+                if let Err(e) = self.register_device(device_id, topic).await {
+                    error!("Failed to register device: {}", e);
+                }
+            }
+        }
+
+        Some(Prepared {
+            message,
+            mapping,
+            records: parsed_messages,
+            json,
+        })
+    }
+
+    /// Insert the records of a message, committed together
+    async fn write(&self, prepared: Prepared) {
+        let subscription = prepared.subscription();
+        let result = if prepared.records.is_empty() {
+            Ok(())
+        } else {
+            self.insert_messages(&prepared.records).await
+        };
+        if let Err(e) = &result {
+            self.state
+                .budget
+                .log_error(subscription, "Failed to insert message", e);
+        }
+        self.stored(&prepared, &prepared.records, &result);
+        if !prepared.records.is_empty() {
+            self.state
+                .budget
+                .record_message(subscription, result.is_err());
+        }
+        self.finish(&prepared.message, result.is_ok());
+    }
+
+    /// Count the written `records` of a message and republish its readings
+    fn stored(&self, prepared: &Prepared, records: &[ParsedMessage], result: &Result<()>) {
+        for message in records {
+            self.state
+                .stats
+                .record_insert(prepared.subscription(), result.as_ref().err());
+
+            if let (Ok(()), ParsedMessage::TelemetryReading(reading), Some(mapping)) =
+                (result, message, &prepared.mapping)
+            {
+                self.republish(
+                    mapping,
+                    &prepared.message.topic,
+                    prepared.json.as_ref(),
+                    reading,
+                );
+            }
+        }
+    }
+
+    /// Publish a stored reading as set by the mapping's `republish`
+    /// Readings are dropped rather than holding up storage while the
+    /// client is backed up
+    fn republish(
+        &self,
+        mapping: &TopicMapping,
+        topic: &str,
+        payload: Option<&Value>,
+        reading: &TelemetryReading,
+    ) {
+        let Some(republish) = &mapping.spec().republish else {
+            return;
+        };
+
+        let target = match mapping.republish_topic(topic, payload, reading) {
+            Ok(Some(target)) => target,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to republish reading: {}", e);
+                return;
+            }
+        };
+        let client = match &republish.broker {
+            Some(name) => match self.options.publishers.get(name) {
+                Some(client) => client,
+                None => {
+                    warn!("Unknown broker {}, not republishing", name);
+                    return;
+                }
+            },
+            None => &self.client,
+        };
+
+        let message = json!({
+            "device_id": reading.device_id,
+            "sensor_name": reading.sensor_name,
+            "value": reading.value,
+            "timestamp": reading.timestamp,
+            "topic": reading.topic,
+            "unit": reading.unit,
+            "device_class": reading.device_class,
+            "quality": reading.quality.as_ref().map(|(quality, _)| quality),
+        });
+        if let Err(e) = client.try_publish(
+            &target,
+            qos(republish.qos),
+            republish.retain,
+            message.to_string(),
+        ) {
+            warn!("Failed to republish reading to {}: {}", target, e);
+        }
+    }
+
+    /// Records kept of a payload over the size limit, which is not decoded
+    fn oversized(&self, topic: &str, payload: &[u8], subscription: &str) -> Vec<ParsedMessage> {
+        let limit = self.options.payloads.max_size;
+        let policy = self.options.payloads.oversized;
+        let action = match policy {
+            OversizedPayload::Drop => "dropping it",
+            OversizedPayload::Truncate => "truncating it",
+            OversizedPayload::Hash => "storing its digest",
+        };
+        warn!(
+            "Payload of {} bytes on {} is over the {} byte limit, {}",
+            payload.len(),
+            topic,
+            limit,
+            action
+        );
+        metrics()
+            .oversized_payloads
+            .with_label_values(&[subscription])
+            .inc();
+
+        let stored = match policy {
+            OversizedPayload::Drop => {
+                self.state.stats.record_dropped(subscription);
+                return Vec::new();
+            }
+            OversizedPayload::Truncate => String::from_utf8_lossy(&payload[..limit]).into_owned(),
+            OversizedPayload::Hash => format!("sha256:{:x}", Sha256::digest(payload)),
+        };
+
+        vec![ParsedMessage::RawMessage(RawMessage {
+            topic: topic.to_string(),
+            payload: stored,
+            timestamp: Utc::now(),
+            tenant: None,
+            payload_size: Some(payload.len() as i64),
+        })]
+    }
+
+    /// Decoder selected by `mapping`, the default decoder otherwise
+    fn decoder(&self, mapping: Option<&TopicMapping>) -> Arc<dyn Decoder> {
+        self.options.decoders.select(mapping, &self.decoder)
+    }
+
+    async fn insert_messages(&self, messages: &[ParsedMessage]) -> Result<()> {
+        let started = Instant::now();
+
+        let result = match messages {
+            [message] => self.sink.write(message).await,
+            _ => self.sink.write_batch(messages).await,
+        };
+
+        for message in messages {
+            observe_insert(message.table(), started, &result);
+        }
+        result
+    }
+
+    async fn insert_bulk(&self, messages: &[ParsedMessage]) -> Result<()> {
+        let started = Instant::now();
+        let result = self.sink.write_bulk(messages).await;
+
+        for message in messages {
+            observe_insert(message.table(), started, &result);
+        }
+        result
+    }
+
+    async fn register_device(&self, device_id: &str, topic: &str) -> Result<()> {
+        let started = Instant::now();
+        let result = match self.database.connection().await {
+            Ok(connection) => {
+                db::register_device(&connection.client, device_id, topic, Utc::now()).await
+            }
+            Err(e) => Err(e),
+        };
+
+        observe_insert(db::DEVICES_TABLE, started, &result);
+        result
+    }
+}
+
+/// Record the latency and outcome of a write to `table`
+fn observe_insert(table: &str, started: Instant, result: &Result<()>) {
+    metrics()
+        .insert_latency
+        .with_label_values(&[table])
+        .observe(started.elapsed().as_secs_f64());
+
+    match result {
+        Ok(()) => metrics().rows_inserted.with_label_values(&[table]).inc(),
+        Err(_) => metrics().insert_errors.with_label_values(&[table]).inc(),
     }
 }
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use async_nats::jetstream::{self, consumer::pull};
+use async_nats::{Client, ConnectOptions};
+use futures::stream::{self, StreamExt};
+use tracing::{error, warn};
+
+use crate::config::{JetStreamConfig, NatsConfig};
+use crate::ingest::{Ingest, Message};
+
+/// Connect to the NATS servers and hand messages on the configured subjects
+/// to the workers until the bridge shuts down
+pub async fn start(config: &NatsConfig, ingest: Ingest) -> Result<()> {
+    let client = connect(config).await?;
+
+    match &config.jetstream {
+        Some(jetstream) => {
+            let messages = consume(&client, config, jetstream).await?;
+            tokio::spawn(read_jetstream(messages, ingest));
+        }
+        None => {
+            let mut subscribers = Vec::with_capacity(config.subjects.len());
+            for subject in &config.subjects {
+                let subscriber = match &config.queue_group {
+                    Some(group) => client.queue_subscribe(subject.clone(), group.clone()).await,
+                    None => client.subscribe(subject.clone()).await,
+                }
+                .with_context(|| format!("Failed to subscribe to NATS subject: {}", subject))?;
+                subscribers.push(subscriber);
+            }
+
+            let mut messages = stream::select_all(subscribers);
+            tokio::spawn(async move {
+                // Subscriptions end when the client is dropped
+                let _client = client;
+                while let Some(message) = messages.next().await {
+                    if !submit(&ingest, &message.subject, &message.payload).await {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+async fn connect(config: &NatsConfig) -> Result<Client> {
+    let mut options = ConnectOptions::new().name("anvil");
+    if let Some(path) = &config.credentials_file {
+        options = options
+            .credentials_file(path)
+            .await
+            .with_context(|| format!("Failed to read NATS credentials file: {}", path))?;
+    }
+    if let Some(token) = &config.token {
+        options = options.token(token.clone());
+    }
+    if let Some(username) = &config.username {
+        options = options.user_and_password(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        );
+    }
+
+    async_nats::connect_with_options(&config.servers, options)
+        .await
+        .with_context(|| format!("Failed to connect to NATS: {}", config.servers.join(", ")))
+}
+
+/// Messages of the durable consumer, created on the stream when missing
+async fn consume(
+    client: &Client,
+    config: &NatsConfig,
+    jetstream: &JetStreamConfig,
+) -> Result<pull::Stream> {
+    let stream = jetstream::new(client.clone())
+        .get_stream(&jetstream.stream)
+        .await
+        .with_context(|| format!("Failed to find JetStream stream: {}", jetstream.stream))?;
+
+    // A single filter works with servers older than 2.10
+    let (filter_subject, filter_subjects) = match &config.subjects[..] {
+        [subject] => (subject.clone(), Vec::new()),
+        subjects => (String::new(), subjects.to_vec()),
+    };
+
+    let consumer = stream
+        .get_or_create_consumer(
+            &jetstream.consumer,
+            pull::Config {
+                durable_name: Some(jetstream.consumer.clone()),
+                filter_subject,
+                filter_subjects,
+                ..Default::default()
+            },
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to create JetStream consumer: {}",
+                jetstream.consumer
+            )
+        })?;
+
+    consumer
+        .messages()
+        .await
+        .with_context(|| format!("Failed to read JetStream consumer: {}", jetstream.consumer))
+}
+
+/// Messages are acknowledged once queued, a message that could not be
+/// queued is redelivered by the server
+async fn read_jetstream(mut messages: pull::Stream, ingest: Ingest) {
+    while let Some(message) = messages.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("JetStream error: {}", e);
+                continue;
+            }
+        };
+
+        if !submit(&ingest, &message.subject, &message.payload).await {
+            break;
+        }
+        if let Err(e) = message.ack().await {
+            error!("Failed to acknowledge JetStream message: {}", e);
+        }
+    }
+}
+
+/// Queue a message, the subject's levels becoming topic levels
+/// Returns false once the bridge is shutting down
+async fn submit(ingest: &Ingest, subject: &str, payload: &[u8]) -> bool {
+    let message = Message {
+        topic: subject.replace('.', "/"),
+        payload: payload.to_vec(),
+        ack: None,
+    };
+
+    match ingest.submit(message).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Stopped reading from NATS: {}", e);
+            false
+        }
+    }
+}
@@ -1,15 +1,61 @@
-use chrono::Utc;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use serde_json::Value;
 use tracing::{debug, warn};
 
-use crate::db::{RawMessage, TelemetryReading};
+use crate::db::{self, RawMessage, TableName, TelemetryReading, WideRow};
+use crate::mapping::SensorSpec;
+use crate::tenant::Tenant;
 
 /// Parse MQTT message into database records
 /// Handles simple JSON telemetry like {"temperature": 80, "ph": 2.4}
 pub fn parse_message(topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+    // Convert payload to string
+    let payload_str = match String::from_utf8(payload.to_vec()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to decode payload as UTF-8: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let json = serde_json::from_str::<Value>(&payload_str).ok();
+    parse_value(topic, payload_str, json.as_ref())
+}
+
+/// Parse a payload already decoded into `json` into database records,
+/// `payload` being the text stored in the audit trail
+pub fn parse_value(topic: &str, payload: String, json: Option<&Value>) -> Vec<ParsedMessage> {
+    // Always store raw message for audit trail
+    let mut results = vec![ParsedMessage::RawMessage(RawMessage {
+        topic: topic.to_string(),
+        payload,
+        timestamp: Utc::now(),
+        tenant: None,
+        payload_size: None,
+    })];
+
+    // Parse telemetry readings from flat JSON
+    if let Some(readings) = json.and_then(|json| parse_telemetry(topic, json)) {
+        results.extend(readings.into_iter().map(ParsedMessage::TelemetryReading));
+    }
+
+    debug!("Parsed {} records from topic {}", results.len(), topic);
+    results
+}
+
+/// Parse a message of a mapping with `sensors` into database records
+/// Handles plain values like `21.5` and JSON objects holding the sensors'
+/// fields, values that are not numeric are skipped
+pub fn parse_sensors(
+    topic: &str,
+    payload: &[u8],
+    device_id: Option<&str>,
+    sensors: &[SensorSpec],
+    paths: &[Option<Vec<String>>],
+) -> Vec<ParsedMessage> {
     let mut results = Vec::new();
 
-    // Convert payload to string
     let payload_str = match String::from_utf8(payload.to_vec()) {
         Ok(s) => s,
         Err(e) => {
@@ -18,29 +64,117 @@ pub fn parse_message(topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
         }
     };
 
-    // Always store raw message for audit trail
     results.push(ParsedMessage::RawMessage(RawMessage {
         topic: topic.to_string(),
         payload: payload_str.clone(),
         timestamp: Utc::now(),
+        tenant: None,
+        payload_size: None,
     }));
 
-    // Try to parse as JSON
-    if let Ok(json) = serde_json::from_str::<Value>(&payload_str) {
-        // Parse telemetry readings from flat JSON
-        if let Some(readings) = parse_telemetry(topic, &json) {
-            results.extend(readings.into_iter().map(ParsedMessage::TelemetryReading));
-        }
+    let json = serde_json::from_str::<Value>(&payload_str).unwrap_or(Value::Null);
+    let device_id = device_id
+        .map(str::to_string)
+        .unwrap_or_else(|| extract_device_id(topic, &json));
+    let timestamp = extract_timestamp(&json);
+
+    for (sensor, path) in sensors.iter().zip(paths) {
+        let value = match path {
+            Some(path) => path
+                .iter()
+                .try_fold(&json, |value, key| value.get(key))
+                .and_then(numeric),
+            None => numeric(&json),
+        };
+        let Some(value) = value else {
+            continue;
+        };
+
+        results.push(ParsedMessage::TelemetryReading(TelemetryReading {
+            device_id: device_id.clone(),
+            sensor_name: sensor.name.clone(),
+            value,
+            topic: topic.to_string(),
+            timestamp,
+            tenant: None,
+            table: None,
+            dedupe_key: None,
+            unit: sensor.unit.clone(),
+            device_class: sensor.device_class.clone(),
+            quality: None,
+            clock_offset: None,
+            custom_insert: None,
+        }));
     }
 
     debug!("Parsed {} records from topic {}", results.len(), topic);
     results
 }
 
-#[derive(Debug)]
+/// Nested fields of a payload field, `.` separating them
+pub fn field_path(field: &str) -> Vec<String> {
+    field.split('.').map(str::to_string).collect()
+}
+
+/// Nested fields of the `field` of each sensor, split once rather than
+/// for every message
+pub fn sensor_paths(sensors: &[SensorSpec]) -> Vec<Option<Vec<String>>> {
+    sensors
+        .iter()
+        .map(|sensor| sensor.field.as_deref().map(field_path))
+        .collect()
+}
+
+/// A number, or a string holding one as some devices send
+fn numeric(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok().filter(|v: &f64| v.is_finite()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ParsedMessage {
     TelemetryReading(TelemetryReading),
     RawMessage(RawMessage),
+    WideRow(WideRow),
+}
+
+impl ParsedMessage {
+    /// Table this record is written to
+    pub fn table(&self) -> &'static str {
+        match self {
+            ParsedMessage::TelemetryReading(_) | ParsedMessage::WideRow(_) => db::TELEMETRY_TABLE,
+            ParsedMessage::RawMessage(_) => db::RAW_MESSAGES_TABLE,
+        }
+    }
+
+    /// Table this record is written to, including tenant and mapping routing
+    pub fn target(&self) -> TableName {
+        match self {
+            ParsedMessage::TelemetryReading(reading) => reading.target(),
+            ParsedMessage::RawMessage(msg) => msg.target(),
+            ParsedMessage::WideRow(row) => row.target(),
+        }
+    }
+
+    /// Device the record came from, unknown for raw messages
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            ParsedMessage::TelemetryReading(reading) => Some(&reading.device_id),
+            ParsedMessage::WideRow(row) => Some(&row.device_id),
+            ParsedMessage::RawMessage(_) => None,
+        }
+    }
+
+    pub fn set_tenant(&mut self, tenant: Tenant) {
+        match self {
+            ParsedMessage::TelemetryReading(reading) => reading.tenant = Some(tenant),
+            ParsedMessage::RawMessage(msg) => msg.tenant = Some(tenant),
+            ParsedMessage::WideRow(row) => row.tenant = Some(tenant),
+        }
+    }
 }
 
 /// Parse telemetry readings from flat JSON
@@ -71,6 +205,14 @@ fn parse_telemetry(topic: &str, json: &Value) -> Option<Vec<TelemetryReading>> {
                     value: num,
                     topic: topic.to_string(),
                     timestamp,
+                    tenant: None,
+                    table: None,
+                    dedupe_key: None,
+                    unit: None,
+                    device_class: None,
+                    quality: None,
+                    clock_offset: None,
+                    custom_insert: None,
                 });
             }
         }
@@ -85,7 +227,7 @@ fn parse_telemetry(topic: &str, json: &Value) -> Option<Vec<TelemetryReading>> {
 
 /// Extract device_id from topic or JSON
 /// Topic format expected: device/<category>/<device_id> or similar
-fn extract_device_id(topic: &str, json: &Value) -> String {
+pub fn extract_device_id(topic: &str, json: &Value) -> String {
     // Try to get from JSON first
     if let Some(id) = json
         .get("device_id")
@@ -113,7 +255,7 @@ fn extract_device_id(topic: &str, json: &Value) -> String {
 }
 
 /// Extract timestamp from JSON or use current time
-fn extract_timestamp(json: &Value) -> chrono::DateTime<Utc> {
+pub fn extract_timestamp(json: &Value) -> chrono::DateTime<Utc> {
     if let Some(ts) = json.get("timestamp").or_else(|| json.get("ts")) {
         // Try to parse as ISO8601 string
         if let Some(ts_str) = ts.as_str() {
@@ -123,22 +265,60 @@ fn extract_timestamp(json: &Value) -> chrono::DateTime<Utc> {
         }
 
         // Try to parse as Unix timestamp (seconds or milliseconds)
-        if let Some(ts_num) = ts.as_i64() {
-            // Check if this looks like milliseconds (> year 2100 in seconds)
-            if ts_num > 4102444800 {
-                let secs = ts_num / 1000;
-                let nsecs = ((ts_num % 1000) * 1_000_000) as u32;
-                if let Some(dt) = chrono::DateTime::from_timestamp(secs, nsecs) {
-                    return dt;
-                }
-            } else {
-                // Seconds
-                if let Some(dt) = chrono::DateTime::from_timestamp(ts_num, 0) {
-                    return dt;
-                }
-            }
+        if let Some(dt) = ts.as_i64().and_then(epoch_timestamp) {
+            return dt;
         }
     }
 
     Utc::now()
 }
+
+/// A timestamp in a payload field, strings without an offset are local
+/// time in `timezone`
+/// Strings are read with `format` (chrono strftime) when given, otherwise
+/// as RFC 3339 or `YYYY-MM-DD HH:MM:SS`, numbers as Unix timestamps
+pub fn parse_timestamp(
+    value: &Value,
+    format: Option<&str>,
+    timezone: Tz,
+) -> Option<chrono::DateTime<Utc>> {
+    let text = match value {
+        Value::String(text) => text.trim(),
+        Value::Number(number) => return number.as_i64().and_then(epoch_timestamp),
+        _ => return None,
+    };
+
+    let local = match format {
+        Some(format) => {
+            if let Ok(dt) = chrono::DateTime::parse_from_str(text, format) {
+                return Some(dt.with_timezone(&Utc));
+            }
+            NaiveDateTime::parse_from_str(text, format).ok()?
+        }
+        None => {
+            if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(text) {
+                return Some(dt.with_timezone(&Utc));
+            }
+            NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f")
+                .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f"))
+                .ok()?
+        }
+    };
+
+    // The earlier of the two readings of a time repeated when clocks go back
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// A Unix timestamp in seconds, or milliseconds past year 2100 in seconds
+fn epoch_timestamp(ts: i64) -> Option<chrono::DateTime<Utc>> {
+    if ts > 4102444800 {
+        let secs = ts / 1000;
+        let nsecs = ((ts % 1000) * 1_000_000) as u32;
+        chrono::DateTime::from_timestamp(secs, nsecs)
+    } else {
+        chrono::DateTime::from_timestamp(ts, 0)
+    }
+}
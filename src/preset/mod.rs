@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::mapping::{SensorSpec, TopicMapping};
+use crate::parser::{parse_sensors, sensor_paths, ParsedMessage};
+
+/// Unit and device class of well-known Tasmota fields, by field name
+const TASMOTA_FIELDS: &[(&str, &str, &str)] = &[
+    ("Temperature", "", "temperature"),
+    ("DewPoint", "", "temperature"),
+    ("Humidity", "%", "humidity"),
+    ("Pressure", "", "pressure"),
+    ("SeaPressure", "", "pressure"),
+    ("Illuminance", "lx", "illuminance"),
+    ("CarbonDioxide", "ppm", "carbon_dioxide"),
+    ("Power", "W", "power"),
+    ("ApparentPower", "VA", "apparent_power"),
+    ("ReactivePower", "var", "reactive_power"),
+    ("Voltage", "V", "voltage"),
+    ("Current", "A", "current"),
+    ("Total", "kWh", "energy"),
+    ("Today", "kWh", "energy"),
+    ("Yesterday", "kWh", "energy"),
+];
+
+/// Payload formats of common device firmwares
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    /// Tasmota `tele/<topic>/SENSOR` messages, e.g.
+    /// `{"AM2301": {"Temperature": 22.1, "Humidity": 45}, "TempUnit": "C"}`
+    /// with one reading per number named after its path, `AM2301_Temperature`
+    TasmotaSensor,
+    /// ESPHome `<node>/sensor/<sensor>/state` messages holding a plain value
+    Esphome,
+}
+
+impl Preset {
+    /// Topic the firmware publishes on by default
+    pub fn topic(self) -> &'static str {
+        match self {
+            Preset::TasmotaSensor => "tele/{device_id}/SENSOR",
+            Preset::Esphome => "{device_id}/sensor/{sensor}/state",
+        }
+    }
+
+    /// Records of a message on `topic`, the device is the `{device_id}`
+    /// capture of `mapping`
+    pub fn decode(self, mapping: &TopicMapping, topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
+        let device_id = mapping.capture(topic, "device_id");
+
+        let sensors = match self {
+            Preset::TasmotaSensor => match serde_json::from_slice::<Value>(payload) {
+                Ok(Value::Object(fields)) => tasmota_sensors(&fields),
+                _ => Vec::new(),
+            },
+            Preset::Esphome => {
+                // The level before `state` names the sensor in custom topics
+                let name = mapping.capture(topic, "sensor").or_else(|| {
+                    let levels: Vec<&str> = topic.split('/').collect();
+                    levels.len().checked_sub(2).map(|index| levels[index])
+                });
+                name.map(|name| SensorSpec {
+                    name: name.to_string(),
+                    ..SensorSpec::default()
+                })
+                .into_iter()
+                .collect()
+            }
+        };
+
+        parse_sensors(topic, payload, device_id, &sensors, &sensor_paths(&sensors))
+    }
+}
+
+/// A sensor for every number in a Tasmota payload, nested objects being
+/// the attached sensor chips or the energy meter
+fn tasmota_sensors(fields: &Map<String, Value>) -> Vec<SensorSpec> {
+    let temperature_unit = match fields.get("TempUnit").and_then(Value::as_str) {
+        Some("F") => "°F",
+        _ => "°C",
+    };
+    let pressure_unit = fields
+        .get("PressureUnit")
+        .and_then(Value::as_str)
+        .unwrap_or("hPa");
+
+    let mut sensors = Vec::new();
+    let mut pending: Vec<(Vec<&str>, &Map<String, Value>)> = vec![(Vec::new(), fields)];
+    while let Some((path, fields)) = pending.pop() {
+        for (key, value) in fields {
+            let mut path = path.clone();
+            path.push(key.as_str());
+
+            match value {
+                Value::Number(_) => {
+                    let known = TASMOTA_FIELDS.iter().find(|(name, _, _)| name == key);
+                    let unit = known.map(|(_, unit, class)| match *class {
+                        "temperature" => temperature_unit,
+                        "pressure" => pressure_unit,
+                        _ => unit,
+                    });
+
+                    sensors.push(SensorSpec {
+                        name: path.join("_"),
+                        field: Some(path.join(".")),
+                        unit: unit.map(str::to_string),
+                        device_class: known.map(|(_, _, class)| class.to_string()),
+                    });
+                }
+                Value::Object(nested) => pending.push((path, nested)),
+                _ => {}
+            }
+        }
+    }
+
+    sensors
+}
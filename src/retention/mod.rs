@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio_postgres::Client;
+use tracing::{error, info};
+
+use crate::config::RetentionConfig;
+use crate::db::{self, TableName, RAW_MESSAGES_TABLE};
+
+/// Rows archived per transaction
+const ARCHIVE_BATCH: i64 = 10_000;
+
+/// Removes old raw messages at a fixed interval, archiving them first when
+/// an archive directory is configured
+pub struct Retention {
+    database_url: String,
+    tables: Vec<TableName>,
+    max_age: Duration,
+    interval: Duration,
+    archive_dir: Option<PathBuf>,
+}
+
+impl Retention {
+    /// Retention as configured, `None` when raw messages are kept forever
+    /// `schemas` are the tenant schemas holding their own `raw_messages`
+    pub fn new(config: &RetentionConfig, database_url: &str, schemas: Vec<String>) -> Option<Self> {
+        if config.raw_messages_secs == 0 {
+            return None;
+        }
+
+        let mut tables = vec![TableName::new(RAW_MESSAGES_TABLE)];
+        tables.extend(schemas.into_iter().map(|schema| TableName {
+            schema: Some(schema),
+            table: RAW_MESSAGES_TABLE.to_string(),
+        }));
+
+        Some(Self {
+            database_url: database_url.to_string(),
+            tables,
+            max_age: Duration::from_secs(config.raw_messages_secs),
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            archive_dir: config.archive_dir.as_ref().map(PathBuf::from),
+        })
+    }
+
+    /// Clean up now and then every interval until the bridge shuts down
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.sweep().await {
+                error!("Raw message retention failed: {:#}", e);
+            }
+        }
+    }
+
+    /// Remove the messages older than the retention window from every table
+    /// A separate connection keeps long deletes from delaying inserts
+    pub async fn sweep(&self) -> Result<()> {
+        let mut client = db::connect(&self.database_url).await?;
+        let cutoff = Utc::now() - self.max_age;
+
+        for table in &self.tables {
+            let removed = match &self.archive_dir {
+                Some(dir) => archive(&mut client, table, cutoff, dir).await?,
+                None => delete(&client, table, cutoff).await?,
+            };
+            if removed > 0 {
+                info!(
+                    "Removed {} raw messages older than {} from {}",
+                    removed,
+                    cutoff.to_rfc3339(),
+                    table
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn delete(client: &Client, table: &TableName, cutoff: DateTime<Utc>) -> Result<u64> {
+    let sql = format!("DELETE FROM {} WHERE timestamp < $1", table.quoted());
+    client
+        .execute(&sql, &[&cutoff])
+        .await
+        .with_context(|| format!("Failed to delete old raw messages from {}", table))
+}
+
+/// Move old messages into `<dir>/<table>-<time>.ndjson.gz`, one JSON object
+/// per row
+/// Each batch is written to the file before its delete is committed, so a
+/// failure leaves the rows in place
+async fn archive(
+    client: &mut Client,
+    table: &TableName,
+    cutoff: DateTime<Utc>,
+    dir: &Path,
+) -> Result<u64> {
+    let sql = format!(
+        "DELETE FROM {table} AS r WHERE (timestamp, id) IN \
+         (SELECT timestamp, id FROM {table} WHERE timestamp < $1 ORDER BY timestamp LIMIT $2) \
+         RETURNING row_to_json(r)::text",
+        table = table.quoted()
+    );
+
+    let mut encoder: Option<GzEncoder<File>> = None;
+    let mut removed = 0;
+    loop {
+        let transaction = client
+            .transaction()
+            .await
+            .with_context(|| "Failed to begin transaction")?;
+        let rows = transaction
+            .query(&sql, &[&cutoff, &ARCHIVE_BATCH])
+            .await
+            .with_context(|| format!("Failed to remove old raw messages from {}", table))?;
+        if rows.is_empty() {
+            break;
+        }
+
+        let file = match &mut encoder {
+            Some(file) => file,
+            None => encoder.insert(create_archive(dir, table)?),
+        };
+        for row in &rows {
+            let line: String = row.get(0);
+            writeln!(file, "{}", line)?;
+        }
+        file.flush()?;
+        file.get_ref()
+            .sync_data()
+            .with_context(|| "Failed to write raw message archive")?;
+
+        transaction
+            .commit()
+            .await
+            .with_context(|| "Failed to commit transaction")?;
+        removed += rows.len() as u64;
+    }
+
+    if let Some(file) = encoder {
+        file.finish()
+            .with_context(|| "Failed to write raw message archive")?;
+    }
+    Ok(removed)
+}
+
+fn create_archive(dir: &Path, table: &TableName) -> Result<GzEncoder<File>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create archive directory: {}", dir.display()))?;
+
+    let path = dir.join(format!(
+        "{}-{}.ndjson.gz",
+        table,
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+    let file = File::create(&path)
+        .with_context(|| format!("Failed to create archive file: {}", path.display()))?;
+
+    info!("Archiving raw messages to {}", path.display());
+    Ok(GzEncoder::new(file, Compression::default()))
+}
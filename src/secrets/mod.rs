@@ -0,0 +1,74 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::config::VaultConfig;
+
+const VAULT_PREFIX: &str = "vault:";
+
+/// Read a secret referenced from the configuration
+/// `vault:<path>#<key>` reads `key` from the Vault secret at `path`, any
+/// other reference is a file such as a mounted Docker or Kubernetes secret
+pub async fn read(reference: &str, vault: Option<&VaultConfig>) -> Result<String> {
+    match reference.strip_prefix(VAULT_PREFIX) {
+        Some(location) => read_vault(location, vault).await,
+        None => read_file(reference),
+    }
+}
+
+/// Secret files usually end with a newline that is not part of the secret
+fn read_file(path: &str) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret file: {}", path))?;
+
+    Ok(contents.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn read_vault(location: &str, vault: Option<&VaultConfig>) -> Result<String> {
+    let Some(vault) = vault else {
+        bail!("Secret vault:{} needs a [vault] section", location);
+    };
+    let Some((path, key)) = location.split_once('#') else {
+        bail!(
+            "Vault secret must be written as vault:<path>#<key>: {}",
+            location
+        );
+    };
+
+    let token = match (&vault.token_file, std::env::var("VAULT_TOKEN")) {
+        (Some(file), _) => read_file(file)?,
+        (None, Ok(token)) => token,
+        (None, Err(_)) => bail!("Vault needs token_file or the VAULT_TOKEN variable"),
+    };
+
+    let url = format!(
+        "{}/v1/{}",
+        vault.address.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+
+    let mut request = reqwest::Client::new()
+        .get(&url)
+        .header("X-Vault-Token", token);
+    if let Some(namespace) = &vault.namespace {
+        request = request.header("X-Vault-Namespace", namespace);
+    }
+
+    let body: Value = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to read Vault secret {}", path))?
+        .json()
+        .await
+        .with_context(|| format!("Invalid response for Vault secret {}", path))?;
+
+    // KV version 2 nests the secret under data.data, version 1 under data
+    let data = &body["data"];
+    let data = data.get("data").filter(|d| d.is_object()).unwrap_or(data);
+
+    match data.get(key) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(value) => Ok(value.to_string()),
+        None => bail!("Vault secret {} has no key {}", path, key),
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use rumqttc::{Incoming, MqttState, Publish, QoS};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// A received MQTT message to acknowledge once it is handled
+#[derive(Debug, Clone, Copy)]
+pub struct Ack {
+    pub pkid: u16,
+    pub qos: QoS,
+}
+
+impl Ack {
+    /// The packet `AsyncClient::ack` answers, which only needs the id and
+    /// QoS of the original
+    pub fn publish(&self) -> Publish {
+        let mut publish = Publish::new("", self.qos, Vec::new());
+        publish.pkid = self.pkid;
+        publish
+    }
+}
+
+/// What a received message is to the session
+#[derive(Debug, PartialEq, Eq)]
+pub enum Receipt {
+    /// Not seen before, to be handled and acknowledged
+    New,
+    /// Redelivered while the first delivery is still being handled
+    Handling,
+    /// Redelivered after it was stored, to be acknowledged again only
+    Stored,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Packet {
+    /// Queued for the workers
+    Handling,
+    /// QoS 2 message stored and acknowledged, until the broker releases it
+    Stored,
+}
+
+/// Contents of the session file
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    client_id: String,
+    /// Packet ids of the QoS 2 messages stored but not released
+    stored: Vec<u16>,
+}
+
+/// Packet ids of the messages of a persistent session not yet done with,
+/// those of stored QoS 2 messages kept in a file so a restarted bridge
+/// recognizes their redelivery
+pub struct Session {
+    path: PathBuf,
+    client_id: String,
+    packets: Mutex<BTreeMap<u16, Packet>>,
+}
+
+impl Session {
+    /// Read the session left by the last run, a file of another client id
+    /// is started over
+    pub fn load(path: &str, client_id: &str) -> Result<Self> {
+        let mut packets = BTreeMap::new();
+
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let file: SessionFile = serde_json::from_str(&contents)
+                    .with_context(|| format!("Failed to parse session file: {}", path))?;
+                if file.client_id == client_id {
+                    packets.extend(file.stored.into_iter().map(|pkid| (pkid, Packet::Stored)));
+                } else {
+                    warn!(
+                        "Session file {} is for client {}, starting a new session",
+                        path, file.client_id
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read session file: {}", path));
+            }
+        }
+
+        debug!("{} stored messages awaiting release", packets.len());
+        Ok(Self {
+            path: PathBuf::from(path),
+            client_id: client_id.to_string(),
+            packets: Mutex::new(packets),
+        })
+    }
+
+    /// Take up the session after connecting, the broker remembers it only
+    /// when `session_present`
+    /// Releases of stored messages are expected by the client, whose own
+    /// state is lost on every reconnect
+    pub fn connected(&self, session_present: bool, state: &mut MqttState) {
+        let mut packets = self.packets.lock().unwrap();
+        if !session_present {
+            let stored = packets.values().filter(|p| **p == Packet::Stored).count();
+            if stored > 0 {
+                warn!(
+                    "The broker lost the session, forgetting {} stored messages awaiting release",
+                    stored
+                );
+            }
+            packets.retain(|_, packet| *packet == Packet::Handling);
+            self.save(&packets);
+            return;
+        }
+
+        for (pkid, _) in packets.iter().filter(|(_, p)| **p == Packet::Stored) {
+            let ack = Ack {
+                pkid: *pkid,
+                qos: QoS::ExactlyOnce,
+            };
+            // Recorded as received without being handed out again
+            if state
+                .handle_incoming_packet(Incoming::Publish(ack.publish()))
+                .is_ok()
+            {
+                state.events.pop_back();
+            }
+        }
+    }
+
+    /// Check a received QoS 1 or 2 message against those not done with
+    pub fn receive(&self, ack: Ack) -> Receipt {
+        let mut packets = self.packets.lock().unwrap();
+        match packets.get(&ack.pkid) {
+            Some(Packet::Handling) => Receipt::Handling,
+            Some(Packet::Stored) if ack.qos == QoS::ExactlyOnce => Receipt::Stored,
+            // A QoS 1 message reusing the id of a stored one is new
+            _ => {
+                packets.insert(ack.pkid, Packet::Handling);
+                Receipt::New
+            }
+        }
+    }
+
+    /// Mark a message handled before acknowledging it, a QoS 2 message is
+    /// remembered until the broker releases it
+    pub fn handled(&self, ack: Ack) {
+        let mut packets = self.packets.lock().unwrap();
+        match ack.qos {
+            QoS::ExactlyOnce => {
+                packets.insert(ack.pkid, Packet::Stored);
+                self.save(&packets);
+            }
+            _ => {
+                packets.remove(&ack.pkid);
+            }
+        }
+    }
+
+    /// Forget a message left unacknowledged because it was not stored, so
+    /// the broker's redelivery on the next connection is handled again
+    pub fn abandon(&self, ack: Ack) {
+        let mut packets = self.packets.lock().unwrap();
+        if packets.get(&ack.pkid) == Some(&Packet::Handling) {
+            packets.remove(&ack.pkid);
+        }
+    }
+
+    /// Forget a QoS 2 message the broker released
+    pub fn released(&self, pkid: u16) {
+        let mut packets = self.packets.lock().unwrap();
+        if packets.remove(&pkid).is_some() {
+            self.save(&packets);
+        }
+    }
+
+    /// Write the stored packet ids, replacing the file only once the new
+    /// one is on disk
+    fn save(&self, packets: &BTreeMap<u16, Packet>) {
+        let file = SessionFile {
+            client_id: self.client_id.clone(),
+            stored: packets
+                .iter()
+                .filter(|(_, packet)| **packet == Packet::Stored)
+                .map(|(pkid, _)| *pkid)
+                .collect(),
+        };
+        if let Err(e) = write_synced(&self.path, &file) {
+            warn!(
+                "Failed to write session file {}: {:#}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn write_synced(path: &Path, file: &SessionFile) -> Result<()> {
+    use std::io::Write;
+
+    let temporary = path.with_extension("tmp");
+    let mut out = std::fs::File::create(&temporary)?;
+    out.write_all(&serde_json::to_vec(file)?)?;
+    out.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use clap::ValueEnum;
+use rumqttc::EventLoop;
+use serde_json::{Map, Value};
+use tracing::{debug, error};
+
+use crate::config::MqttConfig;
+use crate::mqtt;
+
+/// Shape of the generated signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Wave {
+    Sine,
+    Square,
+    Sawtooth,
+    Random,
+}
+
+/// A simulated sensor, parsed from `name[:min:max[:wave]]`
+/// e.g. `temperature:30:40:sine` or `ph:6.8:7.6`
+#[derive(Debug, Clone)]
+pub struct SensorSpec {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub wave: Wave,
+}
+
+impl FromStr for SensorSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+
+        let name = parts[0].trim();
+        if name.is_empty() {
+            bail!("sensor name must not be empty");
+        }
+
+        let (min, max) = match parts.len() {
+            1 => (0.0, 100.0),
+            3 | 4 => (
+                parts[1].parse().context("invalid minimum")?,
+                parts[2].parse().context("invalid maximum")?,
+            ),
+            _ => bail!("expected name[:min:max[:wave]], got {}", s),
+        };
+
+        let wave = match parts.get(3) {
+            Some(wave) => Wave::from_str(wave, true).map_err(|e| anyhow!(e))?,
+            None => Wave::Sine,
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            min,
+            max,
+            wave,
+        })
+    }
+}
+
+impl SensorSpec {
+    /// Value of this sensor at `elapsed` seconds, `phase` shifts the
+    /// signal so devices don't publish identical values
+    fn sample(&self, elapsed: f64, period: f64, phase: f64) -> f64 {
+        let x = (elapsed / period + phase).fract();
+
+        let unit = match self.wave {
+            Wave::Sine => 0.5 + 0.5 * (2.0 * PI * x).sin(),
+            Wave::Square => {
+                if x < 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Wave::Sawtooth => x,
+            Wave::Random => rand::random::<f64>(),
+        };
+
+        self.min + unit * (self.max - self.min)
+    }
+}
+
+pub struct SimulateOptions {
+    pub devices: usize,
+    pub sensors: Vec<SensorSpec>,
+    /// Messages per second, per device
+    pub rate: f64,
+    /// Wave period in seconds
+    pub period: f64,
+    pub topic_prefix: String,
+    /// Stop after this many messages per device
+    pub count: Option<u64>,
+}
+
+/// Publish synthetic telemetry until Ctrl+C (or `count` is reached)
+/// Returns the total number of published messages
+pub async fn run(config: MqttConfig, options: SimulateOptions) -> Result<u64> {
+    if options.rate <= 0.0 {
+        bail!("rate must be greater than zero");
+    }
+
+    let client_id = format!("{}-simulate", config.client_id);
+    let (client, eventloop) = mqtt::create_client(&config, &client_id);
+    let qos = mqtt::qos(config.qos);
+
+    tokio::spawn(drive_eventloop(eventloop));
+
+    let device_ids: Vec<String> = (1..=options.devices).map(|n| format!("sim{}", n)).collect();
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / options.rate));
+    let started = Instant::now();
+    let mut rounds = 0;
+    let mut published = 0;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+
+        let elapsed = started.elapsed().as_secs_f64();
+
+        for (index, device_id) in device_ids.iter().enumerate() {
+            let phase = index as f64 / device_ids.len() as f64;
+            let topic = format!("{}/{}", options.topic_prefix, device_id);
+            let payload = build_payload(&options.sensors, options.period, elapsed, phase);
+
+            client
+                .publish(&topic, qos, false, payload)
+                .await
+                .with_context(|| format!("Failed to publish to topic: {}", topic))?;
+            published += 1;
+        }
+
+        rounds += 1;
+        if options.count.is_some_and(|count| rounds >= count) {
+            break;
+        }
+    }
+
+    // Give the event loop a moment to flush queued publishes
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let _ = client.disconnect().await;
+
+    Ok(published)
+}
+
+/// Build a JSON payload with one value per sensor at `elapsed` seconds
+pub fn build_payload(sensors: &[SensorSpec], period: f64, elapsed: f64, phase: f64) -> String {
+    let mut payload = Map::new();
+    payload.insert(
+        "timestamp".to_string(),
+        Value::String(Utc::now().to_rfc3339()),
+    );
+
+    for sensor in sensors {
+        let value = sensor.sample(elapsed, period, phase);
+        // Keep payloads readable, devices rarely report more than 3 decimals
+        let value = (value * 1000.0).round() / 1000.0;
+        payload.insert(sensor.name.clone(), value.into());
+    }
+
+    Value::Object(payload).to_string()
+}
+
+async fn drive_eventloop(mut eventloop: EventLoop) {
+    loop {
+        match eventloop.poll().await {
+            Ok(event) => debug!("Simulator event: {:?}", event),
+            Err(e) => {
+                error!("MQTT connection error: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client as PgClient;
+use tracing::{debug, error, info, warn};
+
+use crate::config::RetryConfig;
+use crate::db::{self, Connection, Database, TableAllowlist, WideColumns};
+use crate::metrics::metrics;
+use crate::parser::ParsedMessage;
+
+/// Boxed future returned by [`Sink`] methods so sinks can be trait objects
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Destination decoded records are written to
+pub trait Sink: Send + Sync {
+    fn write<'a>(&'a self, message: &'a ParsedMessage) -> BoxFuture<'a, Result<()>>;
+
+    /// Write records that belong together, all of them or none where the
+    /// sink supports it
+    /// By default the records are written one after another, stopping at
+    /// the first failure
+    fn write_batch<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for message in messages {
+                self.write(message).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write the records of many messages at once while catching up on a
+    /// backlog, all of them or none where the sink supports it
+    /// By default the same as [`write_batch`](Self::write_batch)
+    fn write_bulk<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        self.write_batch(messages)
+    }
+
+    /// Replace lost connections before writing again after failures
+    /// By default there is nothing to reconnect
+    fn reconnect(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Writes records to their PostgreSQL tables, the default sink
+pub struct PostgresSink {
+    database: Arc<Database>,
+    allowlist: TableAllowlist,
+    /// Connection batches are written on in a transaction, one batch at a
+    /// time since the other writes share `database`
+    transactions: Option<tokio::sync::Mutex<Connection>>,
+    wide_columns: WideColumns,
+}
+
+impl PostgresSink {
+    pub fn new(database: Arc<Database>, allowlist: TableAllowlist) -> Self {
+        Self {
+            database,
+            allowlist,
+            transactions: None,
+            wide_columns: WideColumns::default(),
+        }
+    }
+
+    /// Write batches in a transaction on `client`, and bulk writes with
+    /// COPY
+    pub fn with_transactions(mut self, client: PgClient) -> Self {
+        self.transactions = Some(tokio::sync::Mutex::new(Connection::new(client)));
+        self
+    }
+}
+
+impl Sink for PostgresSink {
+    fn write<'a>(&'a self, message: &'a ParsedMessage) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.allowlist.check(&message.target())?;
+
+            let connection = self.database.current();
+            let client = &connection.client;
+            match message {
+                ParsedMessage::TelemetryReading(reading) => {
+                    reading.insert(client, &connection.statements).await
+                }
+                ParsedMessage::RawMessage(msg) => msg.insert(client).await,
+                ParsedMessage::WideRow(row) => {
+                    row.add_columns(client, &self.wide_columns).await?;
+                    row.insert(client, &self.wide_columns).await
+                }
+            }
+        })
+    }
+
+    fn write_batch<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let (Some(client), [_, _, ..]) = (&self.transactions, messages) else {
+                for message in messages {
+                    self.write(message).await?;
+                }
+                return Ok(());
+            };
+
+            for message in messages {
+                self.allowlist.check(&message.target())?;
+            }
+            let connection = &mut *client.lock().await;
+            db::insert_batch(
+                &mut connection.client,
+                &connection.statements,
+                &self.wide_columns,
+                messages,
+            )
+            .await
+        })
+    }
+
+    fn write_bulk<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let Some(client) = &self.transactions else {
+                return self.write_batch(messages).await;
+            };
+
+            for message in messages {
+                self.allowlist.check(&message.target())?;
+            }
+            let connection = &mut *client.lock().await;
+            db::copy_batch(
+                &mut connection.client,
+                &connection.statements,
+                &self.wide_columns,
+                messages,
+            )
+            .await
+        })
+    }
+
+    fn reconnect(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            // Statements prepared on a closed connection go with it
+            self.database.connection().await?;
+            let (Some(transactions), Some(url)) = (&self.transactions, self.database.url()) else {
+                return Ok(());
+            };
+            let mut connection = transactions.lock().await;
+            if connection.client.is_closed() {
+                info!("Reconnecting the transaction connection to the database");
+                *connection = Connection::new(db::connect(url).await?);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// SQL states worth retrying besides connection exceptions (class 08)
+const TRANSIENT_STATES: &[SqlState] = &[
+    SqlState::T_R_SERIALIZATION_FAILURE,
+    SqlState::T_R_DEADLOCK_DETECTED,
+    SqlState::ADMIN_SHUTDOWN,
+    SqlState::CRASH_SHUTDOWN,
+    SqlState::CANNOT_CONNECT_NOW,
+    SqlState::TOO_MANY_CONNECTIONS,
+];
+
+/// Errors that may succeed when retried: lost connections, serialization
+/// failures and deadlocks, or a server shutting down or overloaded
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<tokio_postgres::Error>() {
+            return e.is_closed()
+                || e.code().is_some_and(|code| {
+                    TRANSIENT_STATES.contains(code) || code.code().starts_with("08")
+                });
+        }
+        cause.is::<std::io::Error>()
+    })
+}
+
+/// Retries transient failures of another sink and, after repeated
+/// failures, stops writing to it and holds rows in a spill buffer until a
+/// probe reconnects and writes again
+/// Spilled rows are kept in memory only and are lost on restart, and are
+/// written one at a time once the sink recovers, ahead of any new rows
+/// Without spilling, writes fail while the circuit is open
+pub struct RetryingSink {
+    inner: Arc<dyn Sink>,
+    config: RetryConfig,
+    breaker: Mutex<Breaker>,
+    /// Hold rows while the circuit is open, rather than failing the write
+    spills: bool,
+}
+
+#[derive(Default)]
+struct Breaker {
+    /// Consecutive writes that failed after all retries
+    failures: u32,
+    /// Set while the circuit is open
+    opened_at: Option<Instant>,
+    /// A write is testing whether the sink recovered
+    probing: bool,
+    spill: VecDeque<ParsedMessage>,
+    /// Rows were dropped from the full spill buffer since the circuit opened
+    overflowed: bool,
+}
+
+impl Breaker {
+    fn close(&mut self) {
+        self.failures = 0;
+        self.opened_at = None;
+        self.probing = false;
+        self.overflowed = false;
+        metrics().circuit_open.set(0);
+    }
+}
+
+enum Route {
+    Write,
+    Probe,
+    Spill,
+}
+
+impl RetryingSink {
+    pub fn new(inner: Arc<dyn Sink>, config: RetryConfig) -> Self {
+        Self {
+            inner,
+            config,
+            breaker: Mutex::new(Breaker::default()),
+            spills: true,
+        }
+    }
+
+    /// Fail writes while the circuit is open instead of spilling, for
+    /// messages acknowledged to the broker only once they are stored
+    pub fn without_spill(mut self) -> Self {
+        self.spills = false;
+        self
+    }
+
+    fn route(&self) -> Route {
+        let mut breaker = self.breaker.lock().unwrap();
+        let cooldown = Duration::from_secs(self.config.breaker_cooldown_secs);
+
+        match breaker.opened_at {
+            None => Route::Write,
+            Some(opened_at) if !breaker.probing && opened_at.elapsed() >= cooldown => {
+                breaker.probing = true;
+                Route::Probe
+            }
+            Some(_) => Route::Spill,
+        }
+    }
+
+    /// Write `messages` to the inner sink, in bulk or as a batch
+    async fn write_inner(&self, messages: &[ParsedMessage], bulk: bool) -> Result<()> {
+        if bulk {
+            self.inner.write_bulk(messages).await
+        } else {
+            self.inner.write_batch(messages).await
+        }
+    }
+
+    /// Write `messages` together, a failed batch is rolled back so the
+    /// whole batch is retried
+    async fn write_with_retry(&self, messages: &[ParsedMessage], bulk: bool) -> Result<()> {
+        let mut delay = Duration::from_millis(self.config.backoff_ms);
+        let mut attempt = 1;
+
+        loop {
+            match self.write_inner(messages, bulk).await {
+                Err(e) if attempt < self.config.attempts && is_transient(&e) => {
+                    debug!("Retrying write in {:?} after: {:#}", delay, e);
+                    metrics().insert_retries.inc();
+                    tokio::time::sleep(delay).await;
+                    if let Err(e) = self.inner.reconnect().await {
+                        debug!("Failed to reconnect: {:#}", e);
+                    }
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Write, spill or probe with `messages` by the state of the circuit
+    async fn write_routed(&self, messages: &[ParsedMessage], bulk: bool) -> Result<()> {
+        match self.route() {
+            Route::Spill => self.spill_or_fail(
+                messages,
+                anyhow!("Database writes are paused after failures"),
+            ),
+            Route::Probe => {
+                // The circuit stays open while spilled rows are written, so
+                // other writes keep spilling behind them
+                if let Err(e) = self.recover().await {
+                    warn!("Database is still failing: {:#}", e);
+                    self.reopen();
+                    return self.spill_or_fail(messages, e);
+                }
+                match self.write_inner(messages, bulk).await {
+                    Err(e) if is_transient(&e) => {
+                        warn!("Database is still failing: {:#}", e);
+                        self.reopen();
+                        self.spill_or_fail(messages, e)
+                    }
+                    // Any answer from the database means it is reachable again
+                    result => {
+                        // Rows spilled during the probe are written before
+                        // the circuit closes
+                        match self.drain(true).await {
+                            Ok(()) => info!("Database writes recovered, closing the circuit"),
+                            Err(e) => {
+                                warn!("Database failed again while writing spilled rows: {:#}", e);
+                                self.reopen();
+                            }
+                        }
+                        result
+                    }
+                }
+            }
+            Route::Write => match self.write_with_retry(messages, bulk).await {
+                Ok(()) => {
+                    self.reset_failures();
+                    Ok(())
+                }
+                Err(e) if is_transient(&e) => {
+                    if !self.record_failure() {
+                        return Err(e);
+                    }
+                    error!(
+                        "{} consecutive writes failed, pausing writes for {}s: {:#}",
+                        self.config.breaker_threshold, self.config.breaker_cooldown_secs, e
+                    );
+                    self.spill_or_fail(messages, e)
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Count a failed write, returns true when this opens the circuit
+    fn record_failure(&self) -> bool {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.failures += 1;
+
+        let threshold = self.config.breaker_threshold;
+        if threshold == 0 || breaker.failures < threshold || breaker.opened_at.is_some() {
+            return false;
+        }
+
+        breaker.opened_at = Some(Instant::now());
+        metrics().circuit_open.set(1);
+        true
+    }
+
+    fn reset_failures(&self) {
+        self.breaker.lock().unwrap().failures = 0;
+    }
+
+    fn reopen(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.opened_at = Some(Instant::now());
+        breaker.probing = false;
+    }
+
+    fn spill(&self, message: ParsedMessage) {
+        let mut breaker = self.breaker.lock().unwrap();
+
+        if breaker.spill.len() >= self.config.spill_capacity {
+            if !breaker.overflowed {
+                warn!(
+                    "Spill buffer is full ({} rows), dropping the oldest rows",
+                    self.config.spill_capacity
+                );
+                breaker.overflowed = true;
+            }
+            metrics().spill_dropped.inc();
+            if breaker.spill.pop_front().is_none() {
+                // Spilling is disabled
+                return;
+            }
+        }
+
+        breaker.spill.push_back(message);
+        metrics().spill_buffer_rows.set(breaker.spill.len() as i64);
+    }
+
+    /// Spill `messages` as written, or fail with `e` without spilling
+    fn spill_or_fail(&self, messages: &[ParsedMessage], e: anyhow::Error) -> Result<()> {
+        if !self.spills {
+            return Err(e);
+        }
+        self.spill_all(messages);
+        Ok(())
+    }
+
+    fn spill_all(&self, messages: &[ParsedMessage]) {
+        for message in messages {
+            self.spill(message.clone());
+        }
+    }
+
+    /// Take the oldest spilled row, closing the circuit with `close` once
+    /// none are left
+    fn unspill(&self, close: bool) -> Option<ParsedMessage> {
+        let mut breaker = self.breaker.lock().unwrap();
+        let message = breaker.spill.pop_front();
+        metrics().spill_buffer_rows.set(breaker.spill.len() as i64);
+        if message.is_none() && close {
+            breaker.close();
+        }
+        message
+    }
+
+    /// Reconnect the sink and write the rows spilled so far
+    async fn recover(&self) -> Result<()> {
+        self.inner
+            .reconnect()
+            .await
+            .with_context(|| "Failed to reconnect")?;
+        self.drain(false).await
+    }
+
+    /// Write the spilled rows after recovery, in the order they arrived,
+    /// closing the circuit with `close` once they are all written
+    /// Fails on a transient error, the row kept first in the spill buffer
+    async fn drain(&self, close: bool) -> Result<()> {
+        let pending = self.breaker.lock().unwrap().spill.len();
+        if pending > 0 {
+            info!("Writing {} spilled rows", pending);
+        }
+
+        while let Some(message) = self.unspill(close) {
+            match self.inner.write(&message).await {
+                Ok(()) => {}
+                Err(e) if is_transient(&e) => {
+                    let mut breaker = self.breaker.lock().unwrap();
+                    breaker.spill.push_front(message);
+                    metrics().spill_buffer_rows.set(breaker.spill.len() as i64);
+                    return Err(e);
+                }
+                Err(e) => error!("Dropping spilled row: {:#}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for RetryingSink {
+    fn write<'a>(&'a self, message: &'a ParsedMessage) -> BoxFuture<'a, Result<()>> {
+        self.write_batch(std::slice::from_ref(message))
+    }
+
+    fn write_batch<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.write_routed(messages, false))
+    }
+
+    fn write_bulk<'a>(&'a self, messages: &'a [ParsedMessage]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.write_routed(messages, true))
+    }
+
+    fn reconnect(&self) -> BoxFuture<'_, Result<()>> {
+        self.inner.reconnect()
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::budget::ErrorBudget;
+use crate::catchup::CatchUp;
+use crate::config::Config;
+use crate::ingest::Message;
+use crate::mapping::TopicMapping;
+use crate::metrics::metrics;
+use crate::parser::ParsedMessage;
+use crate::stats::Stats;
+
+/// What happens to the messages of a paused subscription
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PauseMode {
+    /// Discard them
+    #[default]
+    Drop,
+    /// Hold them in memory and store them in order on resume
+    Buffer,
+}
+
+impl PauseMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PauseMode::Drop => "drop",
+            PauseMode::Buffer => "buffer",
+        }
+    }
+}
+
+/// A decoded message waiting to be stored
+pub struct HeldMessage {
+    pub message: Message,
+    pub mapping: Option<TopicMapping>,
+    pub records: Vec<ParsedMessage>,
+    /// Payload fields the mapping reads, decoded along with the records
+    pub fields: Option<Value>,
+}
+
+impl HeldMessage {
+    pub fn subscription(&self) -> &str {
+        self.mapping.as_ref().map_or("unknown", |m| m.name())
+    }
+}
+
+/// What became of a message on its way to being stored
+pub enum Hold {
+    /// Not paused, to be stored now
+    Store(Box<HeldMessage>),
+    /// Held until the subscription resumes
+    Held,
+    /// Discarded by a pause in drop mode
+    Dropped(Message),
+    /// Held, the oldest held message was discarded to make room
+    Overflowed(Message),
+}
+
+struct Pause {
+    mode: PauseMode,
+    /// Resumed, the held messages are being stored and new ones still
+    /// queue up behind them
+    resuming: bool,
+    held: VecDeque<HeldMessage>,
+    /// Held messages were dropped for lack of room since the pause began
+    overflowed: bool,
+}
+
+impl Pause {
+    fn new(mode: PauseMode) -> Self {
+        Self {
+            mode,
+            resuming: false,
+            held: VecDeque::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Take the message when paused, dropping or holding it
+    fn take(&mut self, held: HeldMessage, capacity: usize, stats: &Stats) -> Hold {
+        if self.mode == PauseMode::Drop && !self.resuming {
+            debug!(
+                "Subscription {} is paused, dropping message",
+                held.subscription()
+            );
+            stats.record_dropped(held.subscription());
+            return Hold::Dropped(held.message);
+        }
+
+        let mut hold = Hold::Held;
+        if self.held.len() >= capacity {
+            if let Some(oldest) = self.held.pop_front() {
+                if !self.overflowed {
+                    warn!(
+                        "Pause buffer of {} messages is full, dropping the oldest",
+                        capacity
+                    );
+                    self.overflowed = true;
+                }
+                stats.record_dropped(oldest.subscription());
+                hold = Hold::Overflowed(oldest.message);
+            }
+        }
+        self.held.push_back(held);
+        hold
+    }
+}
+
+/// Paused subscriptions, and the pause of all of them
+#[derive(Default)]
+struct Pauses {
+    all: Option<Pause>,
+    mappings: BTreeMap<String, Pause>,
+}
+
+/// Runtime state shared between the bridge and the HTTP endpoints
+pub struct BridgeState {
+    started: Instant,
+    mqtt_connected: AtomicBool,
+    /// Waiting for another instance to give up leadership
+    standby: AtomicBool,
+    /// Milliseconds since `started` at the last event loop iteration
+    last_poll_ms: AtomicU64,
+    /// Received messages not yet picked up by a worker
+    backlog: AtomicUsize,
+    backlog_limit: usize,
+    /// Configuration currently in effect, including reloaded subscriptions
+    config: RwLock<Config>,
+    /// Subscriptions whose messages are currently not stored
+    paused: Mutex<Pauses>,
+    pause_buffer: usize,
+    /// Wakes the task storing held messages once a pause ends
+    pub resumed: Notify,
+    pub stats: Stats,
+    pub budget: ErrorBudget,
+    pub catchup: CatchUp,
+}
+
+impl BridgeState {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            started: Instant::now(),
+            mqtt_connected: AtomicBool::new(false),
+            standby: AtomicBool::new(false),
+            last_poll_ms: AtomicU64::new(0),
+            backlog: AtomicUsize::new(0),
+            backlog_limit: config.workers.queue_size.max(1),
+            config: RwLock::new(config.clone()),
+            paused: Mutex::new(Pauses::default()),
+            pause_buffer: config.workers.pause_buffer.max(1),
+            resumed: Notify::new(),
+            stats: Stats::new(config.mqtt.topics.iter().map(|m| m.name())),
+            budget: ErrorBudget::new(&config.errors),
+            catchup: CatchUp::new(&config.catchup),
+        }
+    }
+
+    pub fn set_mqtt_connected(&self, connected: bool) {
+        self.mqtt_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn mqtt_connected(&self) -> bool {
+        self.mqtt_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::Relaxed);
+    }
+
+    /// Whether the bridge waits for leadership, without a broker
+    /// connection or an event loop yet
+    pub fn standby(&self) -> bool {
+        self.standby.load(Ordering::Relaxed)
+    }
+
+    /// Record that the event loop made progress
+    pub fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_poll_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the event loop last made progress
+    pub fn since_last_poll(&self) -> Duration {
+        let last_poll = Duration::from_millis(self.last_poll_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_poll)
+    }
+
+    /// Record a message queued for the workers
+    pub fn enqueued(&self) {
+        let depth = self.backlog.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics().ingest_queue_depth.set(depth as i64);
+    }
+
+    /// Record a message taken off the queue by a worker
+    pub fn dequeued(&self) {
+        let depth = self.backlog.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics().ingest_queue_depth.set(depth as i64);
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::Relaxed)
+    }
+
+    /// The workers are not keeping up and the queue is full
+    pub fn backlog_full(&self) -> bool {
+        self.backlog() >= self.backlog_limit
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replace the subscription list after a reload
+    /// Messages held for removed subscriptions are dropped with them
+    pub fn set_topics(&self, topics: Vec<TopicMapping>) {
+        let names: Vec<String> = topics.iter().map(|m| m.name().to_string()).collect();
+        self.stats.retain(&names);
+        self.budget.retain(&names);
+        self.paused
+            .lock()
+            .unwrap()
+            .mappings
+            .retain(|name, _| names.contains(name));
+        self.config.write().unwrap().mqtt.topics = topics;
+    }
+
+    /// Whether the subscription is paused, on its own or with all others
+    pub fn is_paused(&self, subscription: &str) -> bool {
+        self.pause_status(Some(subscription)).is_some()
+    }
+
+    /// Mode and held message count of the pause of a subscription, of all
+    /// subscriptions when `None`
+    /// Resumed subscriptions still storing held messages are not paused
+    pub fn pause_status(&self, subscription: Option<&str>) -> Option<(PauseMode, usize)> {
+        let paused = self.paused.lock().unwrap();
+        let all = paused.all.as_ref().filter(|pause| !pause.resuming);
+        let pause = match subscription {
+            Some(name) => paused
+                .mappings
+                .get(name)
+                .filter(|pause| !pause.resuming)
+                .or(all),
+            None => all,
+        };
+        pause.map(|pause| (pause.mode, pause.held.len()))
+    }
+
+    /// Pause a subscription, all subscriptions when `None`
+    /// Returns false if it was already paused in this mode
+    pub fn pause(&self, subscription: Option<&str>, mode: PauseMode) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        let pause = match subscription {
+            Some(name) => paused.mappings.get_mut(name),
+            None => paused.all.as_mut(),
+        };
+        if let Some(pause) = pause {
+            // Pausing again while held messages are still being stored
+            // keeps them, in front of what is held next
+            let changed = pause.mode != mode || pause.resuming;
+            pause.mode = mode;
+            pause.resuming = false;
+            return changed;
+        }
+
+        match subscription {
+            Some(name) => {
+                paused.mappings.insert(name.to_string(), Pause::new(mode));
+            }
+            None => paused.all = Some(Pause::new(mode)),
+        }
+        true
+    }
+
+    /// Resume a subscription, all subscriptions when `None`, storing its
+    /// held messages first
+    /// Returns false if it was not paused
+    pub fn resume(&self, subscription: Option<&str>) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        let pause = match subscription {
+            Some(name) => paused.mappings.get_mut(name),
+            None => paused.all.as_mut(),
+        };
+        let Some(pause) = pause.filter(|pause| !pause.resuming) else {
+            return false;
+        };
+
+        pause.resuming = true;
+        drop(paused);
+        self.resumed.notify_one();
+        true
+    }
+
+    /// Take the message of a paused subscription, dropping or holding it
+    pub fn hold(&self, held: HeldMessage) -> Hold {
+        let mut paused = self.paused.lock().unwrap();
+        let paused = &mut *paused;
+        if let Some(pause) = &mut paused.all {
+            return pause.take(held, self.pause_buffer, &self.stats);
+        }
+        match paused.mappings.get_mut(held.subscription()) {
+            Some(pause) => pause.take(held, self.pause_buffer, &self.stats),
+            None => Hold::Store(Box::new(held)),
+        }
+    }
+
+    /// Next held message of a resumed subscription, in the order received
+    /// Pauses end once their last held message is taken
+    pub fn next_resumed(&self) -> Option<HeldMessage> {
+        let mut paused = self.paused.lock().unwrap();
+        let paused = &mut *paused;
+
+        while let Some(pause) = paused.all.as_mut().filter(|pause| pause.resuming) {
+            let Some(held) = pause.held.pop_front() else {
+                paused.all = None;
+                break;
+            };
+            // Still paused on its own, held already so kept whatever the
+            // mode of that pause
+            match paused.mappings.get_mut(held.subscription()) {
+                Some(pause) if !pause.resuming => pause.held.push_back(held),
+                _ => return Some(held),
+            }
+        }
+        // Subscriptions resumed while all were paused wait for that pause
+        if paused.all.is_some() {
+            return None;
+        }
+
+        let mut done = Vec::new();
+        let mut next = None;
+        for (name, pause) in paused.mappings.iter_mut().filter(|(_, p)| p.resuming) {
+            match pause.held.pop_front() {
+                Some(held) => {
+                    next = Some(held);
+                    break;
+                }
+                None => done.push(name.clone()),
+            }
+        }
+        for name in done {
+            paused.mappings.remove(&name);
+        }
+        next
+    }
+
+    /// Messages held by all pauses
+    pub fn held(&self) -> usize {
+        let paused = self.paused.lock().unwrap();
+        paused
+            .all
+            .iter()
+            .chain(paused.mappings.values())
+            .map(|pause| pause.held.len())
+            .sum()
+    }
+}
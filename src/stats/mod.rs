@@ -0,0 +1,299 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::state::BridgeState;
+
+/// Counters for a single subscription
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MappingStats {
+    /// Messages received on the subscription
+    pub messages: u64,
+    /// Rows successfully inserted
+    pub rows_written: u64,
+    /// Rows that failed to insert
+    pub rows_failed: u64,
+    /// Messages that could not be decoded into any reading
+    pub conversion_errors: u64,
+    /// Messages discarded while the subscription was paused
+    pub dropped: u64,
+    pub last_message: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// Ingest lag of the messages received since the last summary, in seconds
+/// Negative when the device clock is ahead
+#[derive(Debug, Clone, Copy)]
+pub struct LagSummary {
+    pub messages: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl LagSummary {
+    pub fn mean(&self) -> f64 {
+        self.sum / self.messages as f64
+    }
+}
+
+/// Insert errors kept for display
+const RECENT_ERRORS: usize = 50;
+/// Distinct topics without readings kept for display
+const UNMATCHED_TOPICS: usize = 100;
+/// Longest payload sample kept per unmatched topic, in bytes
+const SAMPLE_BYTES: usize = 4096;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEntry {
+    pub time: DateTime<Utc>,
+    pub mapping: String,
+    pub error: String,
+}
+
+/// A topic matching no mapping, or whose messages produced no telemetry
+/// readings
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmatchedTopic {
+    pub topic: String,
+    /// Mapping that matched the topic but read nothing from it
+    pub mapping: Option<String>,
+    pub messages: u64,
+    pub last_seen: DateTime<Utc>,
+    /// Latest payload, lossily decoded and cut to `SAMPLE_BYTES`
+    pub sample: String,
+}
+
+/// Per-subscription statistics, keyed by subscription filter
+pub struct Stats {
+    mappings: Mutex<BTreeMap<String, MappingStats>>,
+    recent_errors: Mutex<VecDeque<ErrorEntry>>,
+    unmatched: Mutex<HashMap<String, UnmatchedTopic>>,
+    lag: Mutex<HashMap<String, LagSummary>>,
+}
+
+impl Stats {
+    /// Start with an empty entry for every subscription so quiet ones
+    /// show up in reports too
+    pub fn new<'a>(subscriptions: impl IntoIterator<Item = &'a str>) -> Self {
+        let mappings = subscriptions
+            .into_iter()
+            .map(|name| (name.to_string(), MappingStats::default()))
+            .collect();
+
+        Self {
+            mappings: Mutex::new(mappings),
+            recent_errors: Mutex::new(VecDeque::new()),
+            unmatched: Mutex::new(HashMap::new()),
+            lag: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_message(&self, mapping: &str, readings: usize) {
+        self.update(mapping, |stats| {
+            stats.messages += 1;
+            stats.last_message = Some(Utc::now());
+            if readings == 0 {
+                stats.conversion_errors += 1;
+            }
+        });
+    }
+
+    pub fn record_insert(&self, mapping: &str, error: Option<&anyhow::Error>) {
+        self.update(mapping, |stats| match error {
+            None => stats.rows_written += 1,
+            Some(e) => {
+                stats.rows_failed += 1;
+                stats.last_error = Some(format!("{:#}", e));
+                stats.last_error_at = Some(Utc::now());
+            }
+        });
+
+        if let Some(e) = error {
+            let mut recent = self.recent_errors.lock().unwrap();
+            if recent.len() == RECENT_ERRORS {
+                recent.pop_front();
+            }
+            recent.push_back(ErrorEntry {
+                time: Utc::now(),
+                mapping: mapping.to_string(),
+                error: format!("{:#}", e),
+            });
+        }
+    }
+
+    pub fn record_dropped(&self, mapping: &str) {
+        self.update(mapping, |stats| stats.dropped += 1);
+    }
+
+    pub fn record_lag(&self, mapping: &str, seconds: f64) {
+        let mut lag = self.lag.lock().unwrap();
+        match lag.get_mut(mapping) {
+            Some(summary) => {
+                summary.messages += 1;
+                summary.sum += seconds;
+                summary.min = summary.min.min(seconds);
+                summary.max = summary.max.max(seconds);
+            }
+            None => {
+                lag.insert(
+                    mapping.to_string(),
+                    LagSummary {
+                        messages: 1,
+                        sum: seconds,
+                        min: seconds,
+                        max: seconds,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Ingest lag recorded since the last call, by subscription
+    pub fn take_lag(&self) -> HashMap<String, LagSummary> {
+        std::mem::take(&mut *self.lag.lock().unwrap())
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, MappingStats> {
+        self.mappings.lock().unwrap().clone()
+    }
+
+    /// Most recent insert errors, newest first
+    pub fn recent_errors(&self) -> Vec<ErrorEntry> {
+        self.recent_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Topics that produced no readings, most recently seen first
+    pub fn unmatched_topics(&self) -> Vec<UnmatchedTopic> {
+        let mut topics: Vec<_> = self.unmatched.lock().unwrap().values().cloned().collect();
+        topics.sort_by_key(|entry| Reverse(entry.last_seen));
+        topics
+    }
+
+    /// Remember a message on `topic`, which matched no mapping or `mapping`
+    /// without readings, keeping its payload as the sample
+    pub fn record_unmatched(&self, topic: &str, mapping: Option<&str>, payload: &[u8]) {
+        let mut unmatched = self.unmatched.lock().unwrap();
+        let now = Utc::now();
+        let sample = sample(payload);
+
+        if let Some(entry) = unmatched.get_mut(topic) {
+            entry.mapping = mapping.map(str::to_string);
+            entry.messages += 1;
+            entry.last_seen = now;
+            entry.sample = sample;
+            return;
+        }
+
+        // Evict the stalest topic so a flood of distinct topics stays bounded
+        if unmatched.len() >= UNMATCHED_TOPICS {
+            if let Some(stalest) = unmatched
+                .values()
+                .min_by_key(|entry| entry.last_seen)
+                .map(|entry| entry.topic.clone())
+            {
+                unmatched.remove(&stalest);
+            }
+        }
+
+        unmatched.insert(
+            topic.to_string(),
+            UnmatchedTopic {
+                topic: topic.to_string(),
+                mapping: mapping.map(str::to_string),
+                messages: 1,
+                last_seen: now,
+                sample,
+            },
+        );
+    }
+
+    /// Keep counters for `subscriptions` only, adding entries for new ones
+    pub fn retain(&self, subscriptions: &[String]) {
+        let mut mappings = self.mappings.lock().unwrap();
+        mappings.retain(|name, _| subscriptions.contains(name));
+        for name in subscriptions {
+            mappings.entry(name.clone()).or_default();
+        }
+    }
+
+    fn update(&self, mapping: &str, f: impl FnOnce(&mut MappingStats)) {
+        let mut mappings = self.mappings.lock().unwrap();
+        match mappings.get_mut(mapping) {
+            Some(stats) => f(stats),
+            None => f(mappings.entry(mapping.to_string()).or_default()),
+        }
+    }
+}
+
+/// Log a summary line per subscription every `interval`, warning about
+/// subscriptions that received nothing during the last interval
+pub async fn report_periodically(state: Arc<BridgeState>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let snapshot = state.stats.snapshot();
+        let lag = state.stats.take_lag();
+        let quiet_since = Utc::now() - interval;
+
+        info!("Subscription summary ({} subscriptions)", snapshot.len());
+        for (mapping, stats) in &snapshot {
+            let last_message = stats
+                .last_message
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_else(|| "never".to_string());
+
+            if stats.last_message.is_none_or(|t| t < quiet_since) {
+                warn!(
+                    mapping = %mapping,
+                    last_message = %last_message,
+                    "No messages received during the last {}s",
+                    interval.as_secs()
+                );
+            } else {
+                info!(
+                    mapping = %mapping,
+                    messages = stats.messages,
+                    rows_written = stats.rows_written,
+                    rows_failed = stats.rows_failed,
+                    conversion_errors = stats.conversion_errors,
+                    last_message = %last_message,
+                    "Subscription statistics"
+                );
+            }
+
+            if let Some(lag) = lag.get(mapping) {
+                info!(
+                    mapping = %mapping,
+                    messages = lag.messages,
+                    mean_secs = %format!("{:.3}", lag.mean()),
+                    min_secs = %format!("{:.3}", lag.min),
+                    max_secs = %format!("{:.3}", lag.max),
+                    "Ingest lag"
+                );
+            }
+        }
+    }
+}
+
+/// Start of a payload as text, a cut multi-byte character becomes U+FFFD
+fn sample(payload: &[u8]) -> String {
+    let end = payload.len().min(SAMPLE_BYTES);
+    String::from_utf8_lossy(&payload[..end]).into_owned()
+}
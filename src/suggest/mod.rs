@@ -0,0 +1,187 @@
+use std::fmt::Write;
+
+use chrono_tz::Tz;
+use serde_json::Value;
+
+use crate::parser::{extract_device_id, parse_timestamp};
+
+/// Payload fields naming the device, as the default decoder reads them
+const DEVICE_FIELDS: [&str; 3] = ["device_id", "deviceId", "device"];
+/// Field names taken for the time of the readings
+const TIMESTAMP_FIELDS: [&str; 5] = ["timestamp", "ts", "time", "datetime", "date_time"];
+
+/// Draft a mappings file for messages like `payload` on `topic`, with a
+/// sensor per numeric payload field and the fields left out as comments
+pub fn mapping(topic: &str, payload: &[u8]) -> String {
+    let text = String::from_utf8_lossy(payload);
+    let json: Option<Value> = serde_json::from_str(text.trim()).ok();
+
+    let filter = topic_filter(topic, json.as_ref());
+
+    let mut yaml = String::from("mappings:\n");
+    let _ = writeln!(yaml, "  # Drafted from a message on {}", topic);
+    let _ = writeln!(yaml, "  - topic: {}", scalar(&filter));
+
+    match json {
+        Some(Value::Object(fields)) => {
+            let mut leaves = Vec::new();
+            flatten(&mut Vec::new(), &fields, &mut leaves);
+            object_body(&mut yaml, &leaves);
+        }
+        Some(Value::Number(_)) => {
+            // Named after the last level that is not the device
+            let name = filter
+                .rsplit('/')
+                .find(|level| !level.is_empty() && !level.starts_with('{'))
+                .unwrap_or("value");
+            let _ = writeln!(yaml, "    sensors:\n      - name: {}", scalar(name));
+        }
+        _ => {
+            yaml.push_str(
+                "    # Neither a JSON object nor a number, name a decoder that reads it\n",
+            );
+            yaml.push_str("    # decoder: my_decoder\n");
+        }
+    }
+
+    yaml
+}
+
+/// `topic` with the level most likely holding the device id as
+/// `{device_id}`, the last one with a digit in it or else where the default
+/// decoder looks
+/// Unchanged when the id is in the payload, and the same for every device
+fn topic_filter(topic: &str, json: Option<&Value>) -> String {
+    let in_payload = json.is_some_and(|json| {
+        DEVICE_FIELDS
+            .iter()
+            .any(|field| json.get(field).is_some_and(Value::is_string))
+    });
+    if in_payload {
+        return topic.to_string();
+    }
+
+    let mut levels: Vec<&str> = topic.split('/').collect();
+    let numbered = levels
+        .iter()
+        .skip(1)
+        .rposition(|level| level.contains(|c: char| c.is_ascii_digit()))
+        .map(|index| index + 1);
+    let device = numbered.or_else(|| {
+        let device_id = extract_device_id(topic, &Value::Null);
+        levels.iter().rposition(|level| *level == device_id)
+    });
+    if let Some(index) = device {
+        levels[index] = "{device_id}";
+    }
+    levels.join("/")
+}
+
+/// Timestamp and sensors of a JSON object payload, from its leaf fields
+fn object_body(yaml: &mut String, leaves: &[(Vec<String>, Value)]) {
+    // A field the bridge can read before one needing a format, then the
+    // least nested
+    let timestamp = leaves
+        .iter()
+        .filter_map(|(path, value)| {
+            let rank = TIMESTAMP_FIELDS
+                .iter()
+                .position(|name| path.last() == Some(&name.to_string()))?;
+            let readable = parse_timestamp(value, None, Tz::UTC).is_some();
+            Some(((!readable, path.len(), rank), path.join("."), readable))
+        })
+        .min_by_key(|(order, _, _)| *order)
+        .map(|(_, field, readable)| (field, readable));
+
+    let mut sensors = Vec::new();
+    let mut skipped = Vec::new();
+    for (path, value) in leaves {
+        let field = path.join(".");
+        let last = path.last().map(String::as_str).unwrap_or_default();
+
+        if path.len() == 1 && DEVICE_FIELDS.contains(&last) {
+            continue;
+        }
+        if TIMESTAMP_FIELDS.contains(&last) {
+            if timestamp
+                .as_ref()
+                .is_some_and(|(chosen, _)| *chosen != field)
+            {
+                skipped.push((field, "another timestamp".to_string()));
+            }
+            continue;
+        }
+        // Field paths split on `.`, keys holding one cannot be named
+        if path.iter().any(|key| key.contains('.')) {
+            skipped.push((field, "key contains a dot".to_string()));
+            continue;
+        }
+        match value {
+            Value::Number(_) => sensors.push((path.join("_"), field)),
+            Value::Array(_) => skipped.push((field, "array".to_string())),
+            other => skipped.push((field, format!("not numeric, {}", other))),
+        }
+    }
+
+    if let Some((field, readable)) = timestamp {
+        let _ = writeln!(yaml, "    timestamp:\n      field: {}", scalar(&field));
+        if !readable {
+            yaml.push_str("      # Not RFC 3339 or a Unix timestamp, give its strftime format\n");
+            yaml.push_str("      # format: \"%d/%m/%Y %H:%M:%S\"\n");
+        }
+    }
+
+    if sensors.is_empty() {
+        yaml.push_str("    # No numeric fields to read as sensors\n");
+    } else {
+        yaml.push_str("    sensors:\n");
+        for (name, field) in &sensors {
+            let _ = writeln!(
+                yaml,
+                "      - name: {}\n        field: {}",
+                scalar(name),
+                scalar(field)
+            );
+        }
+    }
+    let indent = if sensors.is_empty() { "    " } else { "      " };
+    for (field, reason) in &skipped {
+        let _ = writeln!(yaml, "{}# Left out {}: {}", indent, field, reason);
+    }
+}
+
+/// Leaf values of a JSON object, with the keys leading to each
+fn flatten(
+    path: &mut Vec<String>,
+    fields: &serde_json::Map<String, Value>,
+    leaves: &mut Vec<(Vec<String>, Value)>,
+) {
+    for (key, value) in fields {
+        path.push(key.clone());
+        match value {
+            Value::Object(nested) => flatten(path, nested, leaves),
+            _ => leaves.push((path.clone(), value.clone())),
+        }
+        path.pop();
+    }
+}
+
+/// `text` as a YAML scalar, quoted unless it is plain letters, digits and
+/// separators that cannot start anything else
+fn scalar(text: &str) -> String {
+    let plain = !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-./{}+".contains(c))
+        && text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && !matches!(
+            text.to_ascii_lowercase().as_str(),
+            "true" | "false" | "yes" | "no" | "on" | "off" | "null"
+        );
+    if plain {
+        text.to_string()
+    } else {
+        // JSON strings are valid double-quoted YAML
+        Value::String(text.to_string()).to_string()
+    }
+}
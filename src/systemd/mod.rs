@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use tokio::time::Interval;
+use tracing::debug;
+
+/// Send a state change to the service manager, e.g. `READY=1`
+/// Does nothing unless started by systemd with `Type=notify`
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(e) = send(&socket, state) {
+        debug!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    // A leading `@` names a socket in the abstract namespace
+    let address = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Ticks at half the systemd watchdog timeout, never when the service has
+/// no watchdog
+pub struct Keepalive {
+    interval: Option<Interval>,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Self {
+            interval: watchdog_timeout().map(|timeout| tokio::time::interval(timeout / 2)),
+        }
+    }
+
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `WatchdogSec` of the unit, when the watchdog is meant for this process
+fn watchdog_timeout() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use chrono::Local;
+use colored::Colorize;
+use rumqttc::{Event, Packet};
+use tracing::error;
+
+use crate::config::MqttConfig;
+use crate::mqtt;
+use crate::parser::{parse_message, ParsedMessage};
+
+/// Longest payload excerpt printed for messages without readings
+const PAYLOAD_PREVIEW_LEN: usize = 120;
+
+pub struct TailOptions {
+    /// Only show messages whose topic matches this MQTT filter
+    pub filter: Option<String>,
+    /// Only show messages that produced no telemetry readings
+    pub unmatched_only: bool,
+}
+
+/// Subscribe with the bridge configuration and print every incoming
+/// message together with the readings the parser generates from it
+/// Nothing is written to the database
+pub async fn run(config: MqttConfig, options: TailOptions) -> Result<()> {
+    let client_id = format!("{}-tail", config.client_id);
+    let (client, mut eventloop) = mqtt::create_client(&config, &client_id);
+
+    mqtt::subscribe(&client, &config).await?;
+
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => {
+                match event {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        print_message(&config, &options, &publish.topic, &publish.payload);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("MQTT connection error: {}", e);
+                        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                    }
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_message(config: &MqttConfig, options: &TailOptions, topic: &str, payload: &[u8]) {
+    if let Some(filter) = &options.filter {
+        if !rumqttc::matches(topic, filter) {
+            return;
+        }
+    }
+
+    let readings: Vec<_> = parse_message(topic, payload)
+        .into_iter()
+        .filter_map(|message| match message {
+            ParsedMessage::TelemetryReading(reading) => Some(reading),
+            ParsedMessage::RawMessage(_) | ParsedMessage::WideRow(_) => None,
+        })
+        .collect();
+
+    if options.unmatched_only && !readings.is_empty() {
+        return;
+    }
+
+    let subscription = mqtt::matching_mapping(config, topic).map_or("?", |m| m.name());
+
+    let time = Local::now().format("%H:%M:%S%.3f").to_string();
+
+    if readings.is_empty() {
+        let payload = String::from_utf8_lossy(payload);
+        let preview: String = payload.chars().take(PAYLOAD_PREVIEW_LEN).collect();
+        println!(
+            "{} {} {} {} {}",
+            time.dimmed(),
+            topic.cyan(),
+            format!("[{}]", subscription).dimmed(),
+            "no readings".red(),
+            preview.dimmed()
+        );
+        return;
+    }
+
+    let columns: Vec<String> = readings
+        .iter()
+        .map(|reading| format!("{}={}", reading.sensor_name.bright_green(), reading.value))
+        .collect();
+
+    println!(
+        "{} {} {} {} {}",
+        time.dimmed(),
+        topic.cyan(),
+        format!("[{}]", subscription).dimmed(),
+        readings[0].device_id.yellow(),
+        columns.join(" ")
+    );
+}
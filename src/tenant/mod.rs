@@ -0,0 +1,147 @@
+use anyhow::{bail, Result};
+
+use crate::config::{TenantRouting, TenantsConfig};
+use crate::parser::ParsedMessage;
+
+const TENANT_PLACEHOLDER: &str = "{tenant}";
+
+/// Longest tenant id accepted, keeps `tenant_<id>` within PostgreSQL's
+/// 63 byte identifier limit
+const MAX_TENANT_LEN: usize = 48;
+
+/// Tenant a record belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    pub id: String,
+    pub routing: TenantRouting,
+}
+
+impl Tenant {
+    /// Schema holding this tenant's tables when routed by schema
+    pub fn schema(&self) -> String {
+        format!("tenant_{}", self.id)
+    }
+}
+
+/// Outcome of looking up the tenant of a topic
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// The topic does not match the tenant pattern
+    Shared,
+    Tenant(Tenant),
+    /// The tenant is invalid or not in the allowlist
+    Rejected(String),
+}
+
+/// Extracts the tenant from message topics
+pub struct TenantResolver {
+    /// The pattern with `{tenant}` replaced by `+`
+    filter: String,
+    /// Topic level holding the tenant
+    level: usize,
+    routing: TenantRouting,
+    allowed: Vec<String>,
+}
+
+impl TenantResolver {
+    /// Build a resolver, or `None` when tenant routing is not configured
+    pub fn new(config: &TenantsConfig) -> Result<Option<Self>> {
+        let Some(pattern) = &config.topic_pattern else {
+            return Ok(None);
+        };
+
+        let levels: Vec<&str> = pattern.split('/').collect();
+        let positions: Vec<usize> = levels
+            .iter()
+            .enumerate()
+            .filter(|(_, level)| **level == TENANT_PLACEHOLDER)
+            .map(|(index, _)| index)
+            .collect();
+
+        let [level] = positions[..] else {
+            bail!(
+                "Tenant topic pattern must contain {} exactly once as a whole level: {}",
+                TENANT_PLACEHOLDER,
+                pattern
+            );
+        };
+
+        for tenant in &config.allowed {
+            if !valid_tenant_id(tenant) {
+                bail!("Invalid tenant id in allowlist: {}", tenant);
+            }
+        }
+
+        Ok(Some(Self {
+            filter: pattern.replace(TENANT_PLACEHOLDER, "+"),
+            level,
+            routing: config.routing,
+            allowed: config.allowed.clone(),
+        }))
+    }
+
+    pub fn resolve(&self, topic: &str) -> Resolution {
+        if !rumqttc::matches(topic, &self.filter) {
+            return Resolution::Shared;
+        }
+
+        let Some(id) = topic.split('/').nth(self.level) else {
+            return Resolution::Shared;
+        };
+
+        if !valid_tenant_id(id) {
+            return Resolution::Rejected(id.to_string());
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|tenant| tenant == id) {
+            return Resolution::Rejected(id.to_string());
+        }
+
+        Resolution::Tenant(Tenant {
+            id: id.to_string(),
+            routing: self.routing,
+        })
+    }
+
+    /// Tag `messages` with the tenant of `topic`
+    /// Returns the tenant id as the error when the tenant is rejected
+    pub fn assign(&self, topic: &str, messages: &mut [ParsedMessage]) -> Result<(), String> {
+        match self.resolve(topic) {
+            Resolution::Shared => Ok(()),
+            Resolution::Tenant(tenant) => {
+                for message in messages {
+                    message.set_tenant(tenant.clone());
+                }
+                Ok(())
+            }
+            Resolution::Rejected(id) => Err(id),
+        }
+    }
+
+    /// Schemas that must exist for the allowlisted tenants
+    pub fn schemas(&self) -> Vec<String> {
+        if self.routing != TenantRouting::Schema {
+            return Vec::new();
+        }
+
+        self.allowed
+            .iter()
+            .map(|id| {
+                Tenant {
+                    id: id.clone(),
+                    routing: self.routing,
+                }
+                .schema()
+            })
+            .collect()
+    }
+}
+
+/// Tenant ids end up in schema names, so only lowercase letters, digits
+/// and underscores are accepted
+fn valid_tenant_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_TENANT_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
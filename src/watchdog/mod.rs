@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::config::DevicesConfig;
+use crate::db::Database;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    Online,
+    Offline,
+}
+
+/// Published to the status topic and written to the status table
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusEvent {
+    pub device_id: String,
+    pub status: DeviceStatus,
+    pub last_seen: DateTime<Utc>,
+    pub time: DateTime<Utc>,
+}
+
+struct DeviceEntry {
+    last_seen: DateTime<Utc>,
+    online: bool,
+}
+
+/// Tracks the last message time per device and reports devices going
+/// offline after a silence window and coming back online
+/// Only devices seen since the bridge started are tracked
+pub struct Watchdog {
+    devices: Mutex<HashMap<String, DeviceEntry>>,
+    offline_after: Duration,
+    /// Topic template, `{device_id}` is replaced with the device
+    status_topic: Option<String>,
+    status_table: bool,
+    database: Arc<Database>,
+}
+
+impl Watchdog {
+    pub fn new(config: &DevicesConfig, database: Arc<Database>) -> Self {
+        Self {
+            devices: Mutex::new(HashMap::new()),
+            offline_after: Duration::from_secs(config.offline_after_secs),
+            status_topic: config.status_topic.clone(),
+            status_table: config.status_table,
+            database,
+        }
+    }
+
+    /// Record a message from `device_id`, reporting it online if it is new
+    /// or was offline
+    pub fn seen(self: &Arc<Self>, device_id: &str, mqtt: &AsyncClient) {
+        let now = Utc::now();
+        let came_online = {
+            let mut devices = self.devices.lock().unwrap();
+            match devices.get_mut(device_id) {
+                Some(entry) => {
+                    entry.last_seen = now;
+                    !std::mem::replace(&mut entry.online, true)
+                }
+                None => {
+                    devices.insert(
+                        device_id.to_string(),
+                        DeviceEntry {
+                            last_seen: now,
+                            online: true,
+                        },
+                    );
+                    true
+                }
+            }
+        };
+
+        if came_online {
+            self.report(
+                StatusEvent {
+                    device_id: device_id.to_string(),
+                    status: DeviceStatus::Online,
+                    last_seen: now,
+                    time: now,
+                },
+                mqtt,
+            );
+        }
+    }
+
+    /// Periodically mark silent devices offline
+    pub async fn run(self: Arc<Self>, mqtt: AsyncClient) {
+        // Check often enough that a device is reported within a quarter of
+        // the window after it expires
+        let period = (self.offline_after / 4).max(Duration::from_secs(1));
+        let mut ticker = tokio::time::interval(period);
+
+        loop {
+            ticker.tick().await;
+
+            let now = Utc::now();
+            let silent_since = now - self.offline_after;
+            let mut expired = Vec::new();
+
+            for (device_id, entry) in self.devices.lock().unwrap().iter_mut() {
+                if entry.online && entry.last_seen < silent_since {
+                    entry.online = false;
+                    expired.push(StatusEvent {
+                        device_id: device_id.clone(),
+                        status: DeviceStatus::Offline,
+                        last_seen: entry.last_seen,
+                        time: now,
+                    });
+                }
+            }
+
+            for event in expired {
+                self.report(event, &mqtt);
+            }
+        }
+    }
+
+    fn report(self: &Arc<Self>, event: StatusEvent, mqtt: &AsyncClient) {
+        match event.status {
+            DeviceStatus::Online => info!(device_id = %event.device_id, "Device online"),
+            DeviceStatus::Offline => warn!(
+                device_id = %event.device_id,
+                last_seen = %event.last_seen.to_rfc3339(),
+                "Device offline"
+            ),
+        }
+
+        let watchdog = self.clone();
+        let mqtt = mqtt.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watchdog.publish(&event, &mqtt).await {
+                error!("Failed to report device status: {:#}", e);
+            }
+        });
+    }
+
+    async fn publish(&self, event: &StatusEvent, mqtt: &AsyncClient) -> Result<()> {
+        if let Some(template) = &self.status_topic {
+            let topic = template.replace("{device_id}", &event.device_id);
+            let payload = serde_json::to_vec(event)?;
+            // Retained so new subscribers learn the current status
+            mqtt.publish(&topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .with_context(|| format!("Failed to publish status to {}", topic))?;
+        }
+
+        if self.status_table {
+            self.database
+                .connection()
+                .await?
+                .client
+                .execute(
+                    "INSERT INTO device_status (device_id, online, last_seen, changed_at) \
+                     VALUES ($1, $2, $3, $4) \
+                     ON CONFLICT (device_id) DO UPDATE SET \
+                     online = EXCLUDED.online, \
+                     last_seen = EXCLUDED.last_seen, \
+                     changed_at = EXCLUDED.changed_at",
+                    &[
+                        &event.device_id,
+                        &(event.status == DeviceStatus::Online),
+                        &event.last_seen,
+                        &event.time,
+                    ],
+                )
+                .await
+                .with_context(|| "Failed to update device status")?;
+        }
+
+        Ok(())
+    }
+}